@@ -14,9 +14,20 @@ pub fn benchmarks(c: &mut Criterion) {
     c.bench_function("set_existing", |b| b.iter(|| {
         db.insert(b"newkey", 0u32, 1000u64, b"1234567890");
     }));
+
+    let options = DatabaseOptions { hot_cache_size: 64 * 1024 * 1024, ..Default::default() };
+    let cached_db = Database::open_with_options("/tmp/rocksdb_benchmark_hot_cache", &options);
+    cached_db.insert(b"existingkey", 0u32, 1000u64, b"1234567890");
+
+    c.bench_function("get_existing_hot_cache", |b| b.iter(|| {
+        cached_db.get(black_box(vec![b"existingkey"]), false);
+    }));
+    c.bench_function("set_existing_hot_cache", |b| b.iter(|| {
+        cached_db.insert(b"newkey", 0u32, 1000u64, b"1234567890");
+    }));
 }
 
-use rockscached_db::db::Database;
+use rockscached_db::db::{Database, DatabaseOptions};
 
 
 
@@ -13,14 +13,30 @@ pub fn convert_bytes_to_u64(bytes: &[u8]) -> Result<u64, ParseIntError> {
 }
 
 pub fn bytes_to_u32(bytes: &[u8]) -> u32 {
+    convert_bytes_to_u32(bytes).unwrap()
+}
+
+pub fn convert_bytes_to_u32(bytes: &[u8]) -> Result<u32, ParseIntError> {
     let x = String::from_utf8_lossy(bytes);
-    u32::from_str(x.as_ref()).unwrap()
+    u32::from_str(x.as_ref())
 }
 
 pub fn u64_to_bytes<'a>(u: u64) -> Vec<u8> {
     u.to_string().into_bytes()
 }
 
+/// Smallest key greater than every key starting with `prefix`, none when there is no such key.
+pub fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last < 0xff {
+            successor.push(last + 1);
+            return Some(successor);
+        }
+    }
+    None
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -40,4 +56,12 @@ mod tests {
     fn u64_to_bytes_nominal() {
         assert_eq!(u64_to_bytes(12345u64), b"12345");
     }
+
+    #[test]
+    fn prefix_successor_nominal() {
+        assert_eq!(prefix_successor(b"user:"), Some(b"user;".to_vec()));
+        assert_eq!(prefix_successor(&[b'a', 0xff, 0xff]), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(&[0xff]), None);
+        assert_eq!(prefix_successor(b""), None);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use crate::record::Record;
+
+const SHARD_COUNT: usize = 16;
+/// Smallest capacity of a shard, a smaller cache being split in fewer shards.
+const MIN_SHARD_CAPACITY: usize = 64 * 1024;
+
+/// Sharded in-process LRU keeping the hottest records in front of RocksDB.
+/// The capacity is expressed in bytes of key and value.
+pub struct HotCache {
+    shards: Vec<Mutex<Shard>>,
+}
+
+struct Shard {
+    entries: HashMap<Vec<u8>, (Record, u64)>,
+    recency: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    size: usize,
+    capacity: usize,
+}
+
+impl HotCache {
+    pub fn new(capacity: usize) -> HotCache {
+        let shard_count = (capacity / MIN_SHARD_CAPACITY).max(1).min(SHARD_COUNT);
+        let shards = (0..shard_count)
+            .map(|_| Mutex::new(Shard::new(capacity / shard_count)))
+            .collect();
+        HotCache { shards }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Record> {
+        self.shard(key).lock().unwrap().get(key)
+    }
+
+    pub fn put(&self, key: &[u8], record: Record) {
+        self.shard(key).lock().unwrap().put(key, record)
+    }
+
+    pub fn remove(&self, key: &[u8]) {
+        self.shard(key).lock().unwrap().remove(key)
+    }
+
+    /// Removes the keys from `start` included to `end` excluded, or to the last key.
+    pub fn remove_range(&self, start: &[u8], end: Option<&[u8]>) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let keys: Vec<Vec<u8>> = shard.entries.keys()
+                .filter(|key| in_range(key, start, end))
+                .cloned()
+                .collect();
+            for key in keys {
+                shard.remove(&key);
+            }
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
+
+pub fn in_range(key: &[u8], start: &[u8], end: Option<&[u8]>) -> bool {
+    match end {
+        Some(end) => key >= start && key < end,
+        None => key >= start
+    }
+}
+
+impl Shard {
+    fn new(capacity: usize) -> Shard {
+        Shard {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: &[u8]) -> Option<Record> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(key) {
+            Some((record, last_access)) => {
+                self.recency.remove(last_access);
+                self.recency.insert(tick, key.to_vec());
+                *last_access = tick;
+                Some(record.clone())
+            }
+            None => None
+        }
+    }
+
+    fn put(&mut self, key: &[u8], record: Record) {
+        self.remove(key);
+        let entry_size = key.len() + record.value.len();
+        if entry_size > self.capacity {
+            return;
+        }
+        self.tick += 1;
+        self.entries.insert(key.to_vec(), (record, self.tick));
+        self.recency.insert(self.tick, key.to_vec());
+        self.size += entry_size;
+        while self.size > self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(tick) => *tick,
+                None => break
+            };
+            if let Some(evicted) = self.recency.remove(&oldest) {
+                self.remove(&evicted);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((record, last_access)) = self.entries.remove(key) {
+            self.recency.remove(&last_access);
+            self.size -= key.len() + record.value.len();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(value: &[u8]) -> Record {
+        Record::new(0, 0, 0, value.to_vec())
+    }
+
+    #[test]
+    fn shard_put_and_get() {
+        let mut shard = Shard::new(100);
+        shard.put(b"k1", record(b"v1"));
+        assert_eq!(shard.get(b"k1"), Some(record(b"v1")));
+        assert_eq!(shard.get(b"k2"), None);
+    }
+
+    #[test]
+    fn shard_evicts_least_recently_used() {
+        let mut shard = Shard::new(12);
+        shard.put(b"k1", record(b"v1"));
+        shard.put(b"k2", record(b"v2"));
+        shard.put(b"k3", record(b"v3"));
+        shard.get(b"k1");
+        shard.put(b"k4", record(b"v4"));
+        assert_eq!(shard.get(b"k2"), None);
+        assert_eq!(shard.get(b"k1"), Some(record(b"v1")));
+        assert_eq!(shard.size, 12);
+    }
+
+    #[test]
+    fn shard_skips_oversized_entries() {
+        let mut shard = Shard::new(4);
+        shard.put(b"key", record(b"value"));
+        assert_eq!(shard.get(b"key"), None);
+        assert_eq!(shard.size, 0);
+    }
+
+    #[test]
+    fn remove_range() {
+        let cache = HotCache::new(1600);
+        cache.put(b"a", record(b"v"));
+        cache.put(b"user:1", record(b"v"));
+        cache.put(b"user:2", record(b"v"));
+        cache.put(b"z", record(b"v"));
+        cache.remove_range(b"user:", Some(b"user;"));
+        assert_eq!(cache.get(b"user:1"), None);
+        assert_eq!(cache.get(b"user:2"), None);
+        assert_eq!(cache.get(b"a"), Some(record(b"v")));
+        cache.remove_range(b"b", None);
+        assert_eq!(cache.get(b"z"), None);
+        assert_eq!(cache.get(b"a"), Some(record(b"v")));
+    }
+
+    #[test]
+    fn small_cache_keeps_entries() {
+        let cache = HotCache::new(10);
+        assert_eq!(cache.shards.len(), 1);
+        cache.put(b"k", record(b"v"));
+        assert_eq!(cache.get(b"k"), Some(record(b"v")));
+        assert_eq!(HotCache::new(1 << 30).shards.len(), SHARD_COUNT);
+    }
+
+    #[test]
+    fn shard_remove() {
+        let mut shard = Shard::new(100);
+        shard.put(b"k1", record(b"v1"));
+        shard.remove(b"k1");
+        assert_eq!(shard.get(b"k1"), None);
+        assert_eq!(shard.size, 0);
+    }
+}
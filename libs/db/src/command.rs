@@ -1,8 +1,20 @@
 use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug_span, field, info, warn};
 use crate::db::Database;
 use crate::response::Response;
 use crate::parser::parse;
 
+/// Verbs of the commands, as returned by `Command::name`.
+pub const COMMAND_NAMES: [&str; 22] = [
+    "get", "gets", "delete", "set", "add", "append", "prepend", "incr", "decr", "stats", "flush_all", "use",
+    "delete_prefix", "delete_range", "tset", "invalidate_tag", "checkpoint", "backup", "ingest", "read_only", "keys",
+    "slow_log",
+];
+
+/// Number of slow commands returned by `slow_log` without a count.
+const DEFAULT_SLOW_LOG_COUNT: u64 = 10;
+
 #[derive(PartialEq, Debug)]
 pub enum Command<'a> {
     Get { keys: Vec<&'a [u8]> },
@@ -15,16 +27,123 @@ pub enum Command<'a> {
     Increment { key: &'a [u8], value: u64 },
     Decrement { key: &'a [u8], value: u64 },
     Stats,
+    FlushAll,
+    Use { namespace: &'a [u8] },
+    DeletePrefix { prefix: &'a [u8], noreply: bool },
+    DeleteRange { start: &'a [u8], end: &'a [u8], noreply: bool },
+    TaggedSet { key: &'a [u8], flags: u32, ttl: u64, value: &'a [u8], tags: Vec<&'a [u8]> },
+    InvalidateTag { tag: &'a [u8], noreply: bool },
+    /// A checkpoint created in the checkpoint directory, under a name without any `/`.
+    Checkpoint { name: &'a [u8] },
+    Backup,
+    Ingest { paths: Vec<&'a [u8]> },
+    ReadOnly { enabled: bool },
+    Keys { prefix: &'a [u8], limit: Option<u64>, cursor: Option<&'a [u8]> },
+    LatencyStats,
+    HotKeyStats,
+    SlowLog { count: Option<u64> },
+    SlowLogReset,
 }
 
 impl<'a> Command<'a> {
-    pub fn handle(line: &'a[u8], db: &'a Arc<Database>) -> Response {
+    /// Whether the command changes the stored keys.
+    pub fn is_write(&self) -> bool {
+        matches!(self, Command::Delete { .. } | Command::Set { .. } | Command::Add { .. } | Command::Append { .. }
+            | Command::Prepend { .. } | Command::Increment { .. } | Command::Decrement { .. }
+            | Command::FlushAll | Command::DeletePrefix { .. } | Command::DeleteRange { .. }
+            | Command::TaggedSet { .. } | Command::InvalidateTag { .. } | Command::Ingest { .. })
+    }
+
+    /// The verb of the command.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get { .. } => "get",
+            Command::Gets { .. } => "gets",
+            Command::Delete { .. } => "delete",
+            Command::Set { .. } => "set",
+            Command::Add { .. } => "add",
+            Command::Append { .. } => "append",
+            Command::Prepend { .. } => "prepend",
+            Command::Increment { .. } => "incr",
+            Command::Decrement { .. } => "decr",
+            Command::Stats => "stats",
+            Command::FlushAll => "flush_all",
+            Command::Use { .. } => "use",
+            Command::DeletePrefix { .. } => "delete_prefix",
+            Command::DeleteRange { .. } => "delete_range",
+            Command::TaggedSet { .. } => "tset",
+            Command::InvalidateTag { .. } => "invalidate_tag",
+            Command::Checkpoint { .. } => "checkpoint",
+            Command::Backup => "backup",
+            Command::Ingest { .. } => "ingest",
+            Command::ReadOnly { .. } => "read_only",
+            Command::Keys { .. } => "keys",
+            Command::LatencyStats | Command::HotKeyStats => "stats",
+            Command::SlowLog { .. } | Command::SlowLogReset => "slow_log",
+        }
+    }
+
+    /// The first key the command applies to, if any.
+    pub fn key(&self) -> Option<&'a [u8]> {
+        match self {
+            Command::Get { keys } | Command::Gets { keys } => keys.first().copied(),
+            Command::Delete { key } | Command::Set { key, .. } | Command::Add { key, .. } | Command::Append { key, .. }
+            | Command::Prepend { key, .. } | Command::Increment { key, .. } | Command::Decrement { key, .. }
+            | Command::TaggedSet { key, .. } => Some(key),
+            Command::DeletePrefix { prefix, .. } | Command::Keys { prefix, .. } => Some(prefix),
+            Command::DeleteRange { start, .. } => Some(start),
+            Command::InvalidateTag { tag, .. } => Some(tag),
+            _ => None
+        }
+    }
+
+    /// Number of keys the command applies to.
+    pub fn key_count(&self) -> usize {
+        match self {
+            Command::Get { keys } | Command::Gets { keys } => keys.len(),
+            command => command.key().is_some() as usize
+        }
+    }
+
+    /// Size of the value the command writes, if any.
+    fn value_size(&self) -> Option<usize> {
+        match self {
+            Command::Set { value, .. } | Command::Add { value, .. } | Command::Append { value, .. }
+            | Command::Prepend { value, .. } | Command::TaggedSet { value, .. } => Some(value.len()),
+            _ => None
+        }
+    }
+
+    /// Runs a command against the namespace selected by the connection, which `use` switches,
+    /// `client` being the address kept in the slow log.
+    pub fn handle(line: &'a[u8], db: &mut Arc<Database>, client: &str) -> Response {
         let request = match parse(line) {
             Ok(req) => req,
             Err(e) => return Response::Error { msg: Box::new(e) },
         };
+        let name = request.name();
+        // At the debug level, for the commands to be logged only when asked for
+        let span = debug_span!("command", client, verb = name, keys = request.key_count(), bytes = line.len(), outcome = field::Empty);
+        let _entered = span.enter();
+        let key = request.key().unwrap_or_default();
+        let value_size = request.value_size();
+        let start = Instant::now();
+        let response = Command::run(request, db);
+        let duration = start.elapsed();
+        db.record_command(name, duration);
+        if db.slow_log().is_slow(duration) {
+            let size = value_size.unwrap_or_else(|| response.serialized_len());
+            db.slow_log().push(duration, client, name, key, size);
+        }
+        span.record("outcome", response.outcome());
+        response
+    }
 
-        let db = db.clone();
+    fn run(request: Command<'a>, db: &mut Arc<Database>) -> Response {
+        // Rejected here to be traced, counted and logged as slow like any other response
+        if request.is_write() && db.is_read_only() {
+            return Response::ReadOnly;
+        }
         match request {
             Command::Get { keys } => db.get(keys, false),
             Command::Gets { keys } => db.get(keys, true),
@@ -35,13 +154,119 @@ impl<'a> Command<'a> {
             Command::Prepend { key, flags, ttl, value } => db.prepend(key, flags, ttl, value),
             Command::Increment { key, value } => db.increment(key, value),
             Command::Decrement { key, value } => db.decrement(key, value),
-            _ => Response::NotImplemented
+            Command::Stats => db.stats(),
+            Command::FlushAll => db.flush_all(),
+            Command::DeletePrefix { prefix, noreply } => reply(db.delete_prefix(prefix), noreply),
+            Command::DeleteRange { start, end, noreply } => reply(db.delete_range(start, end), noreply),
+            Command::TaggedSet { key, flags, ttl, value, tags } => db.insert_with_tags(key, flags, ttl, value, tags),
+            Command::InvalidateTag { tag, noreply } => reply(db.invalidate_tag(tag), noreply),
+            Command::Checkpoint { name } => {
+                let name = String::from_utf8_lossy(name);
+                match db.checkpoint_in_dir(&name) {
+                    Ok(true) => Response::Ok,
+                    Ok(false) => Response::ClientError { msg: "no checkpoint directory configured" },
+                    Err(e) => {
+                        warn!("Can not create checkpoint {} {}", name, e);
+                        Response::ServerError
+                    }
+                }
+            }
+            Command::Backup => match db.backup() {
+                Ok(true) => Response::Ok,
+                Ok(false) => Response::Error { msg: Box::new(String::from("No backup directory configured")) },
+                Err(e) => {
+                    warn!("Can not back up {}", e);
+                    Response::ServerError
+                }
+            },
+            Command::Ingest { paths } => {
+                let paths: Vec<String> = paths.iter().map(|path| String::from_utf8_lossy(path).into_owned()).collect();
+                let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+                match db.ingest(&paths) {
+                    Ok(ingested) => {
+                        info!("Ingested {} SST files into namespace {}", ingested, db.name());
+                        Response::Ok
+                    }
+                    Err(e) => {
+                        warn!("Can not ingest {:?} {}", paths, e);
+                        Response::ServerError
+                    }
+                }
+            }
+            Command::ReadOnly { enabled } => match db.set_read_only(enabled) {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::Error { msg: Box::new(e) }
+            },
+            Command::Keys { prefix, limit, cursor } => db.keys(prefix, limit, cursor),
+            Command::LatencyStats => Response::Value { value: db.latencies().serialize() },
+            Command::HotKeyStats => db.hot_keys(),
+            Command::SlowLog { count } => Response::Value {
+                value: db.slow_log().serialize(count.unwrap_or(DEFAULT_SLOW_LOG_COUNT) as usize)
+            },
+            Command::SlowLogReset => {
+                db.slow_log().reset();
+                Response::Ok
+            }
+            Command::Use { namespace } => {
+                let name = String::from_utf8_lossy(namespace);
+                match db.namespace(&name) {
+                    Some(namespace) => {
+                        *db = namespace;
+                        Response::Ok
+                    }
+                    None => Response::ClientError { msg: "unknown namespace" }
+                }
+            }
         }
     }
 }
 
+fn reply(response: Response, noreply: bool) -> Response {
+    match noreply {
+        true => Response::NoReply,
+        false => response
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use bytes::Bytes;
+    use crate::db::DatabaseOptions;
+    use crate::test_utils::TempDatabase;
+
+    /// A valid request of the verb `name`.
+    fn request(name: &str) -> String {
+        match name {
+            "get" | "gets" | "delete" | "use" | "delete_prefix" | "invalidate_tag" | "checkpoint" | "ingest" | "keys" => format!("{} a\r\n", name),
+            "incr" | "decr" | "delete_range" => format!("{} a 1\r\n", name),
+            "set" | "add" | "append" | "prepend" => format!("{} a 0 0 1\r\nb\r\n", name),
+            "tset" => format!("{} a 0 0 1 t\r\nb\r\n", name),
+            "read_only" => format!("{} on\r\n", name),
+            "slow_log" => format!("{} 5\r\n", name),
+            _ => format!("{}\r\n", name)
+        }
+    }
+
+    #[test]
+    fn name_is_the_parsed_verb() {
+        for &name in COMMAND_NAMES.iter() {
+            assert_eq!(parse(request(name).as_bytes()).unwrap().name(), name);
+        }
+    }
+
+    #[test]
+    fn writes_are_rejected_when_read_only() {
+        let mut db = TempDatabase::open_with_options("command-read-only", &DatabaseOptions { read_only: true, ..Default::default() });
+        let writes: Vec<&str> = COMMAND_NAMES.iter().copied().filter(|name| parse(request(name).as_bytes()).unwrap().is_write()).collect();
+        assert_eq!(writes, vec!["delete", "set", "add", "append", "prepend", "incr", "decr", "flush_all", "delete_prefix", "delete_range", "tset", "invalidate_tag", "ingest"]);
+        for name in writes {
+            assert_eq!(Command::handle(request(name).as_bytes(), &mut db, "test").serialize(), Bytes::from("SERVER_ERROR read only\r\n"), "{}", name);
+        }
+        assert_eq!(Command::handle(b"get a\r\n", &mut db, "test"), Response::Value { value: b"END\r\n".to_vec() });
+        let latencies = Command::handle(b"stats latency\r\n", &mut db, "test").serialize();
+        assert!(String::from_utf8_lossy(&latencies).contains("STAT latency:set:count 1\r\n"));
+    }
 }
 
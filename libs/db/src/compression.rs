@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use byteorder::{BigEndian, ByteOrder};
+use log::warn;
+use zstd::block::{Compressor, Decompressor};
+use zstd::dict;
+
+/// Prefix of the keys under which trained dictionaries are stored, followed by their id.
+pub const DICTIONARY_PREFIX: &[u8] = b"zstd_dictionary:";
+
+const LEVEL: i32 = 3;
+const DICTIONARY_SAMPLES: usize = 1_000;
+/// Number of values compressed with a dictionary after which a new one is trained.
+const RETRAIN_VALUES: u64 = 1_000_000;
+const DICTIONARY_SIZE: usize = 16 * 1024;
+const LENGTH_SIZE: usize = 4;
+/// Largest value compressed, like the longest RESP argument, which bounds the buffer a
+/// corrupted length prefix can make `decompress` allocate.
+const MAX_VALUE_SIZE: usize = 512 * 1024 * 1024;
+
+struct Dictionary {
+    id: u32,
+    compressor: Mutex<Compressor>,
+    decompressor: Mutex<Decompressor>,
+}
+
+impl Dictionary {
+    fn new(id: u32, bytes: Vec<u8>) -> Dictionary {
+        Dictionary {
+            id,
+            compressor: Mutex::new(Compressor::with_dict(bytes.clone())),
+            decompressor: Mutex::new(Decompressor::with_dict(bytes)),
+        }
+    }
+}
+
+/// Compresses values above a size threshold with zstd.
+///
+/// Until a dictionary has been trained from the first compressed values, they are compressed
+/// without one. A new dictionary is then trained from the most recent values every
+/// `RETRAIN_VALUES` values, so that it follows the data; dictionaries are kept forever so
+/// that every stored value stays readable.
+/// A compressed value is prefixed with its uncompressed length.
+pub struct ValueCompressor {
+    threshold: usize,
+    plain: Arc<Dictionary>,
+    dictionaries: RwLock<HashMap<u32, Arc<Dictionary>>>,
+    current: RwLock<Arc<Dictionary>>,
+    samples: Mutex<Samples>,
+}
+
+/// The most recent compressed values, and the number of values compressed since the last
+/// training.
+#[derive(Default)]
+struct Samples {
+    values: Vec<Vec<u8>>,
+    compressed: u64,
+}
+
+impl ValueCompressor {
+    /// A threshold of 0 disables compression, stored values can still be decompressed.
+    pub fn new(threshold: usize, dictionaries: Vec<(u32, Vec<u8>)>) -> ValueCompressor {
+        let plain = Arc::new(Dictionary::new(0, vec![]));
+        let mut current = plain.clone();
+        let mut by_id = HashMap::new();
+        for (id, bytes) in dictionaries {
+            let dictionary = Arc::new(Dictionary::new(id, bytes));
+            if id > current.id {
+                current = dictionary.clone();
+            }
+            by_id.insert(id, dictionary);
+        }
+        ValueCompressor {
+            threshold,
+            plain,
+            dictionaries: RwLock::new(by_id),
+            current: RwLock::new(current),
+            samples: Mutex::new(Samples::default()),
+        }
+    }
+
+    /// Returns the compressed value and the dictionary used, or `None` when the value
+    /// is too small or does not shrink.
+    pub fn compress(&self, value: &[u8]) -> Option<(Vec<u8>, Option<u32>)> {
+        if self.threshold == 0 || value.len() < self.threshold || value.len() > MAX_VALUE_SIZE {
+            return None;
+        }
+        let dictionary = self.current.read().unwrap().clone();
+        self.collect_sample(value);
+        let compressed = match dictionary.compressor.lock().unwrap().compress(value, LEVEL) {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                warn!("Can not compress value {}", e);
+                return None;
+            }
+        };
+        if compressed.len() + LENGTH_SIZE >= value.len() {
+            return None;
+        }
+        let mut bytes = Vec::with_capacity(LENGTH_SIZE + compressed.len());
+        bytes.extend_from_slice(&u32::to_be_bytes(value.len() as u32));
+        bytes.extend_from_slice(&compressed);
+        let dictionary_id = match dictionary.id {
+            0 => None,
+            id => Some(id)
+        };
+        Some((bytes, dictionary_id))
+    }
+
+    /// Length of a compressed value once decompressed, read from its prefix.
+    pub fn decompressed_length(bytes: &[u8]) -> Result<usize, String> {
+        match bytes.len() < LENGTH_SIZE {
+            true => Err(format!("Truncated compressed value of {} bytes", bytes.len())),
+            false => Ok(BigEndian::read_u32(&bytes[0..LENGTH_SIZE]) as usize)
+        }
+    }
+
+    pub fn decompress(&self, bytes: &[u8], dictionary_id: Option<u32>) -> Result<Vec<u8>, String> {
+        if bytes.len() < LENGTH_SIZE {
+            return Err(format!("Truncated compressed value of {} bytes", bytes.len()));
+        }
+        let dictionary = match dictionary_id {
+            None => self.plain.clone(),
+            Some(id) => match self.dictionaries.read().unwrap().get(&id) {
+                Some(dictionary) => dictionary.clone(),
+                None => return Err(format!("Unknown compression dictionary {}", id))
+            }
+        };
+        let length = BigEndian::read_u32(&bytes[0..LENGTH_SIZE]) as usize;
+        if length > MAX_VALUE_SIZE {
+            return Err(format!("Invalid uncompressed length {}", length));
+        }
+        let mut decompressor = dictionary.decompressor.lock().unwrap();
+        decompressor.decompress(&bytes[LENGTH_SIZE..], length).map_err(|e| e.to_string())
+    }
+
+    /// Trains the first dictionary once enough samples have been collected, then a new one
+    /// every `RETRAIN_VALUES` compressed values. The caller must persist it before calling
+    /// `use_dictionary`, otherwise the values compressed with it could not be read after a restart.
+    pub fn train_dictionary(&self) -> Option<(u32, Vec<u8>)> {
+        let has_dictionary = self.current.read().unwrap().id > 0;
+        let samples: Vec<Vec<u8>> = {
+            let mut samples = self.samples.lock().unwrap();
+            if samples.values.len() < DICTIONARY_SAMPLES || (has_dictionary && samples.compressed < RETRAIN_VALUES) {
+                return None;
+            }
+            samples.compressed = 0;
+            samples.values.drain(..).collect()
+        };
+        match dict::from_samples(&samples, DICTIONARY_SIZE) {
+            Ok(bytes) => {
+                let id = self.dictionaries.read().unwrap().keys().max().unwrap_or(&0) + 1;
+                Some((id, bytes))
+            }
+            Err(e) => {
+                warn!("Can not train a compression dictionary {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn use_dictionary(&self, id: u32, bytes: Vec<u8>) {
+        let dictionary = Arc::new(Dictionary::new(id, bytes));
+        self.dictionaries.write().unwrap().insert(id, dictionary.clone());
+        *self.current.write().unwrap() = dictionary;
+    }
+
+    pub fn has_dictionary(&self, id: u32) -> bool {
+        self.dictionaries.read().unwrap().contains_key(&id)
+    }
+
+    /// Keeps the last `DICTIONARY_SAMPLES` values, the oldest being overwritten first.
+    fn collect_sample(&self, value: &[u8]) {
+        let mut samples = self.samples.lock().unwrap();
+        let slot = (samples.compressed % DICTIONARY_SAMPLES as u64) as usize;
+        samples.compressed += 1;
+        if samples.values.len() < DICTIONARY_SAMPLES {
+            samples.values.push(value.to_vec());
+        } else {
+            samples.values[slot] = value.to_vec();
+        }
+    }
+}
+
+pub fn dictionary_key(id: u32) -> Vec<u8> {
+    let mut key = DICTIONARY_PREFIX.to_vec();
+    key.extend_from_slice(&u32::to_be_bytes(id));
+    key
+}
+
+pub fn dictionary_id(key: &[u8]) -> Option<u32> {
+    if key.len() == DICTIONARY_PREFIX.len() + 4 && key.starts_with(DICTIONARY_PREFIX) {
+        Some(BigEndian::read_u32(&key[DICTIONARY_PREFIX.len()..]))
+    } else {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &[u8] = br#"{"id": 1234, "name": "a product", "description": "a product description", "tags": ["a", "b"]}"#;
+
+    #[test]
+    fn compress_decompress_nominal() {
+        let compressor = ValueCompressor::new(16, vec![]);
+        let value = JSON.repeat(4);
+        let (compressed, dictionary_id) = compressor.compress(&value).unwrap();
+        assert!(compressed.len() < value.len());
+        assert_eq!(dictionary_id, None);
+        assert_eq!(compressor.decompress(&compressed, dictionary_id).unwrap(), value);
+        assert_eq!(ValueCompressor::decompressed_length(&compressed), Ok(value.len()));
+    }
+
+    #[test]
+    fn compress_below_threshold() {
+        let compressor = ValueCompressor::new(1024, vec![]);
+        assert_eq!(compressor.compress(JSON), None);
+    }
+
+    #[test]
+    fn compress_disabled() {
+        let compressor = ValueCompressor::new(0, vec![]);
+        assert_eq!(compressor.compress(&JSON.repeat(4)), None);
+    }
+
+    #[test]
+    fn decompress_unknown_dictionary() {
+        let compressor = ValueCompressor::new(16, vec![]);
+        let (compressed, _) = compressor.compress(&JSON.repeat(4)).unwrap();
+        assert!(compressor.decompress(&compressed, Some(7)).is_err());
+    }
+
+    #[test]
+    fn decompress_invalid_length() {
+        let compressor = ValueCompressor::new(16, vec![]);
+        let (mut compressed, _) = compressor.compress(&JSON.repeat(4)).unwrap();
+        compressed[0..LENGTH_SIZE].copy_from_slice(&u32::to_be_bytes(u32::MAX));
+        assert!(compressor.decompress(&compressed, None).is_err());
+    }
+
+    #[test]
+    fn dictionaries_are_retrained() {
+        let compressor = ValueCompressor::new(16, vec![]);
+        let compress_samples = |compressor: &ValueCompressor| {
+            for i in 0..DICTIONARY_SAMPLES {
+                compressor.compress(format!(r#"{{"id": {}, "name": "product {}", "price": {}}}"#, i, i * 7, i % 13).repeat(4).as_bytes());
+            }
+        };
+        compress_samples(&compressor);
+        let (id, bytes) = compressor.train_dictionary().unwrap();
+        assert_eq!(id, 1);
+        compressor.use_dictionary(id, bytes);
+        compress_samples(&compressor);
+        assert_eq!(compressor.train_dictionary(), None);
+        compressor.samples.lock().unwrap().compressed = RETRAIN_VALUES;
+        assert_eq!(compressor.train_dictionary().map(|(id, _)| id), Some(2));
+    }
+
+    #[test]
+    fn dictionary_key_round_trip() {
+        assert_eq!(dictionary_id(&dictionary_key(42)), Some(42));
+        assert_eq!(dictionary_id(b"format_version"), None);
+    }
+}
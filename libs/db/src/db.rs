@@ -1,17 +1,38 @@
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
-use log::{trace,error,warn};
-use byteorder::{BigEndian, ByteOrder};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use log::{trace,error,warn,info};
 use bytes::{Buf, BufMut, BytesMut};
-use rocksdb::{DB, DBCompressionType, Error, Options, IteratorMode};
+use rocksdb::backup::{BackupEngine, BackupEngineOptions, RestoreOptions};
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{ColumnFamily, DB, DBCompressionType, Direction, Error, IngestExternalFileOptions, Options, IteratorMode, WriteBatch};
+use serde::Deserialize;
+use byteorder::{BigEndian, ByteOrder};
 
-use crate::byte_utils::{convert_bytes_to_u64, u64_to_bytes};
+use crate::byte_utils::{convert_bytes_to_u64, prefix_successor, u64_to_bytes};
+use crate::cache::{HotCache, in_range};
+use crate::compression::{DICTIONARY_PREFIX, ValueCompressor, dictionary_id, dictionary_key};
+use crate::dump::{DumpEntry, read_entry, read_header, write_entry, write_header};
+use crate::encryption::ValueEncryptor;
+use crate::eviction::{ACCESS_TIMES_CF, initial_seed, sample_least_recently_used};
+use crate::hotkeys::{Access, HotKeys};
+use crate::latency::{Latencies, SlowLog};
+use crate::metrics::Metrics;
+use crate::record::{FORMAT_VERSION, Record};
 use crate::response::Response;
+use crate::sst::{SstKind, sst_kind};
+use crate::stats::Stats;
+use crate::tags::{self, TAGS_CF, index_key, index_prefix};
 
 #[derive(Debug)]
 struct DatabaseHolder {
-    rocksdb: DB,
+    rocksdb: Arc<DB>,
     cas: u64,
+    eviction_seed: u64,
 }
 
 impl DatabaseHolder {
@@ -21,73 +42,546 @@ impl DatabaseHolder {
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseOptions {
+    /// Size in bytes of the in-memory hot tier, 0 disables it.
+    pub hot_cache_size: usize,
+    /// Size of the SST files above which least recently used keys are evicted, 0 for no limit.
+    pub max_disk_bytes: u64,
+    /// Size in bytes from which values are compressed with zstd, 0 disables it.
+    pub compression_threshold: usize,
+    /// YAML file holding the keys values are encrypted with, none disables encryption.
+    pub encryption_key_file: Option<String>,
+    /// Time to live in seconds of the values set with an expiration time of 0, 0 to keep
+    /// the expiration time given.
+    pub default_ttl: u64,
+    /// Namespaces besides the default one, each stored in its own column families.
+    pub namespaces: Vec<NamespaceOptions>,
+    /// Directory of the incremental backups taken by `backup`, none disables them.
+    pub backup_dir: Option<String>,
+    /// Number of backups kept, at least one, the older ones being purged after each backup.
+    pub backups_to_keep: usize,
+    /// Directory the checkpoints taken by the `checkpoint` command are created in, none
+    /// disables the command.
+    pub checkpoint_dir: Option<String>,
+    /// Staging directory of the SST files moved in by the `ingest` command, none disables
+    /// the command.
+    pub ingest_dir: Option<String>,
+    /// Time in seconds the write-ahead log is kept once obsolete, for replicas to catch up
+    /// without a full sync, 0 to delete it right away.
+    pub wal_ttl: u64,
+    /// Starts rejecting writes, until switched back with `set_read_only`.
+    pub read_only: bool,
+    /// Only applies the updates of a primary: writes are rejected, and the hot cache as well
+    /// as the eviction are disabled, the primary deciding which keys are removed.
+    pub replica: bool,
+    /// Time in milliseconds from which a command is kept in the slow log, 0 disables it.
+    pub slow_log_threshold: u64,
+    /// Number of commands kept in the slow log, the oldest being dropped.
+    pub slow_log_size: usize,
+    /// One read or write in this number is counted by the hot key tracker, 0 disables it.
+    pub hot_keys_sample_rate: u64,
+    /// Length in seconds of the windows over which the hottest keys are reported.
+    pub hot_keys_window: u64,
+}
+
+/// Settings of a namespace, which the default namespace takes from `DatabaseOptions`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NamespaceOptions {
+    pub name: String,
+    pub default_ttl: u64,
+    pub hot_cache_size: usize,
+    pub max_disk_bytes: u64,
+}
+
+/// Maximum number of keys removed by a single `evict` call.
+const MAX_EVICTIONS_PER_RUN: u32 = 10_000;
+
+/// Number of keys sampled without the lock, then evicted in a single batch under it.
+const EVICTIONS_PER_BATCH: usize = 100;
+
+/// Column family holding the data directory metadata, such as its record format version.
+const META_CF: &str = "meta";
+const FORMAT_VERSION_KEY: &[u8] = b"format_version";
+/// Sequence number of the primary from which a replica requests the next updates.
+const REPLICATION_SEQUENCE_KEY: &[u8] = b"replication_sequence";
+const MIGRATION_BATCH_SIZE: usize = 10_000;
+
+/// Maximum number of records moved to the current encryption key by a single `reencrypt` call,
+/// and of records it reads to find them.
+const MAX_REENCRYPTIONS_PER_RUN: usize = 10_000;
+const MAX_REENCRYPTION_SCANS_PER_RUN: usize = 100_000;
+
+/// Number of records written by a single batch when loading a dump.
+const LOAD_BATCH_SIZE: usize = 1_000;
+/// Number of records between two progress reports of `dump` and `load`.
+const PROGRESS_INTERVAL: u64 = 100_000;
+
+/// Number of keys listed by a `keys` call when no limit is given, and the highest limit.
+const DEFAULT_KEYS_LIMIT: u64 = 100;
+const MAX_KEYS_LIMIT: u64 = 10_000;
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+const NAMESPACE_CF_PREFIX: &str = "namespace:";
+
+/// Whether a read answers a client, only those being counted in the stats and access times,
+/// or looks up the current value for a write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReadKind {
+    Client,
+    Update,
+}
+
+/// A write batch of the write-ahead log, with the sequence number of its first write.
+pub type Update = (u64, Vec<u8>);
+
+/// State shared by the namespaces of a data directory.
+struct Shared {
+    compressor: ValueCompressor,
+    encryptor: Option<ValueEncryptor>,
+    namespaces: RwLock<HashMap<String, Weak<Database>>>,
+    backup_engine: Option<Mutex<BackupEngine>>,
+    backups_to_keep: usize,
+    checkpoint_dir: Option<String>,
+    ingest_dir: Option<String>,
+    replica: bool,
+    read_only: AtomicBool,
+    /// Set while a replica replaces its data with a checkpoint of its primary.
+    syncing: AtomicBool,
+    metrics: Metrics,
+    latencies: Latencies,
+    slow_log: SlowLog,
+    hot_keys_sample_rate: u64,
+    hot_keys_window: u64,
+}
+
+/// A namespace of the data directory, with its own column families, cache, quota and stats.
+/// The default namespace is the one returned by `open` and owns the other ones.
 pub struct Database {
+    name: String,
+    data_cf: String,
+    access_times_cf: String,
+    tags_cf: String,
     mutex: Mutex<DatabaseHolder>,
+    hot_cache: Option<HotCache>,
+    expired_keys: Mutex<HashSet<Vec<u8>>>,
+    accessed_keys: Mutex<HashMap<Vec<u8>, u64>>,
+    max_disk_bytes: u64,
+    default_ttl: u64,
+    shared: Arc<Shared>,
+    namespaces: Vec<Arc<Database>>,
+    stats: Stats,
+    hot_keys: HotKeys,
+    reencryption: Mutex<Reencryption>,
+}
+
+/// Progress of the re-encryption of a namespace, each run resuming from the key the previous
+/// one stopped at. Records are always written with the current key, so a namespace is done
+/// once scanned to the end.
+#[derive(Debug, Default)]
+struct Reencryption {
+    next_key: Vec<u8>,
+    done: bool,
 }
 
 impl Database {
     pub fn open(path: &str) -> Arc<Database> {
-        let mut db_opts = Options::default();
-        db_opts.set_compression_type(DBCompressionType::Lz4);
-        db_opts.set_max_write_buffer_number(16);
+        Database::open_with_options(path, &DatabaseOptions::default())
+    }
+
+    pub fn open_with_options(path: &str, options: &DatabaseOptions) -> Arc<Database> {
+        let mut db_opts = rocksdb_options();
         db_opts.create_if_missing(true);
-        let initial_db = DB::open(&db_opts, path).unwrap();
-        let dh = DatabaseHolder { rocksdb: initial_db, cas: 0 };
-        Arc::new(Database {
-            mutex: Mutex::new(dh),
-        })
+        db_opts.create_missing_column_families(true);
+        if options.wal_ttl > 0 {
+            db_opts.set_wal_ttl_seconds(options.wal_ttl);
+        }
+        // Every existing column family has to be opened, including those of removed namespaces
+        let mut column_families = DB::list_cf(&db_opts, path).unwrap_or_default();
+        for cf in [ACCESS_TIMES_CF, TAGS_CF, META_CF].iter() {
+            column_families.push(cf.to_string());
+        }
+        for namespace in &options.namespaces {
+            if namespace.name.is_empty() || namespace.name == DEFAULT_NAMESPACE {
+                panic!("Invalid namespace name {:?}", namespace.name);
+            }
+            column_families.push(namespace_cf(&namespace.name));
+            column_families.push(namespace_index_cf(&namespace.name, ACCESS_TIMES_CF));
+            column_families.push(namespace_index_cf(&namespace.name, TAGS_CF));
+        }
+        column_families.retain(|cf| cf != DEFAULT_NAMESPACE);
+        column_families.sort();
+        column_families.dedup();
+        let initial_db = Arc::new(DB::open_cf(&db_opts, path, column_families).unwrap());
+        match migrate_records(&initial_db) {
+            Ok(0) => (),
+            Ok(migrated) => info!("Migrated {} records to format version {}", migrated, FORMAT_VERSION),
+            Err(e) => panic!("Can not migrate records to format version {} {}", FORMAT_VERSION, e)
+        }
+        let encryptor = options.encryption_key_file.as_ref().map(|path| match ValueEncryptor::from_file(path) {
+            Ok(encryptor) => encryptor,
+            Err(e) => panic!("Can not load the encryption keys {}", e)
+        });
+        let backup_engine = options.backup_dir.as_ref().map(|backup_dir| match BackupEngine::open(&BackupEngineOptions::default(), backup_dir) {
+            Ok(backup_engine) => Mutex::new(backup_engine),
+            Err(e) => panic!("Can not open the backups in {} {}", backup_dir, e)
+        });
+        let shared = Arc::new(Shared {
+            compressor: ValueCompressor::new(options.compression_threshold, read_dictionaries(&initial_db)),
+            encryptor,
+            namespaces: RwLock::new(HashMap::new()),
+            backup_engine,
+            backups_to_keep: options.backups_to_keep,
+            checkpoint_dir: options.checkpoint_dir.clone(),
+            ingest_dir: options.ingest_dir.clone(),
+            replica: options.replica,
+            read_only: AtomicBool::new(options.read_only || options.replica),
+            syncing: AtomicBool::new(false),
+            metrics: Metrics::default(),
+            latencies: Latencies::default(),
+            slow_log: SlowLog::new(Duration::from_millis(options.slow_log_threshold), options.slow_log_size),
+            hot_keys_sample_rate: options.hot_keys_sample_rate,
+            hot_keys_window: options.hot_keys_window,
+        });
+        let namespace_options = |namespace: &NamespaceOptions| match options.replica {
+            true => NamespaceOptions { hot_cache_size: 0, max_disk_bytes: 0, ..namespace.clone() },
+            false => namespace.clone()
+        };
+        let namespaces = options.namespaces.iter()
+            .map(|namespace| Database::new(&namespace_options(namespace), initial_db.clone(), shared.clone(), vec![]))
+            .collect();
+        let default_namespace = NamespaceOptions {
+            name: DEFAULT_NAMESPACE.to_string(),
+            default_ttl: options.default_ttl,
+            hot_cache_size: options.hot_cache_size,
+            max_disk_bytes: options.max_disk_bytes,
+        };
+        Database::new(&namespace_options(&default_namespace), initial_db, shared, namespaces)
+    }
+
+    fn new(options: &NamespaceOptions, rocksdb: Arc<DB>, shared: Arc<Shared>, namespaces: Vec<Arc<Database>>) -> Arc<Database> {
+        let (data_cf, access_times_cf, tags_cf) = match options.name.as_str() {
+            DEFAULT_NAMESPACE => (DEFAULT_NAMESPACE.to_string(), ACCESS_TIMES_CF.to_string(), TAGS_CF.to_string()),
+            name => (namespace_cf(name), namespace_index_cf(name, ACCESS_TIMES_CF), namespace_index_cf(name, TAGS_CF))
+        };
+        let hot_cache = match options.hot_cache_size {
+            0 => None,
+            size => Some(HotCache::new(size))
+        };
+        let database = Arc::new(Database {
+            name: options.name.clone(),
+            data_cf,
+            access_times_cf,
+            tags_cf,
+            mutex: Mutex::new(DatabaseHolder { rocksdb, cas: 0, eviction_seed: initial_seed() }),
+            hot_cache,
+            expired_keys: Mutex::new(HashSet::new()),
+            accessed_keys: Mutex::new(HashMap::new()),
+            max_disk_bytes: options.max_disk_bytes,
+            default_ttl: options.default_ttl,
+            shared: shared.clone(),
+            namespaces,
+            stats: Stats::default(),
+            hot_keys: HotKeys::new(shared.hot_keys_sample_rate, shared.hot_keys_window),
+            reencryption: Mutex::new(Reencryption::default()),
+        });
+        shared.namespaces.write().unwrap().insert(options.name.clone(), Arc::downgrade(&database));
+        database
+    }
+
+    /// Replaces the data directory at `path` with the latest backup found in `backup_dir`.
+    /// To be called before the data directory is opened.
+    pub fn restore(backup_dir: &str, path: &str) -> Result<(), Error> {
+        let mut backup_engine = BackupEngine::open(&BackupEngineOptions::default(), backup_dir)?;
+        backup_engine.restore_from_latest_backup(path, path, &RestoreOptions::default())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Looks a namespace of the same data directory up by name.
+    pub fn namespace(&self, name: &str) -> Option<Arc<Database>> {
+        self.shared.namespaces.read().unwrap().get(name).and_then(Weak::upgrade)
+    }
+
+    /// Whether writes are rejected, as they are on replicas.
+    pub fn is_read_only(&self) -> bool {
+        self.shared.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Switches every namespace between read only and read-write, a replica staying read only.
+    pub fn set_read_only(&self, read_only: bool) -> Result<(), String> {
+        if self.shared.replica && !read_only {
+            return Err(String::from("A replica can not accept writes"));
+        }
+        if self.shared.read_only.swap(read_only, Ordering::Relaxed) != read_only {
+            info!("Switched to {}", if read_only { "read only" } else { "read-write" });
+        }
+        Ok(())
+    }
+
+    /// The namespaces owned by the default one, which background tasks have to go through.
+    pub fn namespaces(&self) -> &[Arc<Database>] {
+        &self.namespaces
+    }
+
+    fn data<'a>(&self, rocksdb: &'a DB) -> &'a ColumnFamily {
+        rocksdb.cf_handle(&self.data_cf).unwrap()
+    }
+
+    fn access_times<'a>(&self, rocksdb: &'a DB) -> &'a ColumnFamily {
+        rocksdb.cf_handle(&self.access_times_cf).unwrap()
+    }
+
+    fn tags<'a>(&self, rocksdb: &'a DB) -> &'a ColumnFamily {
+        rocksdb.cf_handle(&self.tags_cf).unwrap()
+    }
+
+    /// Deadline of a value set now, `ttl` 0 meaning the namespace default.
+    fn deadline(&self, now: u64, ttl: u64) -> u64 {
+        match ttl {
+            0 => now + self.default_ttl,
+            ttl => now + ttl
+        }
     }
 
     pub fn get(&self, keys: Vec<&[u8]>, include_cas: bool) -> Response {
         let mut bytes_mut = BytesMut::new();
-        let dh = self.mutex.lock().unwrap();
-        let rocksdb = &dh.rocksdb;
+        let mut expired = Vec::new();
         for key in keys {
-            match rocksdb.get(key) {
-                Ok(Some(value)) => process_get_request(key, &value, &mut bytes_mut, include_cas),
+            match self.get_record(key, ReadKind::Client) {
+                Ok(Some(record)) if record.is_expired(current_second()) => expired.push(key),
+                Ok(Some(record)) => process_get_request(key, &record, &mut bytes_mut, include_cas),
                 _ => ()
             };
         }
+        self.enqueue_expired(expired);
         finish_get_response(&mut bytes_mut)
     }
 
+    /// Counters of the instance, shared by every namespace.
+    pub fn metrics(&self) -> &Metrics {
+        &self.shared.metrics
+    }
+
+    /// Latency distribution of the commands, shared by every namespace.
+    pub fn latencies(&self) -> &Latencies {
+        &self.shared.latencies
+    }
+
+    /// Counts a command of any listener in the metrics and the latency histograms.
+    pub fn record_command(&self, name: &str, duration: Duration) {
+        self.metrics().record_command(name, duration);
+        self.latencies().record(name, duration);
+    }
+
+    pub fn slow_log(&self) -> &SlowLog {
+        &self.shared.slow_log
+    }
+
+    /// Hottest keys of the namespace over the last complete window, as `HOTKEY` lines.
+    pub fn hot_keys(&self) -> Response {
+        Response::Value {
+            value: self.hot_keys.serialize(current_second()),
+        }
+    }
+
+    /// The metrics of the instance and of every namespace, in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let now = current_second();
+        let mut namespaces = vec![(self.name.clone(), self.metric_values(), self.hot_keys.top(now))];
+        namespaces.extend(self.namespaces.iter()
+            .map(|namespace| (namespace.name.clone(), namespace.metric_values(), namespace.hot_keys.top(now))));
+        self.shared.metrics.render(self.is_read_only(), &namespaces)
+    }
+
+    /// Stats and RocksDB properties of the namespace, by metric name.
+    fn metric_values(&self) -> HashMap<&'static str, u64> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut values = HashMap::new();
+        values.insert("expired_unfetched_total", load(&self.stats.expired_unfetched));
+        values.insert("reclaimed_total", load(&self.stats.reclaimed));
+        values.insert("hot_cache_hits_total", load(&self.stats.hot_cache_hits));
+        values.insert("hot_cache_misses_total", load(&self.stats.hot_cache_misses));
+        values.insert("evictions_total", load(&self.stats.evictions));
+        values.insert("compressed_bytes_in_total", load(&self.stats.compressed_bytes_in));
+        values.insert("compressed_bytes_out_total", load(&self.stats.compressed_bytes_out));
+        values.insert("reencrypted_total", load(&self.stats.reencrypted));
+        let properties = [
+            ("rocksdb_estimate_num_keys", "rocksdb.estimate-num-keys"),
+            ("rocksdb_total_sst_files_size_bytes", "rocksdb.total-sst-files-size"),
+            ("rocksdb_estimate_live_data_size_bytes", "rocksdb.estimate-live-data-size"),
+            ("rocksdb_memtables_size_bytes", "rocksdb.cur-size-all-mem-tables"),
+            ("rocksdb_pending_compaction_bytes", "rocksdb.estimate-pending-compaction-bytes"),
+        ];
+        let dh = self.mutex.lock().unwrap();
+        let rocksdb = &dh.rocksdb;
+        for &(name, property) in properties.iter() {
+            if let Ok(Some(value)) = rocksdb.property_int_value_cf(self.data(rocksdb), property) {
+                values.insert(name, value);
+            }
+        }
+        values
+    }
+
+    pub fn stats(&self) -> Response {
+        Response::Value {
+            value: self.stats.serialize(self.is_read_only()),
+        }
+    }
+
     pub fn delete(&self, key: &[u8]) -> Response {
         let dh = self.mutex.lock().unwrap();
         let rocksdb = &dh.rocksdb;
-        match rocksdb.delete(key) {
+        self.invalidate(key);
+        let mut batch = WriteBatch::default();
+        let record = read_record(rocksdb, self.data(rocksdb), key);
+        self.delete_in_batch(rocksdb, &mut batch, key, record.as_ref());
+        match rocksdb.write(batch) {
             Ok(()) => Response::Stored,
             Err(_) => Response::NotFoundError
         }
     }
 
-    fn get_raw_value(&self, key: &[u8]) -> Option<Vec<u8>> {
-        let dh = self.mutex.lock().unwrap();
-        let rocksdb = &dh.rocksdb;
-        match rocksdb.get(key) {
-            Ok(Some(value)) => {
-                let expiration = BigEndian::read_u64(&value[0..8]);
-                if expiration > current_second() {
-                    Some(value[20..].to_vec())
-                } else {
+    /// The record of a key read by a client, unless it is missing or expired.
+    pub fn get_live_record(&self, key: &[u8]) -> Option<Record> {
+        self.live_record(key, ReadKind::Client)
+    }
+
+    /// The record of a key about to be changed, unless it is missing or expired, which is not
+    /// counted as a read of the key.
+    pub fn get_live_record_for_update(&self, key: &[u8]) -> Option<Record> {
+        self.live_record(key, ReadKind::Update)
+    }
+
+    fn live_record(&self, key: &[u8], read: ReadKind) -> Option<Record> {
+        match self.get_record(key, read) {
+            Ok(Some(record)) => {
+                if record.is_expired(current_second()) {
+                    self.enqueue_expired(vec![key]);
                     None
+                } else {
+                    Some(record)
                 }
             },
             _ => None,
         }
     }
 
+    /// Queues expired keys met by a read so that `reclaim_expired` deletes them later,
+    /// keeping the deletion itself off the read path.
+    fn enqueue_expired(&self, keys: Vec<&[u8]>) {
+        // A replica leaves the deletion of expired keys to its primary
+        if keys.is_empty() || self.shared.replica {
+            return;
+        }
+        let mut expired_keys = self.expired_keys.lock().unwrap();
+        for key in keys {
+            if expired_keys.insert(key.to_vec()) {
+                Stats::add(&self.stats.expired_unfetched, 1);
+            }
+        }
+    }
+
+    /// Deletes, in a single batch, the queued keys that are still expired. The keys stay
+    /// queued while the instance is read only.
+    pub fn reclaim_expired(&self) -> u32 {
+        if self.is_read_only() {
+            return 0;
+        }
+        let keys: Vec<Vec<u8>> = {
+            let mut expired_keys = self.expired_keys.lock().unwrap();
+            expired_keys.drain().collect()
+        };
+        if keys.is_empty() {
+            return 0;
+        }
+        let dh = self.mutex.lock().unwrap();
+        let rocksdb = &dh.rocksdb;
+        let data = self.data(rocksdb);
+        let mut batch = WriteBatch::default();
+        let mut reclaimed = 0;
+        for key in keys {
+            // The key may have been set again since the read queued it
+            match read_record(rocksdb, data, &key) {
+                Some(record) if record.is_expired(current_second()) => {
+                    self.invalidate(&key);
+                    self.delete_in_batch(rocksdb, &mut batch, &key, Some(&record));
+                    reclaimed += 1;
+                }
+                _ => ()
+            }
+        }
+        match rocksdb.write(batch) {
+            Ok(()) => {
+                Stats::add(&self.stats.reclaimed, reclaimed as u64);
+                reclaimed
+            }
+            Err(e) => {
+                warn!("Can not reclaim expired keys {}", e);
+                0
+            }
+        }
+    }
+
     pub fn insert(&self, key: &[u8], flags: u32, ttl: u64, value: &[u8]) -> Response {
-        let deadline = current_second() + ttl;
-        self.insert_with_deadline(key, flags, deadline, value)
+        self.insert_with_tags(key, flags, ttl, value, vec![])
     }
 
+    /// Sets a key which `invalidate_tag` deletes along with the other keys sharing one of its tags.
+    pub fn insert_with_tags(&self, key: &[u8], flags: u32, ttl: u64, value: &[u8], tags: Vec<&[u8]>) -> Response {
+        if let Err(msg) = tags::validate(&tags) {
+            return Response::ClientError { msg };
+        }
+        let now = current_second();
+        let mut record = Record::new(self.deadline(now, ttl), 0, flags, value.to_vec());
+        record.created_at = Some(now);
+        record.last_access = Some(now);
+        record.tags = tags.iter().map(|tag| tag.to_vec()).collect();
+        self.store(key, record)
+    }
+
+    /// Sets a key only if it holds no live value, answering `NotStored` otherwise.
     pub fn insert_if_not_present(&self, key: &[u8], flags: u32, ttl: u64, value: &[u8]) -> Response {
-        match self.get_raw_value(key) {
-            Some(_) => Response::ServerError,
+        match self.get_live_record_for_update(key) {
+            Some(_) => Response::NotStored,
             _ => self.insert(key, flags, ttl, value)
         }
     }
 
+    /// Sets a key only if its live value still has the given CAS value, answering
+    /// `NotStored` when it changed and `NotFoundError` when it is gone.
+    pub fn check_and_set(&self, key: &[u8], flags: u32, ttl: u64, value: &[u8], cas: u64) -> Response {
+        let now = current_second();
+        let mut record = Record::new(self.deadline(now, ttl), 0, flags, value.to_vec());
+        record.created_at = Some(now);
+        record.last_access = Some(now);
+        self.store_if(key, record, Some(cas))
+    }
+
+    /// Sets a key only if it already holds a live value.
+    pub fn replace(&self, key: &[u8], flags: u32, ttl: u64, value: &[u8]) -> Response {
+        match self.get_live_record_for_update(key) {
+            Some(_) => self.insert(key, flags, ttl, value),
+            _ => Response::NotStored
+        }
+    }
+
+    /// Gives a live key a new time to live, keeping its value.
+    pub fn touch(&self, key: &[u8], ttl: u64) -> Response {
+        match self.get_live_record_for_update(key) {
+            Some(mut record) => {
+                let now = current_second();
+                record.deadline = self.deadline(now, ttl);
+                record.last_access = Some(now);
+                self.store(key, record)
+            }
+            _ => Response::NotFoundError
+        }
+    }
+
     pub fn append(&self, key: &[u8], flags: u32, ttl: u64, value: &[u8]) -> Response {
         let f = |original: Vec<u8>, appendage: &[u8]| -> Vec<u8> {
             let mut bytes_mut = BytesMut::with_capacity(original.len() + appendage.len());
@@ -108,35 +602,70 @@ impl Database {
         self.update_value(key, flags, ttl, value, f)
     }
 
-    fn insert_with_deadline(&self, key: &[u8], flags: u32, deadline: u64, value: &[u8]) -> Response {
-        let deadline_bytes = &u64::to_be_bytes(deadline);
-        let mut flag_bytes = [0; 4];
-        BigEndian::write_u32(&mut flag_bytes, flags);
-        self.insert_raw(key, deadline_bytes, &mut flag_bytes, value)
+    /// Writes the record with a new CAS value.
+    fn store(&self, key: &[u8], record: Record) -> Response {
+        self.store_if(key, record, None)
     }
 
-    fn insert_raw(&self, key: &[u8], deadline_bytes: &[u8], flag_bytes: &[u8], value: &[u8]) -> Response {
+    /// Writes the record with a new CAS value if the live record of the key still has the
+    /// `expected_cas` one, when given.
+    fn store_if(&self, key: &[u8], mut record: Record, expected_cas: Option<u64>) -> Response {
+        let mut sealed = match self.seal(key, &record) {
+            Ok(sealed) => sealed,
+            Err(e) => {
+                warn!("Can not store key {:?} {}", String::from_utf8_lossy(key), e);
+                return Response::ServerError;
+            }
+        };
         let mut dh = self.mutex.lock().unwrap();
-        let cas = dh.increment_cas();
-        let mut bytes_mut = BytesMut::with_capacity(18 + value.len());
-        bytes_mut.put_slice(deadline_bytes);
-        bytes_mut.put_u64(cas);
-        bytes_mut.put_slice(&flag_bytes);
-        bytes_mut.put_slice(value);
+        let current = read_record(&dh.rocksdb, self.data(&dh.rocksdb), key);
+        if let Some(expected_cas) = expected_cas {
+            match &current {
+                Some(current) if current.is_expired(current_second()) => return Response::NotFoundError,
+                Some(current) if current.cas != expected_cas => return Response::NotStored,
+                Some(_) => (),
+                None => return Response::NotFoundError
+            }
+        }
+        record.cas = dh.increment_cas();
+        sealed.cas = record.cas;
         let rocksdb = &dh.rocksdb;
+        let mut batch = WriteBatch::default();
+        if let Some(current) = &current {
+            self.unindex_dropped_tags(rocksdb, &mut batch, key, current, &record.tags);
+        }
+        batch.put_cf(self.data(rocksdb), key, sealed.encode());
+        batch.put_cf(self.access_times(rocksdb), key, u64::to_be_bytes(current_second()));
+        for tag in &record.tags {
+            batch.put_cf(self.tags(rocksdb), index_key(tag, key), b"");
+        }
 
-        match rocksdb.put(key, bytes_mut.bytes()) {
-            Ok(_) => Response::Stored,
-            _ => Response::ServerError
+        match rocksdb.write(batch) {
+            Ok(_) => {
+                if let Some(hot_cache) = &self.hot_cache {
+                    hot_cache.put(key, record);
+                }
+                self.hot_keys.record(key, Access::Write, current_second());
+                Response::Stored
+            }
+            _ => {
+                self.invalidate(key);
+                Response::ServerError
+            }
         }
     }
 
     fn update_value<'a, I>(&self, key: &[u8], flags: u32, ttl: u64, value: &'a [u8], f: I) -> Response
         where I: Fn(Vec<u8>, &'a [u8]) -> Vec<u8>
     {
-        match self.get_raw_value(key) {
+        match self.get_live_record_for_update(key) {
             Some(original) => {
-                self.insert(key, flags, ttl, &f(original, value))
+                let now = current_second();
+                let mut record = Record::new(self.deadline(now, ttl), 0, flags, f(original.value, value));
+                record.created_at = original.created_at.or(Some(now));
+                record.last_access = Some(now);
+                record.tags = original.tags;
+                self.store(key, record)
             }
             _ => Response::NotStored
         }
@@ -147,24 +676,25 @@ impl Database {
     }
 
     pub fn decrement(&self, key: &[u8], increment: u64) -> Response {
-        self.update_number(key, increment, |a, b| { a - b })
+        self.update_number(key, increment, |a, b| { a.saturating_sub(b) })
     }
 
     fn update_number<'a, I>(&self, key: &[u8], increment: u64, f: I) -> Response
         where I: Fn(u64, u64) -> u64
     {
-        match self.get_record(key) {
-            Ok(Some(value)) => {
-                let expiration = BigEndian::read_u64(&value[0..8]);
-
-                if expiration < current_second() {
+        match self.get_record(key, ReadKind::Update) {
+            Ok(Some(mut record)) => {
+                if record.is_expired(current_second()) {
+                    self.enqueue_expired(vec![key]);
                     Response::NotFoundError
                 } else {
-                    match convert_bytes_to_u64(&value[20..]) {
+                    match convert_bytes_to_u64(&record.value) {
                         Ok(stored_value) => {
                             let updated_value = f(stored_value, increment);
                             let new_value_bytes = u64_to_bytes(updated_value);
-                            match self.insert_raw(key, &value[0..8], &value[16..20], &new_value_bytes) {
+                            record.value = new_value_bytes.clone();
+                            record.last_access = Some(current_second());
+                            match self.store(key, record) {
                                 Response::Stored => {
                                     let mut bytes_mut = BytesMut::with_capacity(new_value_bytes.len() + 2);
                                     bytes_mut.put_slice(&new_value_bytes);
@@ -187,23 +717,795 @@ impl Database {
         }
     }
 
-    fn get_record(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    fn get_record(&self, key: &[u8], read: ReadKind) -> Result<Option<Record>, Error> {
+        if self.is_syncing() {
+            return Ok(None);
+        }
+        if read == ReadKind::Client {
+            self.hot_keys.record(key, Access::Read, current_second());
+        }
+        // Recorded before the hot cache, whose hits are the most recently used keys
+        if self.max_disk_bytes > 0 {
+            self.accessed_keys.lock().unwrap().insert(key.to_vec(), current_second());
+        }
+        if let Some(hot_cache) = &self.hot_cache {
+            if let Some(record) = hot_cache.get(key) {
+                if read == ReadKind::Client {
+                    Stats::add(&self.stats.hot_cache_hits, 1);
+                }
+                return Ok(Some(record));
+            }
+            if read == ReadKind::Client {
+                Stats::add(&self.stats.hot_cache_misses, 1);
+            }
+        }
+        let dh = self.mutex.lock().unwrap();
+        let rocksdb = &dh.rocksdb;
+        let record = match rocksdb.get_cf(self.data(rocksdb), key)? {
+            Some(bytes) => decode_record(key, &bytes).and_then(|record| self.unseal(key, record)),
+            None => None
+        };
+        // Filled while holding the lock so that a concurrent write can not be overwritten
+        if let (Some(hot_cache), Some(record)) = (&self.hot_cache, &record) {
+            hot_cache.put(key, record.clone());
+        }
+        Ok(record)
+    }
+
+    /// Persists the access times collected by reads, then removes sampled least recently
+    /// used keys until the SST files fit in `max_disk_bytes` again. Blocks on RocksDB
+    /// compactions, so it must not run on a worker of the runtime. Nothing is evicted while
+    /// the instance is read only.
+    pub fn evict(&self) -> u32 {
+        if self.max_disk_bytes == 0 || self.is_read_only() {
+            return 0;
+        }
+        self.flush_access_times();
+        let (rocksdb, mut seed) = {
+            let dh = self.mutex.lock().unwrap();
+            (dh.rocksdb.clone(), dh.eviction_seed)
+        };
+        let data = self.data(&rocksdb);
+        let disk_usage = match rocksdb.property_int_value_cf(data, "rocksdb.total-sst-files-size") {
+            Ok(Some(size)) => size,
+            _ => return 0
+        };
+        if disk_usage <= self.max_disk_bytes {
+            return 0;
+        }
+        // The SST files being compressed, a key frees its share of them rather than its size
+        let key_count = match rocksdb.property_int_value_cf(data, "rocksdb.estimate-num-keys") {
+            Ok(Some(count)) if count > 0 => count,
+            _ => return 0
+        };
+        let freed_per_key = (disk_usage / key_count).max(1);
+        let access_times = self.access_times(&rocksdb);
+        let mut to_free = disk_usage - self.max_disk_bytes;
+        let mut evicted = 0;
+        let mut evicted_range: Option<(Vec<u8>, Vec<u8>)> = None;
+        while to_free > 0 && evicted < MAX_EVICTIONS_PER_RUN {
+            // Sampled without the lock, so that the writes of the clients go on meanwhile
+            let wanted = ((to_free - 1) / freed_per_key + 1)
+                .min(u64::from(MAX_EVICTIONS_PER_RUN - evicted))
+                .min(EVICTIONS_PER_BATCH as u64) as usize;
+            let mut keys: Vec<Vec<u8>> = Vec::with_capacity(wanted);
+            for _ in 0..wanted {
+                match sample_least_recently_used(&rocksdb, access_times, &mut seed) {
+                    Some(key) if !keys.contains(&key) => keys.push(key),
+                    Some(_) => (),
+                    None => break
+                }
+            }
+            if keys.is_empty() {
+                break;
+            }
+            let dh = self.mutex.lock().unwrap();
+            // The data may have been replaced by a full sync while sampling
+            if !Arc::ptr_eq(&dh.rocksdb, &rocksdb) {
+                break;
+            }
+            let mut batch = WriteBatch::default();
+            let mut batch_evicted = 0;
+            for key in &keys {
+                batch.delete_cf(access_times, key);
+                if let Some(record) = read_record(&rocksdb, data, key) {
+                    self.delete_in_batch(&rocksdb, &mut batch, key, Some(&record));
+                    batch_evicted += 1;
+                }
+                self.invalidate(key);
+            }
+            if let Err(e) = rocksdb.write(batch) {
+                warn!("Can not evict {} keys {}", keys.len(), e);
+                break;
+            }
+            drop(dh);
+            to_free = to_free.saturating_sub(freed_per_key * batch_evicted as u64);
+            evicted += batch_evicted;
+            let first = keys.iter().min().unwrap().clone();
+            let last = keys.iter().max().unwrap().clone();
+            evicted_range = match evicted_range {
+                Some((previous_first, previous_last)) => Some((previous_first.min(first), previous_last.max(last))),
+                None => Some((first, last))
+            };
+        }
+        self.mutex.lock().unwrap().eviction_seed = seed;
+        Stats::add(&self.stats.evictions, evicted as u64);
+        // Deleted records only leave the SST files once compacted, both bounds included
+        if let Some((first, last)) = evicted_range {
+            rocksdb.compact_range_cf(self.data(&rocksdb), Some(&first), Some(&last));
+        }
+        evicted
+    }
+
+    fn flush_access_times(&self) {
+        let accessed_keys: Vec<(Vec<u8>, u64)> = {
+            let mut accessed_keys = self.accessed_keys.lock().unwrap();
+            accessed_keys.drain().collect()
+        };
+        if accessed_keys.is_empty() {
+            return;
+        }
+        let dh = self.mutex.lock().unwrap();
+        let rocksdb = &dh.rocksdb;
+        let access_times = self.access_times(rocksdb);
+        let mut batch = WriteBatch::default();
+        for (key, last_access) in accessed_keys {
+            // Keys deleted since their last read must not come back in the access times
+            if let Ok(Some(_)) = rocksdb.get_cf(self.data(rocksdb), &key) {
+                batch.put_cf(access_times, key, u64::to_be_bytes(last_access));
+            }
+        }
+        if let Err(e) = rocksdb.write(batch) {
+            warn!("Can not store access times {}", e);
+        }
+    }
+
+    /// Adds to `batch` the deletion of a key, with its access time and the index entries of
+    /// the tags of its `record`, when known.
+    fn delete_in_batch(&self, rocksdb: &DB, batch: &mut WriteBatch, key: &[u8], record: Option<&Record>) {
+        batch.delete_cf(self.data(rocksdb), key);
+        batch.delete_cf(self.access_times(rocksdb), key);
+        if let Some(record) = record {
+            for tag in &record.tags {
+                batch.delete_cf(self.tags(rocksdb), index_key(tag, key));
+            }
+        }
+    }
+
+    /// Adds to `batch` the deletion of the index entries of the tags of the `current` record
+    /// of a key which its new `tags` no longer have.
+    fn unindex_dropped_tags(&self, rocksdb: &DB, batch: &mut WriteBatch, key: &[u8], current: &Record, tags: &[Vec<u8>]) {
+        for tag in current.tags.iter().filter(|tag| !tags.contains(tag)) {
+            batch.delete_cf(self.tags(rocksdb), index_key(tag, key));
+        }
+    }
+
+    fn invalidate(&self, key: &[u8]) {
+        if let Some(hot_cache) = &self.hot_cache {
+            hot_cache.remove(key);
+        }
+    }
+
+    /// Compresses then encrypts the value of a record about to be written.
+    fn seal(&self, key: &[u8], record: &Record) -> Result<Record, String> {
+        let mut sealed = record.clone();
+        if let Some((value, dictionary_id)) = self.shared.compressor.compress(&record.value) {
+            Stats::add(&self.stats.compressed_bytes_in, record.value.len() as u64);
+            Stats::add(&self.stats.compressed_bytes_out, value.len() as u64);
+            sealed.compressed = true;
+            sealed.dictionary_id = dictionary_id;
+            sealed.value = value;
+        }
+        if let Some(encryptor) = &self.shared.encryptor {
+            let (value, key_id) = encryptor.encrypt(key, &sealed.value)?;
+            sealed.encryption_key_id = Some(key_id);
+            sealed.value = value;
+        }
+        Ok(sealed)
+    }
+
+    /// Reverts `seal`, the records handed out and cached always hold the plain value.
+    fn unseal(&self, key: &[u8], mut record: Record) -> Option<Record> {
+        match self.unseal_value(key, &record) {
+            Ok(value) => {
+                record.value = value;
+                record.compressed = false;
+                record.dictionary_id = None;
+                record.encryption_key_id = None;
+                Some(record)
+            }
+            Err(e) => {
+                warn!("Can not read key {:?} {}", String::from_utf8_lossy(key), e);
+                None
+            }
+        }
+    }
+
+    fn unseal_value(&self, key: &[u8], record: &Record) -> Result<Vec<u8>, String> {
+        let decrypted = self.decrypt_value(key, record)?;
+        match record.compressed {
+            true => self.shared.compressor.decompress(&decrypted, record.dictionary_id),
+            false => Ok(decrypted)
+        }
+    }
+
+    fn decrypt_value(&self, key: &[u8], record: &Record) -> Result<Vec<u8>, String> {
+        match (record.encryption_key_id, &self.shared.encryptor) {
+            (Some(key_id), Some(encryptor)) => encryptor.decrypt(key, &record.value, key_id),
+            (Some(key_id), None) => Err(format!("Encrypted with key {} while encryption is disabled", key_id)),
+            (None, _) => Ok(record.value.clone())
+        }
+    }
+
+    /// Size of the plain value of a record, a compressed one not being decompressed.
+    fn value_size(&self, key: &[u8], record: &Record) -> Result<usize, String> {
+        if !record.compressed && record.encryption_key_id.is_none() {
+            return Ok(record.value.len());
+        }
+        let decrypted = self.decrypt_value(key, record)?;
+        match record.compressed {
+            true => ValueCompressor::decompressed_length(&decrypted),
+            false => Ok(decrypted.len())
+        }
+    }
+
+    /// Rewrites the records which are not encrypted with the current key, so that retired
+    /// keys can eventually be removed from the key file. Values are decrypted and encrypted
+    /// again as is, without being decompressed. Blocks on RocksDB, so it must not run on a
+    /// worker of the runtime. Paused while the instance is read only, as on replicas.
+    pub fn reencrypt(&self) -> u32 {
+        let encryptor = match &self.shared.encryptor {
+            Some(encryptor) if !self.is_read_only() => encryptor,
+            _ => return 0
+        };
+        let current_key_id = Some(encryptor.current_key_id());
+        let mut reencryption = self.reencryption.lock().unwrap();
+        if reencryption.done {
+            return 0;
+        }
+        let rocksdb = self.mutex.lock().unwrap().rocksdb.clone();
+        let mut keys = vec![];
+        let mut next_key = None;
+        let iterator = rocksdb.iterator_cf(self.data(&rocksdb), IteratorMode::From(&reencryption.next_key, Direction::Forward));
+        for (scanned, (key, value)) in iterator.enumerate() {
+            if scanned == MAX_REENCRYPTION_SCANS_PER_RUN || keys.len() == MAX_REENCRYPTIONS_PER_RUN {
+                next_key = Some(key.to_vec());
+                break;
+            }
+            if let Ok(record) = Record::decode(&value) {
+                if record.encryption_key_id != current_key_id {
+                    keys.push(key);
+                }
+            }
+        }
+        match next_key {
+            Some(key) => reencryption.next_key = key,
+            None => reencryption.done = true
+        }
+        let mut reencrypted = 0;
+        for key in keys {
+            // Read again under the lock so that a concurrent write is not overwritten
+            let dh = self.mutex.lock().unwrap();
+            let data = self.data(&dh.rocksdb);
+            let mut record = match read_record(&dh.rocksdb, data, &key) {
+                Some(record) if record.encryption_key_id != current_key_id => record,
+                _ => continue
+            };
+            let decrypted = match record.encryption_key_id {
+                Some(key_id) => encryptor.decrypt(&key, &record.value, key_id),
+                None => Ok(record.value.clone())
+            };
+            let result = decrypted.and_then(|value| encryptor.encrypt(&key, &value)).and_then(|(value, key_id)| {
+                record.value = value;
+                record.encryption_key_id = Some(key_id);
+                dh.rocksdb.put_cf(data, &key, record.encode()).map_err(|e| e.to_string())
+            });
+            match result {
+                Ok(()) => reencrypted += 1,
+                Err(e) => warn!("Can not re-encrypt key {:?} {}", String::from_utf8_lossy(&key), e)
+            }
+        }
+        Stats::add(&self.stats.reencrypted, reencrypted as u64);
+        reencrypted
+    }
+
+    /// Snapshots the whole data directory, every namespace included, into `path` which must
+    /// not exist yet. SST files are hard linked when on the same file system, so a checkpoint
+    /// of a running instance is cheap and can be opened as a data directory as is.
+    pub fn checkpoint(&self, path: &str) -> Result<(), Error> {
+        let rocksdb = self.mutex.lock().unwrap().rocksdb.clone();
+        Checkpoint::new(&rocksdb)?.create_checkpoint(path)
+    }
+
+    /// Creates a checkpoint named `name` in the checkpoint directory, for the `checkpoint`
+    /// command whose clients must not write anywhere else. Returns false when no checkpoint
+    /// directory is configured.
+    pub fn checkpoint_in_dir(&self, name: &str) -> Result<bool, Error> {
+        let checkpoint_dir = match &self.shared.checkpoint_dir {
+            Some(checkpoint_dir) => checkpoint_dir,
+            None => return Ok(false)
+        };
+        self.checkpoint(&Path::new(checkpoint_dir).join(name).to_string_lossy())?;
+        Ok(true)
+    }
+
+    /// Takes an incremental backup of the whole data directory, then purges the backups
+    /// above `backups_to_keep`. Returns false when no backup directory is configured.
+    pub fn backup(&self) -> Result<bool, Error> {
+        let backup_engine = match &self.shared.backup_engine {
+            Some(backup_engine) => backup_engine,
+            None => return Ok(false)
+        };
+        let rocksdb = self.mutex.lock().unwrap().rocksdb.clone();
+        let mut backup_engine = backup_engine.lock().unwrap();
+        backup_engine.create_new_backup(&rocksdb)?;
+        backup_engine.purge_old_backups(self.shared.backups_to_keep.max(1))?;
+        Ok(true)
+    }
+
+    /// Ingests the SST files written by `SstBuilder` into the namespace, the ingested entries
+    /// overwriting the existing keys. The paths are relative to the staging directory, which
+    /// the files are moved out of, and a directory stands for the SST files it contains.
+    /// Returns the number of files ingested.
+    /// Each column family ingests its files in a single call, the data files last so that the
+    /// new records become visible all at once, after their access times and tag index entries.
+    /// The ingestion is not atomic across column families: a failure may leave access times
+    /// and index entries without their records, which the eviction and `invalidate_tag` skip.
+    /// The files hold plain values, so they are refused when encryption is enabled.
+    pub fn ingest(&self, paths: &[&str]) -> Result<usize, String> {
+        if self.shared.encryptor.is_some() {
+            return Err(String::from("SST files hold plain values while encryption is enabled"));
+        }
+        let ingest_dir = match &self.shared.ingest_dir {
+            Some(ingest_dir) => ingest_dir,
+            None => return Err(String::from("No ingest directory configured"))
+        };
+        let paths = staged_paths(ingest_dir, paths).map_err(|e| format!("Invalid SST file path {}", e))?;
+        let mut data_files = vec![];
+        let mut access_times_files = vec![];
+        let mut tags_files = vec![];
+        for path in sst_files(&paths).map_err(|e| format!("Can not list SST files {}", e))? {
+            match sst_kind(&path) {
+                Some(SstKind::Data) => data_files.push(path),
+                Some(SstKind::AccessTimes) => access_times_files.push(path),
+                Some(SstKind::Tags) => tags_files.push(path),
+                None => return Err(format!("Unknown SST file {}", path.display()))
+            }
+        }
+        let ingested = data_files.len() + access_times_files.len() + tags_files.len();
+        let mut ingest_options = IngestExternalFileOptions::default();
+        ingest_options.set_move_files(true);
+        let dh = self.mutex.lock().unwrap();
+        let rocksdb = &dh.rocksdb;
+        let column_families = vec![
+            (self.access_times(rocksdb), access_times_files),
+            (self.tags(rocksdb), tags_files),
+            (self.data(rocksdb), data_files),
+        ];
+        for (cf, files) in column_families {
+            if !files.is_empty() {
+                rocksdb.ingest_external_file_cf_opts(cf, &ingest_options, files).map_err(|e| e.to_string())?;
+            }
+        }
+        if let Some(hot_cache) = &self.hot_cache {
+            hot_cache.remove_range(b"", None);
+        }
+        Ok(ingested)
+    }
+
+    /// Directory of the data, shared by every namespace.
+    pub fn path(&self) -> PathBuf {
+        self.mutex.lock().unwrap().rocksdb.path().to_path_buf()
+    }
+
+    /// Sequence number of the last write of the data directory.
+    pub fn latest_sequence_number(&self) -> u64 {
+        self.mutex.lock().unwrap().rocksdb.latest_sequence_number()
+    }
+
+    /// Reads the write batches of the data directory from sequence number `next` on, at most
+    /// `max` of them, returning them with the sequence number following the last one.
+    /// None when the write-ahead log does not go back to `next` anymore, in which case the
+    /// replica needs a full sync.
+    pub fn updates_since(&self, next: u64, max: usize) -> Result<Option<(Vec<Update>, u64)>, Error> {
+        let rocksdb = self.mutex.lock().unwrap().rocksdb.clone();
+        let latest = rocksdb.latest_sequence_number();
+        if next == 0 || next > latest + 1 {
+            // Unknown position, or one of another history such as a restored primary
+            return Ok(None);
+        }
+        let mut updates = vec![];
+        let mut following = next;
+        if next == latest + 1 {
+            return Ok(Some((updates, following)));
+        }
+        let wal = match rocksdb.get_updates_since(next) {
+            Ok(wal) => wal,
+            Err(e) => {
+                info!("Can not read the write-ahead log from sequence {} {}", next, e);
+                return Ok(None);
+            }
+        };
+        for (sequence, batch) in wal {
+            if sequence > following {
+                // The write batches in between have been purged
+                return Ok(None);
+            }
+            let end = sequence + batch.len() as u64;
+            if end <= following {
+                continue;
+            }
+            updates.push((sequence, batch.data().to_vec()));
+            following = end;
+            if updates.len() == max {
+                break;
+            }
+        }
+        Ok(Some((updates, following)))
+    }
+
+    /// Sequence number of the primary from which a replica resumes, 0 when unknown.
+    pub fn replication_sequence(&self) -> Result<u64, Error> {
         let dh = self.mutex.lock().unwrap();
         let rocksdb = &dh.rocksdb;
-        rocksdb.get(key)
+        Ok(match rocksdb.get_cf(rocksdb.cf_handle(META_CF).unwrap(), REPLICATION_SEQUENCE_KEY)? {
+            Some(bytes) if bytes.len() == 8 => BigEndian::read_u64(&bytes),
+            _ => 0
+        })
+    }
+
+    pub fn set_replication_sequence(&self, next: u64) -> Result<(), Error> {
+        let dh = self.mutex.lock().unwrap();
+        let rocksdb = &dh.rocksdb;
+        rocksdb.put_cf(rocksdb.cf_handle(META_CF).unwrap(), REPLICATION_SEQUENCE_KEY, u64::to_be_bytes(next))
+    }
+
+    /// Applies a write batch of the primary starting at `sequence`, along with the sequence
+    /// number to resume from. The column families of a replica are those of its primary, as
+    /// its data directory starts from a checkpoint of the primary.
+    pub fn apply_update(&self, sequence: u64, data: &[u8]) -> Result<(), Error> {
+        let mut batch = WriteBatch::from_data(data);
+        let following = sequence + batch.len() as u64;
+        let dh = self.mutex.lock().unwrap();
+        let rocksdb = &dh.rocksdb;
+        let meta = rocksdb.cf_handle(META_CF).unwrap();
+        batch.put_cf(meta, REPLICATION_SEQUENCE_KEY, u64::to_be_bytes(following));
+        rocksdb.write(batch)?;
+        self.load_dictionaries(rocksdb);
+        Ok(())
+    }
+
+    /// Whether a full sync is replacing the data, reads missing every key meanwhile.
+    pub fn is_syncing(&self) -> bool {
+        self.shared.syncing.load(Ordering::Relaxed)
+    }
+
+    /// Replaces the content of every column family with the one of the checkpoint at `path`,
+    /// then removes the checkpoint. Used by a replica whose primary purged the write-ahead
+    /// log it needed. Reads miss every key until the copy is done, rather than seeing a mix
+    /// of both, and keep missing after a failed copy until a full sync succeeds.
+    pub fn replace_with_checkpoint(&self, path: &str, next: u64) -> Result<(), Error> {
+        self.shared.syncing.store(true, Ordering::Relaxed);
+        let options = rocksdb_options();
+        self.copy_checkpoint(&options, path, next)?;
+        self.shared.syncing.store(false, Ordering::Relaxed);
+        DB::destroy(&options, path)
+    }
+
+    fn copy_checkpoint(&self, options: &Options, path: &str, next: u64) -> Result<(), Error> {
+        let checkpoint_cfs = DB::list_cf(options, path)?;
+        let checkpoint = DB::open_cf(options, path, checkpoint_cfs.iter().filter(|cf| *cf != DEFAULT_NAMESPACE))?;
+        let dh = self.mutex.lock().unwrap();
+        let rocksdb = &dh.rocksdb;
+        for name in &checkpoint_cfs {
+            let (target, source) = match (rocksdb.cf_handle(name), checkpoint.cf_handle(name)) {
+                (Some(target), Some(source)) => (target, source),
+                _ => {
+                    warn!("Column family {} of the primary is not configured", name);
+                    continue;
+                }
+            };
+            let mut batch = WriteBatch::default();
+            delete_to_last(rocksdb, target, b"", &mut batch);
+            for (key, value) in checkpoint.iterator_cf(source, IteratorMode::Start) {
+                batch.put_cf(target, key, value);
+                if batch.len() >= MIGRATION_BATCH_SIZE {
+                    rocksdb.write(batch)?;
+                    batch = WriteBatch::default();
+                }
+            }
+            rocksdb.write(batch)?;
+        }
+        rocksdb.put_cf(rocksdb.cf_handle(META_CF).unwrap(), REPLICATION_SEQUENCE_KEY, u64::to_be_bytes(next))?;
+        self.load_dictionaries(rocksdb);
+        for namespace in std::iter::once(self).chain(self.namespaces.iter().map(|namespace| namespace.as_ref())) {
+            namespace.expired_keys.lock().unwrap().clear();
+            namespace.accessed_keys.lock().unwrap().clear();
+            if let Some(hot_cache) = &namespace.hot_cache {
+                hot_cache.remove_range(b"", None);
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts using the dictionaries stored since the data directory was opened.
+    fn load_dictionaries(&self, rocksdb: &DB) {
+        let mut dictionaries = read_dictionaries(rocksdb);
+        dictionaries.sort_by_key(|(id, _)| *id);
+        for (id, bytes) in dictionaries {
+            if !self.shared.compressor.has_dictionary(id) {
+                self.shared.compressor.use_dictionary(id, bytes);
+            }
+        }
+    }
+
+    /// Writes the live records of the namespace to `writer` in the portable dump format,
+    /// calling `progress` with the number of records written so far every now and then.
+    pub fn dump<W: Write, P: FnMut(u64)>(&self, writer: &mut W, mut progress: P) -> io::Result<u64> {
+        let rocksdb = self.mutex.lock().unwrap().rocksdb.clone();
+        let now = current_second();
+        write_header(writer)?;
+        let mut dumped = 0;
+        for (key, value) in rocksdb.iterator_cf(self.data(&rocksdb), IteratorMode::Start) {
+            let record = match decode_record(&key, &value).and_then(|record| self.unseal(&key, record)) {
+                Some(record) if !record.is_expired(now) => record,
+                _ => continue
+            };
+            let entry = DumpEntry {
+                key: key.to_vec(),
+                flags: record.flags,
+                ttl: record.deadline - now,
+                cas: record.cas,
+                tags: record.tags,
+                value: record.value,
+            };
+            write_entry(writer, &entry)?;
+            dumped += 1;
+            if dumped % PROGRESS_INTERVAL == 0 {
+                progress(dumped);
+            }
+        }
+        writer.flush()?;
+        progress(dumped);
+        Ok(dumped)
+    }
+
+    /// Loads a dump written by `dump` into the namespace, overwriting the existing keys.
+    pub fn load<R: Read, P: FnMut(u64)>(&self, reader: &mut R, mut progress: P) -> io::Result<u64> {
+        read_header(reader)?;
+        let mut loaded = 0;
+        let mut next_progress = PROGRESS_INTERVAL;
+        let mut entries = Vec::with_capacity(LOAD_BATCH_SIZE);
+        loop {
+            let entry = read_entry(reader)?;
+            let end = entry.is_none();
+            entries.extend(entry);
+            if entries.len() == LOAD_BATCH_SIZE || end {
+                loaded += self.load_batch(std::mem::replace(&mut entries, Vec::with_capacity(LOAD_BATCH_SIZE)))?;
+                if loaded >= next_progress {
+                    progress(loaded);
+                    next_progress += PROGRESS_INTERVAL;
+                }
+            }
+            if end {
+                break;
+            }
+        }
+        progress(loaded);
+        Ok(loaded)
+    }
+
+    fn load_batch(&self, entries: Vec<DumpEntry>) -> io::Result<u64> {
+        let now = current_second();
+        let mut records = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let mut record = Record::new(now + entry.ttl, 0, entry.flags, entry.value);
+            record.created_at = Some(now);
+            record.last_access = Some(now);
+            if let Err(e) = tags::validate(&entry.tags) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Key {:?} {}", String::from_utf8_lossy(&entry.key), e)));
+            }
+            record.tags = entry.tags;
+            let sealed = self.seal(&entry.key, &record).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            records.push((entry.key, sealed));
+        }
+        let loaded = records.len() as u64;
+        let mut dh = self.mutex.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        for (key, mut sealed) in records {
+            sealed.cas = dh.increment_cas();
+            let rocksdb = &dh.rocksdb;
+            if let Some(current) = read_record(rocksdb, self.data(rocksdb), &key) {
+                self.unindex_dropped_tags(rocksdb, &mut batch, &key, &current, &sealed.tags);
+            }
+            batch.put_cf(self.data(rocksdb), &key, sealed.encode());
+            batch.put_cf(self.access_times(rocksdb), &key, u64::to_be_bytes(now));
+            for tag in &sealed.tags {
+                batch.put_cf(self.tags(rocksdb), index_key(tag, &key), b"");
+            }
+            self.invalidate(&key);
+        }
+        dh.rocksdb.write(batch).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        Ok(loaded)
+    }
+
+    /// Trains a compression dictionary from the values compressed so far and starts using it
+    /// once persisted. Returns the id of the new dictionary, if any.
+    /// Dictionaries are made of plain values and stored as is, so none is trained when values
+    /// are encrypted, nor while the instance is read only.
+    pub fn train_compression_dictionary(&self) -> Option<u32> {
+        if self.shared.encryptor.is_some() || self.is_read_only() {
+            return None;
+        }
+        let (id, bytes) = self.shared.compressor.train_dictionary()?;
+        let dh = self.mutex.lock().unwrap();
+        let rocksdb = &dh.rocksdb;
+        if let Err(e) = rocksdb.put_cf(rocksdb.cf_handle(META_CF).unwrap(), dictionary_key(id), &bytes) {
+            warn!("Can not store compression dictionary {} {}", id, e);
+            return None;
+        }
+        self.shared.compressor.use_dictionary(id, bytes);
+        Some(id)
+    }
+
+    /// Lists the live keys starting with `prefix` along with their metadata, at most `limit`
+    /// of them. When more keys match, the listing ends with a `CURSOR` line giving the key
+    /// to pass back to get the next page.
+    /// The lock is only held to clone the RocksDB handle, so a listing does not block writes.
+    pub fn keys(&self, prefix: &[u8], limit: Option<u64>, cursor: Option<&[u8]>) -> Response {
+        if self.is_syncing() {
+            return Response::NotFoundError;
+        }
+        // A limit of 0 would end every page with a cursor to the same key
+        let limit = limit.unwrap_or(DEFAULT_KEYS_LIMIT).max(1).min(MAX_KEYS_LIMIT) as usize;
+        let rocksdb = self.mutex.lock().unwrap().rocksdb.clone();
+        let access_times = self.access_times(&rocksdb);
+        let from = match cursor {
+            Some(cursor) if cursor > prefix => cursor,
+            _ => prefix
+        };
+        let now = current_second();
+        let mut bytes_mut = BytesMut::new();
+        let mut listed = 0;
+        let iterator = rocksdb.iterator_cf(self.data(&rocksdb), IteratorMode::From(from, Direction::Forward))
+            .take_while(|(key, _)| key.starts_with(prefix));
+        for (key, value) in iterator {
+            let record = match decode_record(&key, &value) {
+                Some(record) if !record.is_expired(now) => record,
+                _ => continue
+            };
+            if listed == limit {
+                bytes_mut.put_slice(b"CURSOR ");
+                bytes_mut.put_slice(&key);
+                bytes_mut.put_slice(b"\r\n");
+                break;
+            }
+            let last_access = match rocksdb.get_cf(access_times, &key) {
+                Ok(Some(last_access)) if last_access.len() == 8 => Some(BigEndian::read_u64(&last_access)),
+                _ => record.last_access
+            };
+            let size = match self.value_size(&key, &record) {
+                Ok(size) => size,
+                Err(e) => {
+                    warn!("Can not read key {:?} {}", String::from_utf8_lossy(&key), e);
+                    continue;
+                }
+            };
+            append_key_metadata(&key, &record, last_access, size, &mut bytes_mut);
+            listed += 1;
+        }
+        finish_get_response(&mut bytes_mut)
+    }
+
+    /// Deletes, in a single batch, every key still carrying `tag`, answering their number.
+    /// Index entries left by keys since deleted or set again without the tag are dropped.
+    pub fn invalidate_tag(&self, tag: &[u8]) -> Response {
+        let dh = self.mutex.lock().unwrap();
+        let rocksdb = &dh.rocksdb;
+        let (data, tags) = (self.data(rocksdb), self.tags(rocksdb));
+        let prefix = index_prefix(tag);
+        let mut batch = WriteBatch::default();
+        let mut invalidated = 0;
+        let iterator = rocksdb.iterator_cf(tags, IteratorMode::From(&prefix, Direction::Forward))
+            .take_while(|(index_key, _)| index_key.starts_with(&prefix));
+        for (index_key, _) in iterator {
+            batch.delete_cf(tags, &index_key);
+            let key = &index_key[prefix.len()..];
+            match read_record(rocksdb, data, key) {
+                Some(record) if record.tags.iter().any(|record_tag| record_tag.as_slice() == tag) => {
+                    self.invalidate(key);
+                    // Also drops the entries of its other tags
+                    self.delete_in_batch(rocksdb, &mut batch, key, Some(&record));
+                    invalidated += 1;
+                }
+                _ => ()
+            }
+        }
+        match rocksdb.write(batch) {
+            Ok(()) => number_response(invalidated),
+            Err(e) => {
+                warn!("Can not invalidate tag {:?} {}", String::from_utf8_lossy(tag), e);
+                Response::ServerError
+            }
+        }
+    }
+
+    /// Removes every key of the namespace, leaving the other namespaces untouched.
+    pub fn flush_all(&self) -> Response {
+        match self.delete_key_range(b"", None) {
+            Ok(()) => Response::Ok,
+            Err(e) => {
+                warn!("Can not flush namespace {} {}", self.name, e);
+                Response::ServerError
+            }
+        }
     }
 
+    /// Deletes the keys starting with `prefix`, answering the number of deleted ranges.
+    pub fn delete_prefix(&self, prefix: &[u8]) -> Response {
+        self.delete_range_response(prefix, prefix_successor(prefix).as_deref())
+    }
+
+    /// Deletes the keys from `start` included to `end` excluded, answering the number of
+    /// deleted ranges.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Response {
+        if start >= end {
+            return number_response(0);
+        }
+        self.delete_range_response(start, Some(end))
+    }
+
+    fn delete_range_response(&self, start: &[u8], end: Option<&[u8]>) -> Response {
+        match self.delete_key_range(start, end) {
+            Ok(()) => number_response(1),
+            Err(e) => {
+                warn!("Can not delete the keys from {:?} {}", String::from_utf8_lossy(start), e);
+                Response::ServerError
+            }
+        }
+    }
+
+    /// Deletes the keys from `start` included to `end` excluded, or to the last key, with a
+    /// single range tombstone per column family instead of a delete per key.
+    fn delete_key_range(&self, start: &[u8], end: Option<&[u8]>) -> Result<(), Error> {
+        let dh = self.mutex.lock().unwrap();
+        let rocksdb = &dh.rocksdb;
+        let mut batch = WriteBatch::default();
+        let mut column_families = vec![self.data(rocksdb), self.access_times(rocksdb)];
+        if start.is_empty() && end.is_none() {
+            // Flushing the whole namespace also drops its tag index
+            column_families.push(self.tags(rocksdb));
+        }
+        // A partial range leaves its tag entries behind: the index is sorted by tag, and
+        // invalidate_tag already skips the entries whose record no longer carries the tag
+        for cf in column_families.iter() {
+            match end {
+                Some(end) => batch.delete_range_cf(cf, start, end),
+                None => delete_to_last(rocksdb, cf, start, &mut batch)
+            }
+        }
+        let result = rocksdb.write(batch);
+        self.expired_keys.lock().unwrap().retain(|key| !in_range(key, start, end));
+        self.accessed_keys.lock().unwrap().retain(|key, _| !in_range(key, start, end));
+        if let Some(hot_cache) = &self.hot_cache {
+            hot_cache.remove_range(start, end);
+        }
+        result
+    }
 
     pub fn delete_expired(&self) -> u32 {
         let mut dh = self.mutex.lock().unwrap();
         let rocksdb = &dh.rocksdb;
-        let iterator = rocksdb.full_iterator(IteratorMode::Start);
+        let data = self.data(rocksdb);
+        let iterator = rocksdb.iterator_cf(data, IteratorMode::Start);
         let mut deleted: u32 = 0;
-        for item in iterator {
-            let expiration = BigEndian::read_u64(&item.1[0..8]);
-            if expiration < current_second() {
-                let key = item.0;
-                match rocksdb.delete(key.clone()){
+        for (key, value) in iterator {
+            let record = decode_record(&key, &value);
+            if record.as_ref().map_or(false, |record| record.is_expired(current_second())) {
+                self.invalidate(&key);
+                let mut batch = WriteBatch::default();
+                self.delete_in_batch(rocksdb, &mut batch, &key, record.as_ref());
+                match rocksdb.write(batch) {
                     Ok(()) => deleted +=1 ,
                     _ => warn!("Can not delete key {:?}", key)
                 };
@@ -220,19 +1522,15 @@ fn finish_get_response(bytes_mut: &mut BytesMut) -> Response {
     }
 }
 
-fn process_get_request(key: &[u8], value: &[u8], bytes_mut: &mut BytesMut, include_cas: bool) {
-    let expiration = BigEndian::read_u64(&value[0..8]);
-    if expiration > current_second() {
-        let cas = match include_cas {
-            true => Some(BigEndian::read_u64(&value[8..16])),
-            _ => None
-        };
-        append_get_response(key, cas, &value[16..20], &value[20..], bytes_mut);
-    }
+fn process_get_request(key: &[u8], record: &Record, bytes_mut: &mut BytesMut, include_cas: bool) {
+    let cas = match include_cas {
+        true => Some(record.cas),
+        _ => None
+    };
+    append_get_response(key, cas, record.flags, &record.value, bytes_mut);
 }
 
-fn append_get_response(key: &[u8], cas: Option<u64>, flag_bytes: &[u8], data_bytes: &[u8], bytes_mut: &mut BytesMut) {
-    let flag = BigEndian::read_u32(flag_bytes);
+fn append_get_response(key: &[u8], cas: Option<u64>, flag: u32, data_bytes: &[u8], bytes_mut: &mut BytesMut) {
     let length = data_bytes.len() as u32;
     bytes_mut.put_slice(b"VALUE ");
     bytes_mut.put_slice(key);
@@ -252,6 +1550,149 @@ fn append_get_response(key: &[u8], cas: Option<u64>, flag_bytes: &[u8], data_byt
     bytes_mut.put_slice(b"\r\n");
 }
 
+fn number_response(n: u64) -> Response {
+    let mut bytes_mut = BytesMut::new();
+    bytes_mut.put_slice(&u64_to_bytes(n));
+    bytes_mut.put_slice(b"\r\n");
+    Response::Value { value: bytes_mut.to_vec() }
+}
+
+/// Describes a key the way memcached's `lru_crawler metadump` does, `fetch` telling whether
+/// the key was read since it was written, and `size` being the size of its plain value.
+fn append_key_metadata(key: &[u8], record: &Record, last_access: Option<u64>, size: usize, bytes_mut: &mut BytesMut) {
+    let last_access = last_access.unwrap_or(0);
+    let fetched = match record.created_at {
+        Some(created_at) => last_access > created_at,
+        None => false
+    };
+    bytes_mut.put_slice(b"key=");
+    bytes_mut.put_slice(key);
+    let metadata = format!(" exp={} la={} cas={} fetch={} size={}\r\n",
+                           record.deadline, last_access, record.cas, if fetched { "yes" } else { "no" }, size);
+    bytes_mut.put_slice(metadata.as_bytes());
+}
+
+fn read_record(rocksdb: &DB, data: &ColumnFamily, key: &[u8]) -> Option<Record> {
+    match rocksdb.get_cf(data, key) {
+        Ok(Some(bytes)) => decode_record(key, &bytes),
+        _ => None
+    }
+}
+
+fn decode_record(key: &[u8], bytes: &[u8]) -> Option<Record> {
+    match Record::decode(bytes) {
+        Ok(record) => Some(record),
+        Err(e) => {
+            warn!("Can not read key {:?} {}", String::from_utf8_lossy(key), e);
+            None
+        }
+    }
+}
+
+/// Deletes the keys of `cf` from `start` to the last one, the end of a range being excluded
+/// the last key is deleted on its own.
+fn delete_to_last(rocksdb: &DB, cf: &ColumnFamily, start: &[u8], batch: &mut WriteBatch) {
+    if let Some((last, _)) = rocksdb.iterator_cf(cf, IteratorMode::End).next() {
+        if &*last >= start {
+            batch.delete_range_cf(cf, start, &last[..]);
+            batch.delete_cf(cf, &last);
+        }
+    }
+}
+
+/// Options shared by the data directories and the checkpoints of primaries opened by replicas.
+fn rocksdb_options() -> Options {
+    let mut options = Options::default();
+    options.set_compression_type(DBCompressionType::Lz4);
+    options.set_max_write_buffer_number(16);
+    options
+}
+
+/// Resolves paths relative to the staging directory, rejecting those leading out of it.
+fn staged_paths(ingest_dir: &str, paths: &[&str]) -> io::Result<Vec<PathBuf>> {
+    let ingest_dir = fs::canonicalize(ingest_dir)?;
+    paths.iter().map(|path| {
+        let staged = fs::canonicalize(ingest_dir.join(path))?;
+        match staged.starts_with(&ingest_dir) {
+            true => Ok(staged),
+            false => Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is out of {}", path, ingest_dir.display())))
+        }
+    }).collect()
+}
+
+/// Paths of the SST files, those of a directory being listed in name order.
+fn sst_files(paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for path in paths {
+        if path.is_dir() {
+            let mut dir_files = vec![];
+            for entry in fs::read_dir(path)? {
+                let file = entry?.path();
+                if file.is_file() && sst_kind(&file).is_some() {
+                    dir_files.push(file);
+                }
+            }
+            dir_files.sort();
+            files.extend(dir_files);
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+    Ok(files)
+}
+
+fn namespace_cf(name: &str) -> String {
+    format!("{}{}", NAMESPACE_CF_PREFIX, name)
+}
+
+/// Column family of a namespace indexing its keys, such as its access times.
+fn namespace_index_cf(name: &str, index: &str) -> String {
+    format!("{}{}:{}", NAMESPACE_CF_PREFIX, name, index)
+}
+
+fn read_dictionaries(rocksdb: &DB) -> Vec<(u32, Vec<u8>)> {
+    let meta = rocksdb.cf_handle(META_CF).unwrap();
+    rocksdb.iterator_cf(meta, IteratorMode::From(DICTIONARY_PREFIX, Direction::Forward))
+        .take_while(|(key, _)| key.starts_with(DICTIONARY_PREFIX))
+        .filter_map(|(key, bytes)| dictionary_id(&key).map(|id| (id, bytes.to_vec())))
+        .collect()
+}
+
+/// Rewrites the records stored before `FORMAT_VERSION` so that only the current layout
+/// has to be written, then records the version to skip the scan on the next start.
+fn migrate_records(rocksdb: &DB) -> Result<u64, Error> {
+    let meta = rocksdb.cf_handle(META_CF).unwrap();
+    if let Some(version) = rocksdb.get_cf(meta, FORMAT_VERSION_KEY)? {
+        if version.first() == Some(&FORMAT_VERSION) {
+            return Ok(0);
+        }
+    }
+    let access_times = rocksdb.cf_handle(ACCESS_TIMES_CF).unwrap();
+    let now = current_second();
+    let mut migrated = 0;
+    let mut batch = WriteBatch::default();
+    for (key, value) in rocksdb.iterator(IteratorMode::Start) {
+        if !Record::is_legacy(&value) {
+            continue;
+        }
+        match Record::decode(&value) {
+            Ok(record) => {
+                batch.put(&key, record.encode());
+                batch.put_cf(access_times, &key, u64::to_be_bytes(now));
+                migrated += 1;
+            }
+            Err(e) => warn!("Can not migrate key {:?} {}", String::from_utf8_lossy(&key), e)
+        }
+        if batch.len() >= MIGRATION_BATCH_SIZE {
+            rocksdb.write(batch)?;
+            batch = WriteBatch::default();
+        }
+    }
+    batch.put_cf(meta, FORMAT_VERSION_KEY, [FORMAT_VERSION]);
+    rocksdb.write(batch)?;
+    Ok(migrated)
+}
+
 fn current_second() -> u64 {
     SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
 }
@@ -259,4 +1700,248 @@ fn current_second() -> u64 {
 
 unsafe impl Send for Database {}
 
-unsafe impl Sync for Database {}
\ No newline at end of file
+unsafe impl Sync for Database {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use crate::command::Command;
+    use crate::test_utils::{TempDatabase, TempDir};
+
+    /// Opens a database in an empty directory of its own.
+    fn open(name: &str, options: DatabaseOptions) -> TempDatabase {
+        TempDatabase::open_with_options(name, &options)
+    }
+
+    /// Writes a key file holding the keys `01…` and `02…` under ids 1 and 2.
+    fn write_key_file(dir: &TempDir, current: u32) -> String {
+        fs::create_dir_all(dir.path()).unwrap();
+        let key_file = dir.join("keys.yaml");
+        let keys = format!("current: {}\nkeys:\n  1: \"{}\"\n  2: \"{}\"\n", current, "01".repeat(32), "02".repeat(32));
+        fs::write(&key_file, keys).unwrap();
+        key_file
+    }
+
+    #[test]
+    fn expired_keys_read_are_reclaimed() {
+        let db = open("reclaim", DatabaseOptions::default());
+        // A time to live of 0 without default expires right away
+        assert_eq!(db.insert(b"expired", 0, 0, b"a"), Response::Stored);
+        assert_eq!(db.insert(b"live", 0, 100, b"b"), Response::Stored);
+        assert_eq!(db.get(vec![b"expired", b"live"], false), Response::Value { value: b"VALUE live 0 1\r\nb\r\nEND\r\n".to_vec() });
+        assert_eq!(db.stats.expired_unfetched.load(Ordering::Relaxed), 1);
+        assert_eq!(db.reclaim_expired(), 1);
+        assert_eq!(db.stats.reclaimed.load(Ordering::Relaxed), 1);
+        let dh = db.mutex.lock().unwrap();
+        assert!(read_record(&dh.rocksdb, db.data(&dh.rocksdb), b"expired").is_none());
+        assert!(read_record(&dh.rocksdb, db.data(&dh.rocksdb), b"live").is_some());
+        drop(dh);
+        assert_eq!(db.reclaim_expired(), 0);
+    }
+
+    #[test]
+    fn expired_keys_are_not_reclaimed_while_read_only() {
+        let db = open("reclaim_read_only", DatabaseOptions::default());
+        assert_eq!(db.insert(b"expired", 0, 0, b"a"), Response::Stored);
+        db.get(vec![b"expired"], false);
+        db.set_read_only(true).unwrap();
+        assert_eq!(db.reclaim_expired(), 0);
+        assert_eq!(db.evict(), 0);
+        assert_eq!(db.reencrypt(), 0);
+        assert_eq!(db.train_compression_dictionary(), None);
+        db.set_read_only(false).unwrap();
+        assert_eq!(db.reclaim_expired(), 1);
+    }
+
+    #[test]
+    fn client_reads_count_as_hot_key_reads() {
+        let db = open("hot_key_reads", DatabaseOptions { hot_keys_sample_rate: 1, hot_keys_window: 10, ..DatabaseOptions::default() });
+        assert_eq!(db.insert(b"a", 0, 100, b"1"), Response::Stored);
+        db.get(vec![b"a"], false);
+        db.get_live_record(b"a");
+        db.get_live_record_for_update(b"a");
+        let top = db.hot_keys.top(current_second() + 10);
+        assert_eq!(top[0].key, b"a".to_vec());
+        assert_eq!(top[0].reads_per_second, 0.2);
+        assert_eq!(top[0].writes_per_second, 0.1);
+    }
+
+    #[test]
+    fn only_client_reads_count_in_the_hot_cache_stats() {
+        let db = open("hot_cache_stats", DatabaseOptions { hot_cache_size: 1 << 20, ..Default::default() });
+        db.insert(b"n", 0, 100, b"1");
+        db.increment(b"n", 1);
+        db.append(b"n", 0, 100, b"0");
+        db.touch(b"n", 100);
+        assert_eq!(db.stats.hot_cache_hits.load(Ordering::Relaxed), 0);
+        assert_eq!(db.stats.hot_cache_misses.load(Ordering::Relaxed), 0);
+        assert_eq!(db.get_live_record(b"n").unwrap().value, b"20".to_vec());
+        db.get(vec![b"missing"], false);
+        assert_eq!(db.stats.hot_cache_hits.load(Ordering::Relaxed), 1);
+        assert_eq!(db.stats.hot_cache_misses.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn keys_report_the_plain_value_size() {
+        let key_dir = TempDir::new("keys-size");
+        let options = DatabaseOptions {
+            encryption_key_file: Some(write_key_file(&key_dir, 1)),
+            compression_threshold: 16,
+            ..Default::default()
+        };
+        let db = open("keys_size", options);
+        let value = b"a value compressed before being encrypted".repeat(4);
+        assert_eq!(db.insert(b"compressed", 0, 100, &value), Response::Stored);
+        assert_eq!(db.insert(b"small", 0, 100, b"abc"), Response::Stored);
+        let listing = match db.keys(b"", None, None) {
+            Response::Value { value } => String::from_utf8(value).unwrap(),
+            response => panic!("Unexpected response {:?}", response)
+        };
+        assert!(listing.starts_with("key=compressed "));
+        assert!(listing.lines().next().unwrap().ends_with(&format!(" size={}", value.len())));
+        assert!(listing.lines().nth(1).unwrap().ends_with(" size=3"));
+    }
+
+    #[test]
+    fn records_are_reencrypted_once_with_the_current_key() {
+        let key_dir = TempDir::new("keys");
+        let options = DatabaseOptions {
+            encryption_key_file: Some(write_key_file(&key_dir, 1)),
+            compression_threshold: 16,
+            ..Default::default()
+        };
+        let dir = TempDir::new("reencrypt");
+        let db = Database::open_with_options(dir.path(), &options);
+        let value = b"a value compressed before being encrypted".repeat(4);
+        for key in [&b"a"[..], b"b", b"c"].iter() {
+            assert_eq!(db.insert(key, 0, 100, &value), Response::Stored);
+        }
+        assert_eq!(db.reencrypt(), 0);
+        drop(db);
+        write_key_file(&key_dir, 2);
+        let db = Database::open_with_options(dir.path(), &options);
+        assert_eq!(db.train_compression_dictionary(), None);
+        assert_eq!(db.reencrypt(), 3);
+        assert_eq!(db.reencrypt(), 0);
+        assert_eq!(db.stats.reencrypted.load(Ordering::Relaxed), 3);
+        assert_eq!(db.stats.compressed_bytes_in.load(Ordering::Relaxed), 0);
+        let dh = db.mutex.lock().unwrap();
+        let record = read_record(&dh.rocksdb, db.data(&dh.rocksdb), b"b").unwrap();
+        assert_eq!(record.encryption_key_id, Some(2));
+        assert!(record.compressed);
+        drop(dh);
+        assert_eq!(db.get_live_record(b"b").unwrap().value, value);
+    }
+
+    #[test]
+    fn namespaces_are_isolated() {
+        let namespace = NamespaceOptions { name: String::from("sessions"), default_ttl: 100, hot_cache_size: 0, max_disk_bytes: 0 };
+        let mut db = open("namespaces", DatabaseOptions { namespaces: vec![namespace], ..Default::default() });
+        let sessions = db.namespace("sessions").unwrap();
+        assert_eq!(db.insert(b"a", 0, 100, b"default"), Response::Stored);
+        assert_eq!(sessions.insert(b"a", 0, 0, b"sessions"), Response::Stored);
+        assert_eq!(sessions.insert(b"b", 0, 0, b"sessions"), Response::Stored);
+        assert_eq!(db.get_live_record(b"a").unwrap().value, b"default".to_vec());
+        assert_eq!(sessions.get_live_record(b"a").unwrap().value, b"sessions".to_vec());
+        assert!(db.get_live_record(b"b").is_none());
+        assert_eq!(sessions.delete(b"a"), Response::Stored);
+        assert!(sessions.get_live_record(b"a").is_none());
+        assert!(db.get_live_record(b"a").is_some());
+        assert_eq!(Command::handle(b"use unknown\r\n", &mut db, "test").serialize(), Bytes::from("CLIENT_ERROR unknown namespace\r\n"));
+        assert_eq!(db.name(), DEFAULT_NAMESPACE);
+        assert_eq!(Command::handle(b"use sessions\r\n", &mut db, "test"), Response::Ok);
+        assert_eq!(db.name(), "sessions");
+        assert_eq!(db.get_live_record(b"b").unwrap().value, b"sessions".to_vec());
+    }
+
+    #[test]
+    fn checkpoints_are_created_in_the_checkpoint_directory() {
+        let db = open("no_checkpoint_dir", DatabaseOptions::default());
+        assert!(!db.checkpoint_in_dir("snapshot").unwrap());
+        let checkpoint_dir = TempDir::new("checkpoints");
+        fs::create_dir_all(checkpoint_dir.path()).unwrap();
+        let options = DatabaseOptions { checkpoint_dir: Some(checkpoint_dir.path().to_string()), ..Default::default() };
+        let db = open("checkpoint_dir", options);
+        assert_eq!(db.insert(b"a", 0, 100, b"v"), Response::Stored);
+        assert!(db.checkpoint_in_dir("snapshot").unwrap());
+        assert!(Path::new(&checkpoint_dir.join("snapshot")).is_dir());
+    }
+
+    #[test]
+    fn only_staged_sst_files_are_ingested() {
+        let ingest_dir = TempDir::new("staging");
+        let mut builder = crate::sst::SstBuilder::new(&ingest_dir.join("batch"), current_second()).unwrap();
+        builder.add(DumpEntry { key: b"a".to_vec(), flags: 0, ttl: 100, cas: 0, tags: vec![], value: b"v".to_vec() }).unwrap();
+        builder.finish().unwrap();
+        assert!(open("ingest_disabled", DatabaseOptions::default()).ingest(&["batch"]).is_err());
+        let encrypted = DatabaseOptions {
+            ingest_dir: Some(ingest_dir.path().to_string()),
+            encryption_key_file: Some(write_key_file(&ingest_dir, 1)),
+            ..Default::default()
+        };
+        assert!(open("ingest_encrypted", encrypted).ingest(&["batch"]).is_err());
+        let db = open("ingest", DatabaseOptions { ingest_dir: Some(ingest_dir.path().to_string()), ..Default::default() });
+        assert!(db.ingest(&[".."]).is_err());
+        assert!(db.ingest(&["/tmp"]).is_err());
+        assert_eq!(db.ingest(&["batch"]), Ok(2));
+        assert_eq!(db.get_live_record(b"a").unwrap().value, b"v".to_vec());
+    }
+
+    fn tag_index(db: &Database) -> Vec<Vec<u8>> {
+        let dh = db.mutex.lock().unwrap();
+        let iterator = dh.rocksdb.iterator_cf(db.tags(&dh.rocksdb), IteratorMode::Start);
+        iterator.map(|(index_key, _)| index_key.to_vec()).collect()
+    }
+
+    #[test]
+    fn removed_keys_leave_the_tag_index() {
+        let db = open("tags", DatabaseOptions::default());
+        for key in [&b"a"[..], b"b", b"c", b"d", b"e"].iter() {
+            assert_eq!(db.insert_with_tags(key, 0, 100, b"v", vec![b"t1", b"t2"]), Response::Stored);
+        }
+        assert_eq!(db.insert_with_tags(b"f", 0, 0, b"v", vec![b"t1"]), Response::Stored);
+        assert_eq!(db.delete(b"a"), Response::Stored);
+        assert_eq!(db.insert_with_tags(b"b", 0, 100, b"v", vec![b"t2"]), Response::Stored);
+        assert_eq!(db.append(b"b", 0, 100, b"w"), Response::Stored);
+        assert_eq!(db.delete_range(b"c", b"d"), number_response(1));
+        db.get(vec![b"f"], false);
+        assert_eq!(db.reclaim_expired(), 1);
+        // The range delete leaves the entries of "c" until its tags are invalidated
+        assert_eq!(tag_index(&db), vec![index_key(b"t1", b"c"), index_key(b"t1", b"d"), index_key(b"t1", b"e"), index_key(b"t2", b"b"), index_key(b"t2", b"c"), index_key(b"t2", b"d"), index_key(b"t2", b"e")]);
+        assert_eq!(db.invalidate_tag(b"t1"), number_response(2));
+        assert_eq!(tag_index(&db), vec![index_key(b"t2", b"b"), index_key(b"t2", b"c")]);
+        assert_eq!(db.invalidate_tag(b"t2"), number_response(1));
+        assert_eq!(tag_index(&db), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn tags_are_bounded() {
+        let db = open("tags_bounds", DatabaseOptions::default());
+        let tags = vec![&b"t"[..]; tags::MAX_TAGS + 1];
+        assert_eq!(db.insert_with_tags(b"a", 0, 100, b"v", tags).serialize(), Bytes::from("CLIENT_ERROR too many tags\r\n"));
+        let long_tag = vec![b't'; tags::MAX_TAG_LENGTH + 1];
+        assert_eq!(db.insert_with_tags(b"a", 0, 100, b"v", vec![&long_tag]), Response::ClientError { msg: "tag too long" });
+        assert!(db.get_live_record(b"a").is_none());
+    }
+
+    #[test]
+    fn least_recently_used_keys_are_evicted_above_the_disk_quota() {
+        let db = open("evict", DatabaseOptions { max_disk_bytes: 16 * 1024, ..Default::default() });
+        let mut seed = 1u64;
+        for i in 0..64 {
+            // Not compressible, so that the quota is exceeded once flushed
+            let value: Vec<u8> = (0..512).map(|_| { seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1); (seed >> 56) as u8 }).collect();
+            assert_eq!(db.insert(format!("key{}", i).as_bytes(), 0, 100, &value), Response::Stored);
+        }
+        let dh = db.mutex.lock().unwrap();
+        dh.rocksdb.flush_cf(db.data(&dh.rocksdb)).unwrap();
+        drop(dh);
+        let evicted = db.evict();
+        assert!(evicted > 0 && evicted < 64, "evicted {} keys", evicted);
+        assert_eq!(db.stats.evictions.load(Ordering::Relaxed), evicted as u64);
+        let dh = db.mutex.lock().unwrap();
+        let remaining = (0..64).filter(|i| read_record(&dh.rocksdb, db.data(&dh.rocksdb), format!("key{}", i).as_bytes()).is_some()).count();
+        assert_eq!(remaining, 64 - evicted as usize);
+    }
+}
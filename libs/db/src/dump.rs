@@ -0,0 +1,121 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// Portable dump of the live records, independent from the RocksDB and record formats.
+///
+/// Layout of version 1: the `MAGIC` bytes and the version, then for every record
+/// `[key length u32][key][flags u32][remaining ttl u64][cas u64][tag count u16]`
+/// `[tag length u16][tag]...[value length u32][value]`.
+/// The CAS is informative only, loading assigns new ones.
+const MAGIC: &[u8] = b"RCDUMP";
+pub const DUMP_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DumpEntry {
+    pub key: Vec<u8>,
+    pub flags: u32,
+    pub ttl: u64,
+    pub cas: u64,
+    pub tags: Vec<Vec<u8>>,
+    pub value: Vec<u8>,
+}
+
+pub fn write_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_u8(DUMP_VERSION)
+}
+
+pub fn read_header<R: Read>(reader: &mut R) -> Result<()> {
+    let mut magic = [0u8; 6];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a rockscached dump"));
+    }
+    match reader.read_u8()? {
+        DUMP_VERSION => Ok(()),
+        version => Err(Error::new(ErrorKind::InvalidData, format!("Unsupported dump version {}", version)))
+    }
+}
+
+pub fn write_entry<W: Write>(writer: &mut W, entry: &DumpEntry) -> Result<()> {
+    write_bytes(writer, &entry.key)?;
+    writer.write_u32::<BigEndian>(entry.flags)?;
+    writer.write_u64::<BigEndian>(entry.ttl)?;
+    writer.write_u64::<BigEndian>(entry.cas)?;
+    writer.write_u16::<BigEndian>(entry.tags.len() as u16)?;
+    for tag in &entry.tags {
+        writer.write_u16::<BigEndian>(tag.len() as u16)?;
+        writer.write_all(tag)?;
+    }
+    write_bytes(writer, &entry.value)
+}
+
+/// Reads the next entry, none once the end of the dump is reached.
+pub fn read_entry<R: Read>(reader: &mut R) -> Result<Option<DumpEntry>> {
+    let key_length = match reader.read_u32::<BigEndian>() {
+        Ok(length) => length,
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    let key = read_bytes(reader, key_length as usize)?;
+    let flags = reader.read_u32::<BigEndian>()?;
+    let ttl = reader.read_u64::<BigEndian>()?;
+    let cas = reader.read_u64::<BigEndian>()?;
+    let mut tags = vec![];
+    for _ in 0..reader.read_u16::<BigEndian>()? {
+        let tag_length = reader.read_u16::<BigEndian>()?;
+        tags.push(read_bytes(reader, tag_length as usize)?);
+    }
+    let value_length = reader.read_u32::<BigEndian>()?;
+    let value = read_bytes(reader, value_length as usize)?;
+    Ok(Some(DumpEntry { key, flags, ttl, cas, tags, value }))
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    writer.write_u32::<BigEndian>(bytes.len() as u32)?;
+    writer.write_all(bytes)
+}
+
+fn read_bytes<R: Read>(reader: &mut R, length: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0u8; length];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn entry(key: &[u8]) -> DumpEntry {
+        DumpEntry { key: key.to_vec(), flags: 3, ttl: 60, cas: 12, tags: vec![b"product:42".to_vec()], value: b"the value".to_vec() }
+    }
+
+    #[test]
+    fn write_read_nominal() {
+        let mut bytes = vec![];
+        write_header(&mut bytes).unwrap();
+        write_entry(&mut bytes, &entry(b"k1")).unwrap();
+        write_entry(&mut bytes, &entry(b"k2")).unwrap();
+        let mut reader = Cursor::new(bytes);
+        read_header(&mut reader).unwrap();
+        assert_eq!(read_entry(&mut reader).unwrap(), Some(entry(b"k1")));
+        assert_eq!(read_entry(&mut reader).unwrap(), Some(entry(b"k2")));
+        assert_eq!(read_entry(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn read_invalid_header() {
+        assert!(read_header(&mut Cursor::new(b"RCDUMP\x09".to_vec())).is_err());
+        assert!(read_header(&mut Cursor::new(b"NOTDUMP".to_vec())).is_err());
+    }
+
+    #[test]
+    fn read_truncated_entry() {
+        let mut bytes = vec![];
+        write_entry(&mut bytes, &entry(b"k1")).unwrap();
+        bytes.truncate(bytes.len() - 2);
+        assert!(read_entry(&mut Cursor::new(bytes)).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::fs;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use serde::Deserialize;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+/// Content of the key file, e.g.
+/// ```yaml
+/// current: 2
+/// keys:
+///   1: "<64 hexadecimal characters>"
+///   2: "<64 hexadecimal characters>"
+/// ```
+/// Retired keys must stay in the file until the re-encryption has moved every value to the
+/// current one.
+#[derive(Debug, Deserialize)]
+struct KeyFile {
+    current: u32,
+    keys: HashMap<u32, String>,
+}
+
+/// Encrypts values with ChaCha20-Poly1305. The stored key authenticates the value, so that
+/// an encrypted value can not be moved under another key.
+/// An encrypted value is prefixed with its random nonce.
+pub struct ValueEncryptor {
+    current: u32,
+    ciphers: HashMap<u32, ChaCha20Poly1305>,
+}
+
+impl ValueEncryptor {
+    pub fn from_file(path: &str) -> Result<ValueEncryptor, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Can not read key file {} {}", path, e))?;
+        let key_file: KeyFile = serde_yaml::from_str(&content).map_err(|e| format!("Invalid key file {} {}", path, e))?;
+        let mut keys = HashMap::new();
+        for (id, key) in key_file.keys {
+            let key = hex::decode(key.trim()).map_err(|e| format!("Invalid key {} {}", id, e))?;
+            keys.insert(id, key);
+        }
+        ValueEncryptor::new(key_file.current, keys)
+    }
+
+    pub fn new(current: u32, keys: HashMap<u32, Vec<u8>>) -> Result<ValueEncryptor, String> {
+        let mut ciphers = HashMap::new();
+        for (id, key) in keys {
+            if key.len() != KEY_SIZE {
+                return Err(format!("Key {} must be {} bytes long, not {}", id, KEY_SIZE, key.len()));
+            }
+            ciphers.insert(id, ChaCha20Poly1305::new(Key::from_slice(&key)));
+        }
+        if !ciphers.contains_key(&current) {
+            return Err(format!("Unknown current key {}", current));
+        }
+        Ok(ValueEncryptor { current, ciphers })
+    }
+
+    pub fn current_key_id(&self) -> u32 {
+        self.current
+    }
+
+    /// Returns the encrypted value and the id of the key used.
+    pub fn encrypt(&self, key: &[u8], value: &[u8]) -> Result<(Vec<u8>, u32), String> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        getrandom::getrandom(&mut nonce).map_err(|e| e.to_string())?;
+        let cipher = &self.ciphers[&self.current];
+        let encrypted = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: value, aad: key })
+            .map_err(|_| String::from("Can not encrypt value"))?;
+        let mut bytes = Vec::with_capacity(NONCE_SIZE + encrypted.len());
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&encrypted);
+        Ok((bytes, self.current))
+    }
+
+    pub fn decrypt(&self, key: &[u8], bytes: &[u8], key_id: u32) -> Result<Vec<u8>, String> {
+        if bytes.len() < NONCE_SIZE {
+            return Err(format!("Truncated encrypted value of {} bytes", bytes.len()));
+        }
+        let cipher = match self.ciphers.get(&key_id) {
+            Some(cipher) => cipher,
+            None => return Err(format!("Unknown encryption key {}", key_id))
+        };
+        let (nonce, encrypted) = bytes.split_at(NONCE_SIZE);
+        cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: encrypted, aad: key })
+            .map_err(|_| String::from("Can not decrypt value"))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encryptor(current: u32) -> ValueEncryptor {
+        let mut keys = HashMap::new();
+        keys.insert(1, vec![1u8; KEY_SIZE]);
+        keys.insert(2, vec![2u8; KEY_SIZE]);
+        ValueEncryptor::new(current, keys).unwrap()
+    }
+
+    #[test]
+    fn encrypt_decrypt_nominal() {
+        let encryptor = encryptor(1);
+        let (encrypted, key_id) = encryptor.encrypt(b"key", b"the value").unwrap();
+        assert_eq!(key_id, 1);
+        assert_ne!(&encrypted[NONCE_SIZE..], b"the value");
+        assert_eq!(encryptor.decrypt(b"key", &encrypted, key_id).unwrap(), b"the value".to_vec());
+    }
+
+    #[test]
+    fn decrypt_after_rotation() {
+        let (encrypted, key_id) = encryptor(1).encrypt(b"key", b"the value").unwrap();
+        let rotated = encryptor(2);
+        assert_eq!(rotated.current_key_id(), 2);
+        assert_eq!(rotated.decrypt(b"key", &encrypted, key_id).unwrap(), b"the value".to_vec());
+    }
+
+    #[test]
+    fn decrypt_under_another_key() {
+        let encryptor = encryptor(1);
+        let (encrypted, key_id) = encryptor.encrypt(b"key", b"the value").unwrap();
+        assert!(encryptor.decrypt(b"other", &encrypted, key_id).is_err());
+        assert!(encryptor.decrypt(b"key", &encrypted, 2).is_err());
+        assert!(encryptor.decrypt(b"key", &encrypted, 7).is_err());
+    }
+
+    #[test]
+    fn new_with_invalid_keys() {
+        assert!(ValueEncryptor::new(3, HashMap::new()).is_err());
+        let mut keys = HashMap::new();
+        keys.insert(1, vec![1u8; 16]);
+        assert!(ValueEncryptor::new(1, keys).is_err());
+    }
+}
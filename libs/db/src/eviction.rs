@@ -0,0 +1,53 @@
+use std::time::SystemTime;
+use byteorder::{BigEndian, ByteOrder};
+use rocksdb::{ColumnFamily, DB, Direction, IteratorMode};
+
+/// Column family mapping every key to the last second it was read or written.
+pub const ACCESS_TIMES_CF: &str = "access_times";
+
+/// Number of access times compared to pick a single victim.
+const SAMPLE_SIZE: usize = 16;
+
+/// Approximates the least recently used key by sampling a few consecutive entries
+/// of the access times column family from a random position, as Redis does.
+pub fn sample_least_recently_used(rocksdb: &DB, access_times: &ColumnFamily, seed: &mut u64) -> Option<Vec<u8>> {
+    let seek_key = random_key(seed);
+    let from = rocksdb.iterator_cf(access_times, IteratorMode::From(&seek_key, Direction::Forward));
+    let from_start = rocksdb.iterator_cf(access_times, IteratorMode::Start);
+    from.chain(from_start)
+        .take(SAMPLE_SIZE)
+        .min_by_key(|(_, last_access)| BigEndian::read_u64(last_access))
+        .map(|(key, _)| key.to_vec())
+}
+
+pub fn initial_seed() -> u64 {
+    let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().subsec_nanos();
+    u64::from(nanos) | 1
+}
+
+/// Two printable characters, which is enough to land anywhere in a memcached key space.
+fn random_key(seed: &mut u64) -> [u8; 2] {
+    let mut key = [0u8; 2];
+    for byte in key.iter_mut() {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        *byte = b'!' + (*seed % 94) as u8;
+    }
+    key
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_key_is_printable() {
+        let mut seed = initial_seed();
+        for _ in 0..1000 {
+            let key = random_key(&mut seed);
+            assert!(key.iter().all(|b| (b'!'..=b'~').contains(b)));
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use bytes::{BufMut, BytesMut};
+
+/// Counters of each row of the count-min sketches, and their number of rows.
+const SKETCH_WIDTH: usize = 2048;
+const SKETCH_DEPTH: usize = 4;
+/// Number of keys reported per window.
+pub const TOP_KEYS: usize = 16;
+
+/// Whether a key was read or written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// A key of the top of a window, with its estimated rates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HotKey {
+    pub key: Vec<u8>,
+    pub reads_per_second: f64,
+    pub writes_per_second: f64,
+}
+
+/// Keys accessed the most over the last complete window, from a sample of the accesses
+/// counted by count-min sketches, only the sampled accesses taking a lock.
+#[derive(Debug)]
+pub struct HotKeys {
+    /// One access in `sample_rate` is counted, 0 disabling the tracker.
+    sample_rate: u64,
+    /// Length of a window in seconds.
+    window: u64,
+    accesses: AtomicU64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    window_start: u64,
+    current: Window,
+    /// Top of the last complete window, the hottest first.
+    previous: Vec<HotKey>,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    reads: Sketch,
+    writes: Sketch,
+    /// Estimated sampled reads and writes of the hottest keys so far.
+    top: HashMap<Vec<u8>, (u64, u64)>,
+}
+
+impl HotKeys {
+    pub fn new(sample_rate: u64, window: u64) -> HotKeys {
+        HotKeys {
+            sample_rate,
+            window: window.max(1),
+            accesses: AtomicU64::new(0),
+            state: Mutex::new(State { window_start: 0, current: Window::default(), previous: vec![] }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sample_rate > 0
+    }
+
+    /// Counts an access at the second `now`, when it is sampled.
+    pub fn record(&self, key: &[u8], access: Access, now: u64) {
+        if !self.is_enabled() || self.accesses.fetch_add(1, Ordering::Relaxed) % self.sample_rate != 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        self.rotate(&mut state, now);
+        state.current.record(key, access);
+    }
+
+    /// The hottest keys of the last complete window at the second `now`, the hottest first.
+    pub fn top(&self, now: u64) -> Vec<HotKey> {
+        let mut state = self.state.lock().unwrap();
+        self.rotate(&mut state, now);
+        state.previous.clone()
+    }
+
+    /// One `HOTKEY <key> <reads per second> <writes per second>` line per key, the hottest first.
+    pub fn serialize(&self, now: u64) -> Vec<u8> {
+        let mut bytes_mut = BytesMut::new();
+        for hot_key in self.top(now) {
+            bytes_mut.put_slice(b"HOTKEY ");
+            bytes_mut.put_slice(&hot_key.key);
+            bytes_mut.put_slice(format!(" {:.2} {:.2}\r\n", hot_key.reads_per_second, hot_key.writes_per_second).as_bytes());
+        }
+        bytes_mut.put_slice(b"END\r\n");
+        bytes_mut.to_vec()
+    }
+
+    /// Starts a new window once the current one is over, the previous top being empty if
+    /// no access was sampled during the last complete window.
+    fn rotate(&self, state: &mut State, now: u64) {
+        if state.window_start == 0 {
+            state.window_start = now;
+        }
+        if now < state.window_start + self.window {
+            return;
+        }
+        state.previous = match now < state.window_start + 2 * self.window {
+            true => state.current.hottest(self.sample_rate as f64 / self.window as f64),
+            false => vec![]
+        };
+        state.current = Window::default();
+        state.window_start = now - (now - state.window_start) % self.window;
+    }
+}
+
+impl Window {
+    fn record(&mut self, key: &[u8], access: Access) {
+        let hashes = hashes(key);
+        match access {
+            Access::Read => self.reads.increment(&hashes),
+            Access::Write => self.writes.increment(&hashes),
+        }
+        let estimate = (self.reads.estimate(&hashes), self.writes.estimate(&hashes));
+        if let Some(counts) = self.top.get_mut(key) {
+            *counts = estimate;
+            return;
+        }
+        if self.top.len() == TOP_KEYS {
+            let coldest = self.top.iter()
+                .min_by_key(|(_, &(reads, writes))| reads + writes)
+                .map(|(key, &(reads, writes))| (key.clone(), reads + writes));
+            match coldest {
+                Some((coldest, total)) if total < estimate.0 + estimate.1 => self.top.remove(&coldest),
+                _ => return
+            };
+        }
+        self.top.insert(key.to_vec(), estimate);
+    }
+
+    /// The top keys, with their sampled counts scaled by `scale` into rates.
+    fn hottest(&self, scale: f64) -> Vec<HotKey> {
+        let mut top: Vec<(&Vec<u8>, &(u64, u64))> = self.top.iter().collect();
+        top.sort_by(|(a_key, &(a_reads, a_writes)), (b_key, &(b_reads, b_writes))| {
+            (b_reads + b_writes).cmp(&(a_reads + a_writes)).then(a_key.cmp(b_key))
+        });
+        top.into_iter().map(|(key, &(reads, writes))| HotKey {
+            key: key.clone(),
+            reads_per_second: reads as f64 * scale,
+            writes_per_second: writes as f64 * scale,
+        }).collect()
+    }
+}
+
+/// Counters of a count-min sketch, whose estimates of a key are never below its actual count.
+#[derive(Debug)]
+struct Sketch {
+    counters: Vec<u32>,
+}
+
+impl Default for Sketch {
+    fn default() -> Sketch {
+        Sketch { counters: vec![0; SKETCH_WIDTH * SKETCH_DEPTH] }
+    }
+}
+
+impl Sketch {
+    fn increment(&mut self, hashes: &[usize; SKETCH_DEPTH]) {
+        for (row, &column) in hashes.iter().enumerate() {
+            let counter = &mut self.counters[row * SKETCH_WIDTH + column];
+            *counter = counter.saturating_add(1);
+        }
+    }
+
+    fn estimate(&self, hashes: &[usize; SKETCH_DEPTH]) -> u64 {
+        hashes.iter().enumerate()
+            .map(|(row, &column)| self.counters[row * SKETCH_WIDTH + column] as u64)
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// Column of the key in every row, derived from the two halves of a single hash.
+fn hashes(key: &[u8]) -> [usize; SKETCH_DEPTH] {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let hash = hasher.finish();
+    let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+    let mut hashes = [0; SKETCH_DEPTH];
+    for (row, column) in hashes.iter_mut().enumerate() {
+        *column = (h1.wrapping_add((row as u64).wrapping_mul(h2)) % SKETCH_WIDTH as u64) as usize;
+    }
+    hashes
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_of_the_last_window() {
+        let hot_keys = HotKeys::new(1, 10);
+        for _ in 0..50 {
+            hot_keys.record(b"viral", Access::Read, 100);
+        }
+        for _ in 0..20 {
+            hot_keys.record(b"viral", Access::Write, 105);
+        }
+        for i in 0..100 {
+            hot_keys.record(format!("key{}", i).as_bytes(), Access::Read, 105);
+        }
+        assert!(hot_keys.top(109).is_empty());
+        let top = hot_keys.top(110);
+        assert_eq!(top.len(), TOP_KEYS);
+        assert_eq!(top[0], HotKey { key: b"viral".to_vec(), reads_per_second: 5.0, writes_per_second: 2.0 });
+        assert!(hot_keys.serialize(115).starts_with(b"HOTKEY viral 5.00 2.00\r\nHOTKEY key"));
+        assert_eq!(hot_keys.serialize(130), b"END\r\n".to_vec());
+    }
+
+    #[test]
+    fn sampled_rates_are_scaled() {
+        let hot_keys = HotKeys::new(4, 2);
+        for _ in 0..40 {
+            hot_keys.record(b"a", Access::Write, 10);
+        }
+        assert_eq!(hot_keys.top(12), vec![HotKey { key: b"a".to_vec(), reads_per_second: 0.0, writes_per_second: 20.0 }]);
+    }
+
+    #[test]
+    fn disabled() {
+        let hot_keys = HotKeys::new(0, 10);
+        hot_keys.record(b"a", Access::Read, 10);
+        assert!(hot_keys.top(20).is_empty());
+    }
+
+    #[test]
+    fn sketch_never_underestimates() {
+        let mut sketch = Sketch::default();
+        for i in 0..10_000u32 {
+            sketch.increment(&hashes(&i.to_be_bytes()));
+        }
+        for _ in 0..3 {
+            sketch.increment(&hashes(b"key"));
+        }
+        assert!(sketch.estimate(&hashes(b"key")) >= 3);
+        assert!(sketch.estimate(&hashes(b"key")) < 20);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use bytes::{BufMut, BytesMut};
+use hdrhistogram::Histogram;
+
+use crate::metrics::command_names;
+
+/// Highest latency tracked by the histograms, in microseconds, longer ones being counted as it.
+const MAX_LATENCY: u64 = 60_000_000;
+const SIGNIFICANT_DIGITS: u8 = 3;
+
+/// Number of histogram sets threads record into, so that they seldom wait for each other.
+const SHARDS: usize = 16;
+
+/// Index of the next thread recording a latency.
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Shard the current thread records into, assigned on its first latency.
+    static SHARD: usize = NEXT_THREAD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+}
+
+/// Latency distribution of every command, in microseconds, as reported by `stats latency`.
+/// Every thread records into its own shard, only merged when serialized.
+#[derive(Debug)]
+pub struct Latencies {
+    /// Histograms of the commands recorded by the threads of each shard, created on their
+    /// first use.
+    shards: Vec<Mutex<HashMap<&'static str, Histogram<u64>>>>,
+}
+
+impl Default for Latencies {
+    fn default() -> Latencies {
+        Latencies { shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect() }
+    }
+}
+
+impl Latencies {
+    pub fn record(&self, command: &str, latency: Duration) {
+        let micros = (latency.as_micros() as u64).min(MAX_LATENCY);
+        let shard = SHARD.with(|shard| *shard);
+        let mut histograms = self.shards[shard].lock().unwrap();
+        if let Some(histogram) = histograms.get_mut(command) {
+            histogram.saturating_record(micros);
+        } else if let Some(name) = command_names().find(|&name| name == command) {
+            // Growing as needed, up to the highest latency tracked
+            let mut histogram = Histogram::new(SIGNIFICANT_DIGITS).unwrap();
+            histogram.saturating_record(micros);
+            histograms.insert(name, histogram);
+        }
+    }
+
+    /// Count and percentiles of the commands run at least once, as `STAT` lines.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut merged: HashMap<&'static str, Histogram<u64>> = HashMap::new();
+        for shard in self.shards.iter() {
+            for (name, histogram) in shard.lock().unwrap().iter() {
+                merged.entry(*name).or_insert_with(|| Histogram::new_from(histogram)).add(histogram).unwrap();
+            }
+        }
+        let mut bytes_mut = BytesMut::new();
+        for name in command_names() {
+            let histogram = match merged.get(name) {
+                Some(histogram) => histogram,
+                None => continue
+            };
+            append_latency(&mut bytes_mut, name, "count", histogram.len());
+            append_latency(&mut bytes_mut, name, "p50", histogram.value_at_quantile(0.5));
+            append_latency(&mut bytes_mut, name, "p90", histogram.value_at_quantile(0.9));
+            append_latency(&mut bytes_mut, name, "p99", histogram.value_at_quantile(0.99));
+            append_latency(&mut bytes_mut, name, "p999", histogram.value_at_quantile(0.999));
+            append_latency(&mut bytes_mut, name, "max", histogram.max());
+        }
+        bytes_mut.put_slice(b"END\r\n");
+        bytes_mut.to_vec()
+    }
+}
+
+fn append_latency(bytes_mut: &mut BytesMut, command: &str, name: &str, value: u64) {
+    bytes_mut.put_slice(format!("STAT latency:{}:{} {}\r\n", command, name, value).as_bytes());
+}
+
+/// A command which took longer than the slow log threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowCommand {
+    pub id: u64,
+    /// Second the command finished at.
+    pub timestamp: u64,
+    pub duration: Duration,
+    pub client: String,
+    pub command: &'static str,
+    pub key: Vec<u8>,
+    /// Size of the value written, or of the response.
+    pub size: usize,
+}
+
+/// The last commands slower than a threshold, the oldest being dropped once full.
+#[derive(Debug)]
+pub struct SlowLog {
+    threshold: Duration,
+    capacity: usize,
+    commands: Mutex<VecDeque<SlowCommand>>,
+    next_id: AtomicU64,
+}
+
+impl SlowLog {
+    /// A threshold or a capacity of 0 disables the log.
+    pub fn new(threshold: Duration, capacity: usize) -> SlowLog {
+        SlowLog { threshold, capacity, commands: Mutex::new(VecDeque::with_capacity(capacity)), next_id: AtomicU64::new(0) }
+    }
+
+    pub fn is_slow(&self, duration: Duration) -> bool {
+        self.capacity > 0 && self.threshold > Duration::from_secs(0) && duration >= self.threshold
+    }
+
+    pub fn push(&self, duration: Duration, client: &str, command: &'static str, key: &[u8], size: usize) {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut commands = self.commands.lock().unwrap();
+        if commands.len() == self.capacity {
+            commands.pop_front();
+        }
+        commands.push_back(SlowCommand { id, timestamp, duration, client: client.to_string(), command, key: key.to_vec(), size });
+    }
+
+    /// The `count` most recent slow commands, the latest first.
+    pub fn latest(&self, count: usize) -> Vec<SlowCommand> {
+        self.commands.lock().unwrap().iter().rev().take(count).cloned().collect()
+    }
+
+    pub fn reset(&self) {
+        self.commands.lock().unwrap().clear();
+    }
+
+    /// One `SLOW <id> <timestamp> <microseconds> <client> <command> <key> <size>` line per
+    /// command, the latest first.
+    pub fn serialize(&self, count: usize) -> Vec<u8> {
+        let mut bytes_mut = BytesMut::new();
+        for command in self.latest(count) {
+            bytes_mut.put_slice(format!("SLOW {} {} {} {} {} ", command.id, command.timestamp, command.duration.as_micros(),
+                                        command.client, command.command).as_bytes());
+            match command.key.is_empty() {
+                true => bytes_mut.put_slice(b"-"),
+                false => bytes_mut.put_slice(&command.key)
+            }
+            bytes_mut.put_slice(format!(" {}\r\n", command.size).as_bytes());
+        }
+        bytes_mut.put_slice(b"END\r\n");
+        bytes_mut.to_vec()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latencies_serialize_used_commands() {
+        let latencies = Latencies::default();
+        for micros in 1..=100 {
+            latencies.record("get", Duration::from_micros(micros));
+        }
+        latencies.record("set", Duration::from_secs(120));
+        latencies.record("unknown", Duration::from_micros(10));
+        latencies.record("resp_get", Duration::from_micros(10));
+        let stats = String::from_utf8(latencies.serialize()).unwrap();
+        assert!(stats.starts_with("STAT latency:get:count 100\r\nSTAT latency:get:p50 50\r\nSTAT latency:get:p90 90\r\nSTAT latency:get:p99 99\r\nSTAT latency:get:p999 100\r\nSTAT latency:get:max 100\r\n"));
+        assert!(stats.contains("STAT latency:set:count 1\r\n"));
+        assert!(stats.contains("STAT latency:resp_get:count 1\r\n"));
+        assert!(!stats.contains("latency:gets:"));
+        assert!(stats.ends_with("END\r\n"));
+    }
+
+    #[test]
+    fn latencies_merge_the_threads() {
+        let latencies = std::sync::Arc::new(Latencies::default());
+        let threads: Vec<_> = (0..4).map(|_| {
+            let latencies = latencies.clone();
+            std::thread::spawn(move || {
+                for micros in 1..=100 {
+                    latencies.record("get", Duration::from_micros(micros));
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let stats = String::from_utf8(latencies.serialize()).unwrap();
+        assert!(stats.starts_with("STAT latency:get:count 400\r\nSTAT latency:get:p50 50\r\n"));
+        assert!(stats.contains("STAT latency:get:max 100\r\n"));
+    }
+
+    #[test]
+    fn slow_log_keeps_the_latest() {
+        let slow_log = SlowLog::new(Duration::from_millis(10), 2);
+        assert!(!slow_log.is_slow(Duration::from_millis(9)));
+        assert!(slow_log.is_slow(Duration::from_millis(10)));
+        slow_log.push(Duration::from_millis(10), "127.0.0.1:1234", "get", b"a", 3);
+        slow_log.push(Duration::from_millis(11), "127.0.0.1:1234", "set", b"b", 4);
+        slow_log.push(Duration::from_millis(12), "127.0.0.1:1234", "stats", b"", 5);
+        let latest = slow_log.latest(10);
+        assert_eq!(latest.iter().map(|command| command.id).collect::<Vec<u64>>(), vec![2, 1]);
+        assert_eq!(slow_log.latest(1)[0].command, "stats");
+        let lines = String::from_utf8(slow_log.serialize(10)).unwrap();
+        assert!(lines.starts_with(&format!("SLOW 2 {} 12000 127.0.0.1:1234 stats - 5\r\nSLOW 1 ", latest[0].timestamp)));
+        slow_log.reset();
+        assert_eq!(slow_log.serialize(10), b"END\r\n".to_vec());
+    }
+
+    #[test]
+    fn slow_log_disabled() {
+        assert!(!SlowLog::new(Duration::from_millis(0), 10).is_slow(Duration::from_secs(1)));
+        assert!(!SlowLog::new(Duration::from_millis(10), 0).is_slow(Duration::from_secs(1)));
+    }
+}
@@ -1,6 +1,22 @@
 
 pub mod command;
 pub mod db;
+pub mod dump;
 pub mod response;
 pub mod parser;
-pub mod byte_utils;
\ No newline at end of file
+pub mod byte_utils;
+pub mod cache;
+pub mod compression;
+pub mod encryption;
+pub mod eviction;
+pub mod hotkeys;
+pub mod latency;
+pub mod metrics;
+pub mod record;
+pub mod replication;
+pub mod resp;
+pub mod sst;
+pub mod stats;
+pub mod tags;
+#[doc(hidden)]
+pub mod test_utils;
\ No newline at end of file
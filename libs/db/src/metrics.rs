@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::command::COMMAND_NAMES;
+use crate::hotkeys::HotKey;
+use crate::resp::RESP_COMMAND_NAMES;
+
+/// Upper bounds, in microseconds, of the buckets of the command latency histograms.
+const LATENCY_BUCKETS: [u64; 13] = [50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000];
+
+/// Listeners whose connections are counted.
+pub const LISTENERS: [&str; 4] = ["memcached", "unix", "resp", "http"];
+
+/// Requests of the HTTP listener, named after their method.
+pub const HTTP_COMMAND_NAMES: [&str; 4] = ["http_get", "http_put", "http_delete", "http_mget"];
+
+/// Commands of every listener whose latency is recorded: the memcached ones, then the RESP
+/// and HTTP ones prefixed with their protocol.
+pub fn command_names() -> impl Iterator<Item = &'static str> {
+    COMMAND_NAMES.iter().chain(RESP_COMMAND_NAMES.iter()).chain(HTTP_COMMAND_NAMES.iter()).copied()
+}
+
+/// Metrics of a namespace, in the order they are exported: name, Prometheus type and help.
+const NAMESPACE_METRICS: [(&str, &str, &str); 13] = [
+    ("expired_unfetched_total", "counter", "Expired records found by a read and queued for deletion."),
+    ("reclaimed_total", "counter", "Expired records removed from RocksDB."),
+    ("hot_cache_hits_total", "counter", "Reads answered by the hot cache."),
+    ("hot_cache_misses_total", "counter", "Reads missing the hot cache."),
+    ("evictions_total", "counter", "Records removed to keep the SST files under max_disk_bytes."),
+    ("compressed_bytes_in_total", "counter", "Size of the compressed values before compression."),
+    ("compressed_bytes_out_total", "counter", "Size of the compressed values after compression."),
+    ("reencrypted_total", "counter", "Records moved to the current encryption key."),
+    ("rocksdb_estimate_num_keys", "gauge", "Estimated number of keys, from rocksdb.estimate-num-keys."),
+    ("rocksdb_total_sst_files_size_bytes", "gauge", "Size of the SST files, from rocksdb.total-sst-files-size."),
+    ("rocksdb_estimate_live_data_size_bytes", "gauge", "Estimated size of the live data, from rocksdb.estimate-live-data-size."),
+    ("rocksdb_memtables_size_bytes", "gauge", "Size of the memtables, from rocksdb.cur-size-all-mem-tables."),
+    ("rocksdb_pending_compaction_bytes", "gauge", "Estimated bytes compaction has to rewrite, from rocksdb.estimate-pending-compaction-bytes."),
+];
+
+/// Values of a namespace, by metric name, and its hottest keys.
+pub type NamespaceMetrics = (String, HashMap<&'static str, u64>, Vec<HotKey>);
+
+/// Counters of the instance exported on the admin listener, the per-namespace ones being
+/// read from the namespaces when rendered.
+#[derive(Debug)]
+pub struct Metrics {
+    commands: HashMap<&'static str, CommandMetrics>,
+    connections: HashMap<&'static str, ConnectionMetrics>,
+}
+
+#[derive(Debug, Default)]
+struct CommandMetrics {
+    /// Count of the commands whose latency is at most the bound of each bucket.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct ConnectionMetrics {
+    open: AtomicI64,
+    total: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            commands: command_names().map(|name| (name, CommandMetrics::default())).collect(),
+            connections: LISTENERS.iter().map(|&name| (name, ConnectionMetrics::default())).collect(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_command(&self, command: &str, latency: Duration) {
+        let metrics = match self.commands.get(command) {
+            Some(metrics) => metrics,
+            None => return
+        };
+        let micros = latency.as_micros() as u64;
+        for (bucket, &bound) in metrics.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            if micros <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        metrics.count.fetch_add(1, Ordering::Relaxed);
+        metrics.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self, listener: &str) {
+        if let Some(metrics) = self.connections.get(listener) {
+            metrics.open.fetch_add(1, Ordering::Relaxed);
+            metrics.total.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn connection_closed(&self, listener: &str) {
+        if let Some(metrics) = self.connections.get(listener) {
+            metrics.open.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a connection refused for exceeding the connection limit.
+    pub fn connection_rejected(&self, listener: &str) {
+        if let Some(metrics) = self.connections.get(listener) {
+            metrics.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text format, with the values and the hot keys
+    /// of every namespace.
+    pub fn render(&self, read_only: bool, namespaces: &[NamespaceMetrics]) -> String {
+        let mut out = String::new();
+        header(&mut out, "commands_total", "counter", "Commands handled, by command.");
+        for name in command_names() {
+            let count = self.commands[name].count.load(Ordering::Relaxed);
+            writeln!(out, "rockscached_commands_total{{command=\"{}\"}} {}", name, count).unwrap();
+        }
+        header(&mut out, "command_duration_seconds", "histogram", "Time taken to handle a command, by command.");
+        for name in command_names() {
+            let metrics = &self.commands[name];
+            for (bucket, &bound) in metrics.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+                writeln!(out, "rockscached_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                         name, bound as f64 / 1e6, bucket.load(Ordering::Relaxed)).unwrap();
+            }
+            let count = metrics.count.load(Ordering::Relaxed);
+            writeln!(out, "rockscached_command_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}", name, count).unwrap();
+            writeln!(out, "rockscached_command_duration_seconds_sum{{command=\"{}\"}} {}",
+                     name, metrics.sum_micros.load(Ordering::Relaxed) as f64 / 1e6).unwrap();
+            writeln!(out, "rockscached_command_duration_seconds_count{{command=\"{}\"}} {}", name, count).unwrap();
+        }
+        header(&mut out, "connections", "gauge", "Open client connections, by listener.");
+        for &listener in LISTENERS.iter() {
+            let open = self.connections[listener].open.load(Ordering::Relaxed);
+            writeln!(out, "rockscached_connections{{listener=\"{}\"}} {}", listener, open).unwrap();
+        }
+        header(&mut out, "connections_total", "counter", "Accepted client connections, by listener.");
+        for &listener in LISTENERS.iter() {
+            let total = self.connections[listener].total.load(Ordering::Relaxed);
+            writeln!(out, "rockscached_connections_total{{listener=\"{}\"}} {}", listener, total).unwrap();
+        }
+        header(&mut out, "connections_rejected_total", "counter", "Connections refused above the connection limit, by listener.");
+        for &listener in LISTENERS.iter() {
+            let rejected = self.connections[listener].rejected.load(Ordering::Relaxed);
+            writeln!(out, "rockscached_connections_rejected_total{{listener=\"{}\"}} {}", listener, rejected).unwrap();
+        }
+        header(&mut out, "read_only", "gauge", "Whether writes are rejected.");
+        writeln!(out, "rockscached_read_only {}", read_only as u8).unwrap();
+        for &(name, kind, help) in NAMESPACE_METRICS.iter() {
+            header(&mut out, name, kind, help);
+            for (namespace, values, _) in namespaces {
+                if let Some(value) = values.get(name) {
+                    writeln!(out, "rockscached_{}{{namespace=\"{}\"}} {}", name, namespace, value).unwrap();
+                }
+            }
+        }
+        header(&mut out, "hot_key_reads_per_second", "gauge", "Estimated reads of the hottest keys over the last window.");
+        for (namespace, _, hot_keys) in namespaces {
+            for hot_key in hot_keys {
+                writeln!(out, "rockscached_hot_key_reads_per_second{{namespace=\"{}\",key=\"{}\"}} {}",
+                         namespace, label_value(&hot_key.key), hot_key.reads_per_second).unwrap();
+            }
+        }
+        header(&mut out, "hot_key_writes_per_second", "gauge", "Estimated writes of the hottest keys over the last window.");
+        for (namespace, _, hot_keys) in namespaces {
+            for hot_key in hot_keys {
+                writeln!(out, "rockscached_hot_key_writes_per_second{{namespace=\"{}\",key=\"{}\"}} {}",
+                         namespace, label_value(&hot_key.key), hot_key.writes_per_second).unwrap();
+            }
+        }
+        out
+    }
+}
+
+/// A key as a label value, with the backslashes, quotes and line feeds escaped.
+fn label_value(key: &[u8]) -> String {
+    String::from_utf8_lossy(key).replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP rockscached_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE rockscached_{} {}", name, kind).unwrap();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_command_histogram() {
+        let metrics = Metrics::default();
+        metrics.record_command("get", Duration::from_micros(80));
+        metrics.record_command("get", Duration::from_millis(3));
+        metrics.record_command("unknown", Duration::from_millis(3));
+        metrics.record_command("resp_get", Duration::from_millis(3));
+        metrics.record_command("http_put", Duration::from_millis(3));
+        let out = metrics.render(false, &[]);
+        assert!(out.contains("rockscached_commands_total{command=\"get\"} 2\n"));
+        assert!(out.contains("rockscached_commands_total{command=\"set\"} 0\n"));
+        assert!(out.contains("rockscached_commands_total{command=\"resp_get\"} 1\n"));
+        assert!(out.contains("rockscached_command_duration_seconds_count{command=\"http_put\"} 1\n"));
+        assert!(out.contains("rockscached_command_duration_seconds_bucket{command=\"get\",le=\"0.00005\"} 0\n"));
+        assert!(out.contains("rockscached_command_duration_seconds_bucket{command=\"get\",le=\"0.0001\"} 1\n"));
+        assert!(out.contains("rockscached_command_duration_seconds_bucket{command=\"get\",le=\"0.005\"} 2\n"));
+        assert!(out.contains("rockscached_command_duration_seconds_bucket{command=\"get\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("rockscached_command_duration_seconds_sum{command=\"get\"} 0.00308\n"));
+        assert!(out.contains("rockscached_read_only 0\n"));
+    }
+
+    #[test]
+    fn render_connections_and_namespaces() {
+        let metrics = Metrics::default();
+        metrics.connection_opened("memcached");
+        metrics.connection_opened("memcached");
+        metrics.connection_closed("memcached");
+        metrics.connection_rejected("resp");
+        metrics.connection_opened("http");
+        let values: HashMap<&'static str, u64> = vec![("evictions_total", 4)].into_iter().collect();
+        let hot_keys = vec![HotKey { key: b"a\"b".to_vec(), reads_per_second: 2.5, writes_per_second: 0.0 }];
+        let out = metrics.render(true, &[(String::from("default"), values, hot_keys)]);
+        assert!(out.contains("rockscached_connections{listener=\"memcached\"} 1\n"));
+        assert!(out.contains("rockscached_connections_total{listener=\"memcached\"} 2\n"));
+        assert!(out.contains("rockscached_connections_rejected_total{listener=\"resp\"} 1\n"));
+        assert!(out.contains("rockscached_connections{listener=\"http\"} 1\n"));
+        assert!(out.contains("# TYPE rockscached_evictions_total counter\nrockscached_evictions_total{namespace=\"default\"} 4\n"));
+        assert!(out.contains("# TYPE rockscached_reclaimed_total counter\n# HELP"));
+        assert!(out.contains("rockscached_hot_key_reads_per_second{namespace=\"default\",key=\"a\\\"b\"} 2.5\n"));
+        assert!(out.contains("rockscached_hot_key_writes_per_second{namespace=\"default\",key=\"a\\\"b\"} 0\n"));
+    }
+}
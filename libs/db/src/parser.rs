@@ -4,13 +4,17 @@ use crate::command::Command;
 use nom::{
     IResult,
     bytes::complete::{tag, take_until, is_not},
-    sequence::tuple,
+    sequence::{preceded, tuple},
     branch::alt,
     character::complete::{crlf, space1, digit1},
+    combinator::opt,
 };
-use crate::byte_utils::{bytes_to_u64, bytes_to_u32};
+use crate::byte_utils::{bytes_to_u64, bytes_to_u32, convert_bytes_to_u32, convert_bytes_to_u64};
 use nom::multi::many1;
 
+/// Longest part of the command line logged for a request which can not be parsed.
+const LOGGED_COMMAND_LENGTH: usize = 64;
+
 #[derive(PartialEq, Debug)]
 struct RawCommand<'a> {
     pub verb: String,
@@ -57,10 +61,107 @@ fn _parse_delete<'a>(input: &'a [u8]) -> IResult<&'a [u8], (&[u8], &[u8])> {
 }
 
 fn parse_stats<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'_>> {
-    let (input, (v, _)) = tuple((tag("stats"), crlf))(input)?;
+    let (input, (v, group, _)) = tuple((tag("stats"), opt(preceded(space1, alt((tag("latency"), tag("hotkeys"))))), crlf))(input)?;
+    Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args: group.into_iter().collect() }))
+}
+
+fn parse_slow_log<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'a>> {
+    let (input, (v, arg, _)) = tuple((tag("slow_log"), opt(preceded(space1, alt((digit1, tag("reset"))))), crlf))(input)?;
+    Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args: arg.into_iter().collect() }))
+}
+
+fn parse_flush_all<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'a>> {
+    let (input, (v, _)) = tuple((tag("flush_all"), crlf))(input)?;
     Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args: vec![] }))
 }
 
+fn parse_backup<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'a>> {
+    let (input, (v, _)) = tuple((tag("backup"), crlf))(input)?;
+    Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args: vec![] }))
+}
+
+fn parse_checkpoint<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'a>> {
+    let (input, (v, _, name, _)) = tuple((tag("checkpoint"), space1, not_space, crlf))(input)?;
+    Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args: vec![name] }))
+}
+
+fn parse_ingest<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'a>> {
+    let (input, (v, paths, _)) = tuple((tag("ingest"), many1(space_and_key), crlf))(input)?;
+    Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args: paths }))
+}
+
+fn parse_read_only<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'a>> {
+    let (input, (v, _, mode, _)) = tuple((tag("read_only"), space1, alt((tag("on"), tag("off"))), crlf))(input)?;
+    Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args: vec![mode] }))
+}
+
+fn parse_use<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'a>> {
+    let (input, (v, _, namespace, _)) = tuple((tag("use"), space1, not_space, crlf))(input)?;
+    Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args: vec![namespace] }))
+}
+
+/// The limit and the cursor being both optional, they always take the second and third
+/// arguments, empty when missing.
+fn parse_keys<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'a>> {
+    let (input, (v, _, prefix, limit, cursor, _)) = tuple((tag("keys"), space1, not_space, opt(preceded(space1, digit1)), opt(preceded(space1, not_space)), crlf))(input)?;
+    let args = vec![prefix, limit.unwrap_or(b""), cursor.unwrap_or(b"")];
+    Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args }))
+}
+
+fn keys_command<'a>(args: &[&'a [u8]]) -> Result<Command<'a>, String> {
+    let limit = match args[1] {
+        b"" => None,
+        limit => match convert_bytes_to_u64(limit) {
+            Ok(limit) if limit > 0 => Some(limit),
+            _ => return Err(String::from("Invalid keys limit"))
+        }
+    };
+    let cursor = match args[2] {
+        b"" => None,
+        cursor => Some(cursor)
+    };
+    Ok(Command::Keys { prefix: args[0], limit, cursor })
+}
+
+fn tset_command<'a>(args: &[&'a [u8]]) -> Result<Command<'a>, String> {
+    match (convert_bytes_to_u32(args[1]), convert_bytes_to_u64(args[2])) {
+        (Ok(flags), Ok(ttl)) => Ok(Command::TaggedSet { key: args[0], flags, ttl, value: args[3], tags: args[4..].to_vec() }),
+        _ => Err(String::from("Invalid flags or expiration"))
+    }
+}
+
+fn parse_tset<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'a>> {
+    let (input, (v, _, key, _, flags, _, expiration_timestamp, _, _, tags, _, value, _)) = tuple((tag("tset"), space1, not_space, space1, digit1, space1, digit1, space1, digit1, many1(space_and_key), crlf, take_until("\r\n"), crlf))(input)?;
+    let mut args = vec![key, flags, expiration_timestamp, value];
+    args.extend(tags);
+    Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args }))
+}
+
+fn parse_invalidate_tag<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'a>> {
+    let (input, (v, _, tag_name, noreply, _)) = tuple((tag("invalidate_tag"), space1, not_space, noreply, crlf))(input)?;
+    let mut args = vec![tag_name];
+    args.extend(noreply);
+    Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args }))
+}
+
+fn noreply<'a>(input: &'a [u8]) -> IResult<&'a [u8], Option<&'a [u8]>> {
+    opt(preceded(space1, tag("noreply")))(input)
+}
+
+fn parse_delete_prefix<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'a>> {
+    let (input, (v, _, prefix, noreply, _)) = tuple((tag("delete_prefix"), space1, not_space, noreply, crlf))(input)?;
+    let mut args = vec![prefix];
+    args.extend(noreply);
+    Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args }))
+}
+
+fn parse_delete_range<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'a>> {
+    let (input, (v, _, start, _, end, noreply, _)) = tuple((tag("delete_range"), space1, not_space, space1, not_space, noreply, crlf))(input)?;
+    let mut args = vec![start, end];
+    args.extend(noreply);
+    Ok((input, RawCommand { verb: String::from_utf8(v.to_vec()).unwrap(), args }))
+}
+
 fn space_and_key<'a>(input: &'a [u8]) -> IResult<&'a [u8], &[u8]> {
     let (input, (_, k)) = tuple((space1, not_space))(input)?;
     Ok((input, k))
@@ -91,7 +192,7 @@ fn parse_delete<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'_>> {
 }
 
 fn parse_raw_command<'a>(input: &'a [u8]) -> IResult<&'a [u8], RawCommand<'_>> {
-    let (input, cmd) = alt((parse_get, parse_delete, parse_set, parse_incr, parse_stats))(input)?;
+    let (input, cmd) = alt((parse_get, parse_delete_prefix, parse_delete_range, parse_delete, parse_set, parse_incr, parse_stats, parse_flush_all, parse_use, parse_keys, parse_tset, parse_invalidate_tag, parse_backup, parse_checkpoint, parse_ingest, parse_read_only, parse_slow_log))(input)?;
     Ok((input, cmd))
 }
 
@@ -108,17 +209,70 @@ pub fn parse(input: &[u8]) -> Result<Command<'_>, String> {
                 "prepend" => Ok(Command::Prepend { key: cmd.args[0], flags: bytes_to_u32(cmd.args[1]), ttl: bytes_to_u64(cmd.args[2]), value: cmd.args[3] }),
                 "incr" => Ok(Command::Increment { key: cmd.args[0], value: bytes_to_u64(cmd.args[1]) }),
                 "decr" => Ok(Command::Decrement { key: cmd.args[0], value: bytes_to_u64(cmd.args[1]) }),
-                "stats" => Ok(Command::Stats),
+                "stats" if cmd.args.is_empty() => Ok(Command::Stats),
+                "stats" => match cmd.args[0] {
+                    b"latency" => Ok(Command::LatencyStats),
+                    _ => Ok(Command::HotKeyStats)
+                },
+                "slow_log" => match cmd.args.first() {
+                    Some(arg) if *arg == b"reset" => Ok(Command::SlowLogReset),
+                    None => Ok(Command::SlowLog { count: None }),
+                    Some(count) => match convert_bytes_to_u64(count) {
+                        Ok(count) => Ok(Command::SlowLog { count: Some(count) }),
+                        Err(_) => Err(String::from("Invalid slow log count"))
+                    }
+                },
+                "flush_all" => Ok(Command::FlushAll),
+                "use" => Ok(Command::Use { namespace: cmd.args[0] }),
+                "delete_prefix" => Ok(Command::DeletePrefix { prefix: cmd.args[0], noreply: cmd.args.len() > 1 }),
+                "delete_range" => Ok(Command::DeleteRange { start: cmd.args[0], end: cmd.args[1], noreply: cmd.args.len() > 2 }),
+                "tset" => tset_command(&cmd.args),
+                "invalidate_tag" => Ok(Command::InvalidateTag { tag: cmd.args[0], noreply: cmd.args.len() > 1 }),
+                "backup" => Ok(Command::Backup),
+                // A name leading out of the checkpoint directory is rejected
+                "checkpoint" => match cmd.args[0] {
+                    b"." | b".." => Err(String::from("Invalid checkpoint name")),
+                    name if name.contains(&b'/') => Err(String::from("Invalid checkpoint name")),
+                    name => Ok(Command::Checkpoint { name })
+                },
+                "read_only" => Ok(Command::ReadOnly { enabled: cmd.args[0] == b"on" }),
+                "ingest" => Ok(Command::Ingest { paths: cmd.args }),
+                "keys" => keys_command(&cmd.args),
                 _ => Err(String::from("Invalid command"))
             }
         }
         _ => {
-            warn!("Unable to parse command `{:?}`", String::from_utf8_lossy(input));
+            warn!("Unable to parse command {}", redact(input));
             Err(String::from("Unable to parse command"))
         }
     }
 }
 
+/// Length of the first complete request of `input`, its command line followed by the data
+/// line of a storage command, or None while it is incomplete.
+pub fn request_length(input: &[u8]) -> Option<usize> {
+    let line = input.windows(2).position(|window| window == b"\r\n")? + 2;
+    let verb_end = input.iter().position(|b| b" \t\r\n".contains(b)).unwrap_or(line);
+    match &input[..verb_end] {
+        b"set" | b"add" | b"append" | b"prepend" | b"tset" => {
+            let data = input[line..].windows(2).position(|window| window == b"\r\n")? + 2;
+            Some(line + data)
+        }
+        _ => Some(line)
+    }
+}
+
+/// The command line of a request, shortened, without the value which may be sensitive.
+fn redact(input: &[u8]) -> String {
+    let line = match input.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => &input[..end],
+        None => input
+    };
+    let shown = &line[..line.len().min(LOGGED_COMMAND_LENGTH)];
+    let ellipsis = if shown.len() < line.len() { "..." } else { "" };
+    format!("`{}{}` ({} bytes)", String::from_utf8_lossy(shown), ellipsis, input.len())
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -157,6 +311,18 @@ mod tests {
     fn parse_for_stats() {
         let result = parse(b"stats\r\n");
         assert_eq!(result.unwrap(), Command::Stats);
+        assert_eq!(parse(b"stats latency\r\n").unwrap(), Command::LatencyStats);
+        assert_eq!(parse(b"stats hotkeys\r\n").unwrap(), Command::HotKeyStats);
+        assert!(parse(b"stats slabs\r\n").is_err());
+    }
+
+    #[test]
+    fn parse_for_slow_log() {
+        assert_eq!(parse(b"slow_log\r\n").unwrap(), Command::SlowLog { count: None });
+        assert_eq!(parse(b"slow_log 10\r\n").unwrap(), Command::SlowLog { count: Some(10) });
+        assert_eq!(parse(b"slow_log reset\r\n").unwrap(), Command::SlowLogReset);
+        assert!(parse(b"slow_log all\r\n").is_err());
+        assert!(parse(b"slow_log 99999999999999999999\r\n").is_err());
     }
 
     #[test]
@@ -188,4 +354,110 @@ mod tests {
         let result = parse(b"decr myKey 1234\r\n");
         assert_eq!(result.unwrap(), Command::Decrement { key: b"myKey", value: 1234 });
     }
+
+    #[test]
+    fn parse_for_flush_all() {
+        let result = parse(b"flush_all\r\n");
+        assert_eq!(result.unwrap(), Command::FlushAll);
+    }
+
+    #[test]
+    fn parse_for_use() {
+        let result = parse(b"use sessions\r\n");
+        assert_eq!(result.unwrap(), Command::Use { namespace: b"sessions" });
+    }
+
+    #[test]
+    fn parse_for_delete_prefix() {
+        let result = parse(b"delete_prefix user:123:\r\n");
+        assert_eq!(result.unwrap(), Command::DeletePrefix { prefix: b"user:123:", noreply: false });
+        let result = parse(b"delete_prefix user:123: noreply\r\n");
+        assert_eq!(result.unwrap(), Command::DeletePrefix { prefix: b"user:123:", noreply: true });
+    }
+
+    #[test]
+    fn parse_for_delete_range() {
+        let result = parse(b"delete_range k1 k5 noreply\r\n");
+        assert_eq!(result.unwrap(), Command::DeleteRange { start: b"k1", end: b"k5", noreply: true });
+    }
+
+    #[test]
+    fn parse_for_tset() {
+        let result = parse(b"tset page:1 0 60 5 product:42 catalog\r\nvalue\r\n");
+        assert_eq!(result.unwrap(), Command::TaggedSet { key: b"page:1", flags: 0, ttl: 60, value: b"value", tags: vec![b"product:42", b"catalog"] });
+        assert!(parse(b"tset page:1 4294967296 60 5 catalog\r\nvalue\r\n").is_err());
+        assert!(parse(b"tset page:1 0 99999999999999999999 5 catalog\r\nvalue\r\n").is_err());
+    }
+
+    #[test]
+    fn parse_for_invalidate_tag() {
+        let result = parse(b"invalidate_tag product:42\r\n");
+        assert_eq!(result.unwrap(), Command::InvalidateTag { tag: b"product:42", noreply: false });
+    }
+
+    #[test]
+    fn parse_for_checkpoint() {
+        let result = parse(b"checkpoint snapshot-1\r\n");
+        assert_eq!(result.unwrap(), Command::Checkpoint { name: b"snapshot-1" });
+        assert!(parse(b"checkpoint /tmp/snapshot\r\n").is_err());
+        assert!(parse(b"checkpoint ../snapshot\r\n").is_err());
+        assert!(parse(b"checkpoint ..\r\n").is_err());
+    }
+
+    #[test]
+    fn parse_for_backup() {
+        assert_eq!(parse(b"backup\r\n").unwrap(), Command::Backup);
+        assert!(parse(b"backup now\r\n").is_err());
+    }
+
+    #[test]
+    fn parse_for_ingest() {
+        let result = parse(b"ingest /tmp/build/data-000001.sst /tmp/build/tags-000001.sst\r\n");
+        assert_eq!(result.unwrap(), Command::Ingest { paths: vec![b"/tmp/build/data-000001.sst", b"/tmp/build/tags-000001.sst"] });
+    }
+
+    #[test]
+    fn parse_for_read_only() {
+        assert_eq!(parse(b"read_only on\r\n").unwrap(), Command::ReadOnly { enabled: true });
+        assert_eq!(parse(b"read_only off\r\n").unwrap(), Command::ReadOnly { enabled: false });
+        assert!(parse(b"read_only maybe\r\n").is_err());
+    }
+
+    #[test]
+    fn parse_for_keys() {
+        let result = parse(b"keys user:\r\n");
+        assert_eq!(result.unwrap(), Command::Keys { prefix: b"user:", limit: None, cursor: None });
+        let result = parse(b"keys user: 50 user:123\r\n");
+        assert_eq!(result.unwrap(), Command::Keys { prefix: b"user:", limit: Some(50), cursor: Some(b"user:123") });
+        let result = parse(b"keys user: user:123\r\n");
+        assert_eq!(result.unwrap(), Command::Keys { prefix: b"user:", limit: None, cursor: Some(b"user:123") });
+        let result = parse(b"keys user: 50\r\n");
+        assert_eq!(result.unwrap(), Command::Keys { prefix: b"user:", limit: Some(50), cursor: None });
+    }
+
+    #[test]
+    fn parse_for_keys_rejects_invalid_limits() {
+        assert!(parse(b"keys user: 0\r\n").is_err());
+        assert!(parse(b"keys user: 0 user:123\r\n").is_err());
+        assert!(parse(b"keys user: 99999999999999999999\r\n").is_err());
+    }
+
+    #[test]
+    fn redact_hides_the_value() {
+        assert_eq!(redact(b"set k 0 0 6\r\nsecret\r\n"), "`set k 0 0 6` (21 bytes)");
+        assert_eq!(redact(b"bogus"), "`bogus` (5 bytes)");
+        let long = format!("get {}\r\n", "k".repeat(100));
+        assert_eq!(redact(long.as_bytes()), format!("`get {}...` (106 bytes)", "k".repeat(60)));
+    }
+
+    #[test]
+    fn request_length_of_pipelined_requests() {
+        let requests = b"get a b\r\nset a 0 0 1\r\nb\r\ndelete a\r\n";
+        assert_eq!(request_length(requests), Some(9));
+        assert_eq!(request_length(&requests[9..]), Some(16));
+        assert_eq!(request_length(&requests[25..]), Some(10));
+        assert_eq!(request_length(b"set a 0 0 1\r\nb"), None);
+        assert_eq!(request_length(b"get a"), None);
+        assert_eq!(request_length(b"\r\n"), Some(2));
+    }
 }
@@ -0,0 +1,253 @@
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+use crc32fast::Hasher;
+
+/// Version written in the first byte of every record.
+/// Records written before versioning start with the most significant byte of their
+/// deadline, which is always 0, so they are read as version 0.
+pub const FORMAT_VERSION: u8 = 1;
+
+const LEGACY_HEADER_SIZE: usize = 20;
+const FIXED_HEADER_SIZE: usize = 22;
+const CHECKSUM_SIZE: usize = 4;
+
+// Bits of the optional fields mask, each present field follows the fixed header in this order
+const COMPRESSED: u8 = 0x01;
+const CREATED_AT: u8 = 0x02;
+const LAST_ACCESS: u8 = 0x04;
+const DICTIONARY_ID: u8 = 0x08;
+const ENCRYPTION_KEY_ID: u8 = 0x10;
+const TAGS: u8 = 0x20;
+
+/// A value as stored in RocksDB.
+///
+/// Layout of version 1:
+/// `[version u8][deadline u64][cas u64][flags u32][fields mask u8][optional fields][crc32 u32][value]`
+/// where the checksum covers everything before it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub deadline: u64,
+    pub cas: u64,
+    pub flags: u32,
+    pub compressed: bool,
+    pub created_at: Option<u64>,
+    pub last_access: Option<u64>,
+    /// Compression dictionary the value was compressed with, if any.
+    pub dictionary_id: Option<u32>,
+    /// Key the value was encrypted with, if any. Values are compressed before being encrypted.
+    pub encryption_key_id: Option<u32>,
+    /// Tags the key can be invalidated by, stored as a count followed by length prefixed tags.
+    pub tags: Vec<Vec<u8>>,
+    pub value: Vec<u8>,
+}
+
+impl Record {
+    pub fn new(deadline: u64, cas: u64, flags: u32, value: Vec<u8>) -> Record {
+        Record {
+            deadline,
+            cas,
+            flags,
+            compressed: false,
+            created_at: None,
+            last_access: None,
+            dictionary_id: None,
+            encryption_key_id: None,
+            tags: vec![],
+            value,
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.deadline <= now
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes_mut = BytesMut::with_capacity(FIXED_HEADER_SIZE + 24 + CHECKSUM_SIZE + self.value.len());
+        let mut mask = 0;
+        if self.compressed {
+            mask |= COMPRESSED;
+        }
+        if self.created_at.is_some() {
+            mask |= CREATED_AT;
+        }
+        if self.last_access.is_some() {
+            mask |= LAST_ACCESS;
+        }
+        if self.dictionary_id.is_some() {
+            mask |= DICTIONARY_ID;
+        }
+        if self.encryption_key_id.is_some() {
+            mask |= ENCRYPTION_KEY_ID;
+        }
+        if !self.tags.is_empty() {
+            mask |= TAGS;
+        }
+        bytes_mut.put_u8(FORMAT_VERSION);
+        bytes_mut.put_u64(self.deadline);
+        bytes_mut.put_u64(self.cas);
+        bytes_mut.put_u32(self.flags);
+        bytes_mut.put_u8(mask);
+        if let Some(created_at) = self.created_at {
+            bytes_mut.put_u64(created_at);
+        }
+        if let Some(last_access) = self.last_access {
+            bytes_mut.put_u64(last_access);
+        }
+        if let Some(dictionary_id) = self.dictionary_id {
+            bytes_mut.put_u32(dictionary_id);
+        }
+        if let Some(encryption_key_id) = self.encryption_key_id {
+            bytes_mut.put_u32(encryption_key_id);
+        }
+        if !self.tags.is_empty() {
+            bytes_mut.put_u16(self.tags.len() as u16);
+            for tag in &self.tags {
+                bytes_mut.put_u16(tag.len() as u16);
+                bytes_mut.put_slice(tag);
+            }
+        }
+        let checksum = checksum(&bytes_mut);
+        bytes_mut.put_u32(checksum);
+        bytes_mut.put_slice(&self.value);
+        bytes_mut.to_vec()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Record, String> {
+        match bytes.first() {
+            Some(0) => decode_legacy(bytes),
+            Some(&FORMAT_VERSION) => decode_v1(bytes),
+            Some(version) => Err(format!("Unsupported record version {}", version)),
+            None => Err(String::from("Empty record")),
+        }
+    }
+
+    pub fn is_legacy(bytes: &[u8]) -> bool {
+        bytes.first() == Some(&0)
+    }
+}
+
+fn decode_legacy(bytes: &[u8]) -> Result<Record, String> {
+    if bytes.len() < LEGACY_HEADER_SIZE {
+        return Err(format!("Truncated record of {} bytes", bytes.len()));
+    }
+    Ok(Record::new(
+        BigEndian::read_u64(&bytes[0..8]),
+        BigEndian::read_u64(&bytes[8..16]),
+        BigEndian::read_u32(&bytes[16..20]),
+        bytes[LEGACY_HEADER_SIZE..].to_vec(),
+    ))
+}
+
+fn decode_v1(bytes: &[u8]) -> Result<Record, String> {
+    if bytes.len() < FIXED_HEADER_SIZE + CHECKSUM_SIZE {
+        return Err(format!("Truncated record of {} bytes", bytes.len()));
+    }
+    let mask = bytes[21];
+    let mut header_size = FIXED_HEADER_SIZE;
+    let mut read_optional = |present: bool, size: usize| -> Result<Option<&[u8]>, String> {
+        if !present {
+            return Ok(None);
+        }
+        if bytes.len() < header_size + size + CHECKSUM_SIZE {
+            return Err(format!("Truncated record of {} bytes", bytes.len()));
+        }
+        let field = &bytes[header_size..header_size + size];
+        header_size += size;
+        Ok(Some(field))
+    };
+    let created_at = read_optional(mask & CREATED_AT != 0, 8)?.map(BigEndian::read_u64);
+    let last_access = read_optional(mask & LAST_ACCESS != 0, 8)?.map(BigEndian::read_u64);
+    let dictionary_id = read_optional(mask & DICTIONARY_ID != 0, 4)?.map(BigEndian::read_u32);
+    let encryption_key_id = read_optional(mask & ENCRYPTION_KEY_ID != 0, 4)?.map(BigEndian::read_u32);
+    let mut tags = vec![];
+    if let Some(count) = read_optional(mask & TAGS != 0, 2)? {
+        for _ in 0..BigEndian::read_u16(count) {
+            let length = read_optional(true, 2)?.map(BigEndian::read_u16).unwrap_or(0);
+            if let Some(tag) = read_optional(true, length as usize)? {
+                tags.push(tag.to_vec());
+            }
+        }
+    }
+    let stored_checksum = BigEndian::read_u32(&bytes[header_size..header_size + CHECKSUM_SIZE]);
+    if stored_checksum != checksum(&bytes[..header_size]) {
+        return Err(String::from("Corrupted record header"));
+    }
+    Ok(Record {
+        deadline: BigEndian::read_u64(&bytes[1..9]),
+        cas: BigEndian::read_u64(&bytes[9..17]),
+        flags: BigEndian::read_u32(&bytes[17..21]),
+        compressed: mask & COMPRESSED != 0,
+        created_at,
+        last_access,
+        dictionary_id,
+        encryption_key_id,
+        tags,
+        value: bytes[header_size + CHECKSUM_SIZE..].to_vec(),
+    })
+}
+
+fn checksum(header: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(header);
+    hasher.finalize()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_nominal() {
+        let record = Record::new(1234, 5, 42, b"the value".to_vec());
+        assert_eq!(Record::decode(&record.encode()).unwrap(), record);
+    }
+
+    #[test]
+    fn encode_decode_optional_fields() {
+        let mut record = Record::new(1234, 5, 42, b"the value".to_vec());
+        record.compressed = true;
+        record.last_access = Some(1000);
+        assert_eq!(Record::decode(&record.encode()).unwrap(), record);
+        record.created_at = Some(999);
+        assert_eq!(Record::decode(&record.encode()).unwrap(), record);
+        record.dictionary_id = Some(3);
+        assert_eq!(Record::decode(&record.encode()).unwrap(), record);
+        record.encryption_key_id = Some(2);
+        assert_eq!(Record::decode(&record.encode()).unwrap(), record);
+        record.tags = vec![b"product:42".to_vec(), b"".to_vec(), b"catalog".to_vec()];
+        assert_eq!(Record::decode(&record.encode()).unwrap(), record);
+    }
+
+    #[test]
+    fn decode_legacy_record() {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&u64::to_be_bytes(1234));
+        bytes.extend_from_slice(&u64::to_be_bytes(5));
+        bytes.extend_from_slice(&u32::to_be_bytes(42));
+        bytes.extend_from_slice(b"the value");
+        assert!(Record::is_legacy(&bytes));
+        assert_eq!(Record::decode(&bytes).unwrap(), Record::new(1234, 5, 42, b"the value".to_vec()));
+    }
+
+    #[test]
+    fn decode_truncated() {
+        assert!(Record::decode(b"").is_err());
+        assert!(Record::decode(&[0u8; 7]).is_err());
+        assert!(Record::decode(&[1u8; 12]).is_err());
+    }
+
+    #[test]
+    fn decode_corrupted_header() {
+        let mut bytes = Record::new(1234, 5, 42, b"the value".to_vec()).encode();
+        bytes[3] ^= 0xff;
+        assert!(Record::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn decode_unknown_version() {
+        let mut bytes = Record::new(1234, 5, 42, b"the value".to_vec()).encode();
+        bytes[0] = 9;
+        assert!(Record::decode(&bytes).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use log::{info, warn};
+use rocksdb::Error;
+
+use crate::db::Database;
+
+/// Primary–replica replication over the write-ahead log.
+///
+/// A replica sends a request, `[REQUEST_UPDATES][next sequence u64]` to follow the primary
+/// or `[REQUEST_CHECKPOINT]` to get a copy of its data directory, and the primary answers
+/// with frames:
+/// `[FRAME_UPDATE][sequence u64][length u32][write batch]` for every write batch,
+/// `[FRAME_HEARTBEAT]` when idle, and `[FRAME_CHECKPOINT][next sequence u64][file count u32]`
+/// followed by `[name length u16][name][size u64][content]` for every file of a checkpoint,
+/// when the write-ahead log does not go back to the requested sequence anymore.
+///
+/// Replicas are not authenticated and get every namespace in plain, encrypted values aside,
+/// so the replication address must only be reachable from a private network.
+const REQUEST_UPDATES: u8 = 1;
+const REQUEST_CHECKPOINT: u8 = 2;
+const FRAME_UPDATE: u8 = 1;
+const FRAME_CHECKPOINT: u8 = 2;
+const FRAME_HEARTBEAT: u8 = 3;
+
+/// Maximum number of write batches read from the write-ahead log at once.
+const MAX_UPDATES_PER_READ: usize = 1_000;
+/// Longest write batch of an update frame, above the largest value stored with its record.
+const MAX_FRAME_LENGTH: usize = 1024 * 1024 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Time without any frame after which a replica considers its primary gone.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+static CHECKPOINTS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Update { sequence: u64, data: Vec<u8> },
+    Checkpoint { next: u64, files: u32 },
+    Heartbeat,
+}
+
+/// Serves the replicas connecting to `listener`, each from its own thread.
+pub fn serve(listener: TcpListener, db: Arc<Database>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Can not accept a replica {}", e);
+                continue;
+            }
+        };
+        let db = db.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+            info!("Replica {} connected", peer);
+            if let Err(e) = serve_replica(&db, stream) {
+                info!("Replica {} disconnected {}", peer, e);
+            }
+        });
+    }
+}
+
+fn serve_replica(db: &Database, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    match reader.read_u8()? {
+        REQUEST_CHECKPOINT => {
+            send_checkpoint(db, &mut writer)?;
+            writer.flush()
+        }
+        REQUEST_UPDATES => {
+            let next = reader.read_u64::<BigEndian>()?;
+            send_updates(db, next, &mut writer)
+        }
+        request => Err(io::Error::new(ErrorKind::InvalidData, format!("Unknown replication request {}", request)))
+    }
+}
+
+/// Streams the write batches from `next` on, until the replica disconnects.
+fn send_updates<W: Write>(db: &Database, mut next: u64, writer: &mut W) -> io::Result<()> {
+    let mut last_frame = Instant::now();
+    loop {
+        match db.updates_since(next, MAX_UPDATES_PER_READ).map_err(rocksdb_error)? {
+            Some((updates, following)) => {
+                let idle = updates.is_empty();
+                for (sequence, data) in updates {
+                    write_frame(writer, &Frame::Update { sequence, data })?;
+                }
+                next = following;
+                if idle {
+                    if last_frame.elapsed() >= HEARTBEAT_INTERVAL {
+                        write_frame(writer, &Frame::Heartbeat)?;
+                        writer.flush()?;
+                        last_frame = Instant::now();
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            }
+            None => {
+                info!("Write-ahead log purged since sequence {}, sending a full sync", next);
+                next = send_checkpoint(db, writer)?;
+            }
+        }
+        writer.flush()?;
+        last_frame = Instant::now();
+    }
+}
+
+/// Sends a checkpoint of the whole data directory, returning the sequence number from
+/// which the updates follow it. Write batches of the checkpoint may be sent again
+/// afterwards, which is harmless as replaying them in order leads to the same state.
+fn send_checkpoint<W: Write>(db: &Database, writer: &mut W) -> io::Result<u64> {
+    let next = db.latest_sequence_number() + 1;
+    let path = format!("{}.replication-{}", db.path().display(), CHECKPOINTS.fetch_add(1, Ordering::Relaxed));
+    db.checkpoint(&path).map_err(rocksdb_error)?;
+    let result = send_files(&path, next, writer);
+    fs::remove_dir_all(&path)?;
+    result.map(|_| next)
+}
+
+fn send_files<W: Write>(path: &str, next: u64, writer: &mut W) -> io::Result<()> {
+    let mut files = vec![];
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(entry);
+        }
+    }
+    write_frame(writer, &Frame::Checkpoint { next, files: files.len() as u32 })?;
+    for entry in files {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let mut file = File::open(entry.path())?;
+        let size = file.metadata()?.len();
+        writer.write_u16::<BigEndian>(name.len() as u16)?;
+        writer.write_all(name.as_bytes())?;
+        writer.write_u64::<BigEndian>(size)?;
+        let copied = io::copy(&mut (&mut file).take(size), writer)?;
+        if copied != size {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, format!("{} changed while being sent", name)));
+        }
+    }
+    Ok(())
+}
+
+/// Follows the primary at `address` forever, reconnecting and resuming from the last
+/// applied write batch whenever the connection is lost.
+pub fn replicate(address: &str, db: Arc<Database>) {
+    loop {
+        if let Err(e) = follow(address, &db) {
+            warn!("Replication from {} interrupted {}", address, e);
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+fn follow(address: &str, db: &Database) -> io::Result<()> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let next = db.replication_sequence().map_err(rocksdb_error)?;
+    writer.write_u8(REQUEST_UPDATES)?;
+    writer.write_u64::<BigEndian>(next)?;
+    info!("Replicating from {} since sequence {}", address, next);
+    let sync_path = format!("{}.sync", db.path().display());
+    loop {
+        match read_frame(&mut reader)? {
+            Frame::Update { sequence, data } => db.apply_update(sequence, &data).map_err(rocksdb_error)?,
+            Frame::Checkpoint { next, files } => {
+                info!("Full sync from {} at sequence {}", address, next);
+                receive_files(&mut reader, &sync_path, files)?;
+                db.replace_with_checkpoint(&sync_path, next).map_err(rocksdb_error)?;
+            }
+            Frame::Heartbeat => ()
+        }
+    }
+}
+
+/// Copies a checkpoint of the primary at `address` into `path`, which must not exist yet,
+/// returning the sequence number to replicate from. A replica starts from such a copy, so
+/// that its column families are the same as those of its primary.
+pub fn fetch_checkpoint(address: &str, path: &str) -> io::Result<u64> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    writer.write_u8(REQUEST_CHECKPOINT)?;
+    match read_frame(&mut reader)? {
+        Frame::Checkpoint { next, files } => {
+            // Downloaded aside so that an interrupted copy is not mistaken for a data directory
+            let download_path = format!("{}.sync", path);
+            receive_files(&mut reader, &download_path, files)?;
+            fs::rename(&download_path, path)?;
+            Ok(next)
+        }
+        frame => Err(io::Error::new(ErrorKind::InvalidData, format!("Unexpected frame {:?}", frame)))
+    }
+}
+
+fn receive_files<R: Read>(reader: &mut R, path: &str, files: u32) -> io::Result<()> {
+    if Path::new(path).exists() {
+        fs::remove_dir_all(path)?;
+    }
+    fs::create_dir_all(path)?;
+    for _ in 0..files {
+        let mut name = vec![0u8; reader.read_u16::<BigEndian>()? as usize];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if name.contains('/') || name == ".." {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("Invalid file name {}", name)));
+        }
+        let size = reader.read_u64::<BigEndian>()?;
+        let mut file = BufWriter::new(File::create(Path::new(path).join(&name))?);
+        let copied = io::copy(&mut (&mut *reader).take(size), &mut file)?;
+        if copied != size {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, format!("Truncated file {}", name)));
+        }
+        file.flush()?;
+    }
+    Ok(())
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    match frame {
+        Frame::Update { sequence, data } => {
+            writer.write_u8(FRAME_UPDATE)?;
+            writer.write_u64::<BigEndian>(*sequence)?;
+            writer.write_u32::<BigEndian>(data.len() as u32)?;
+            writer.write_all(data)
+        }
+        Frame::Checkpoint { next, files } => {
+            writer.write_u8(FRAME_CHECKPOINT)?;
+            writer.write_u64::<BigEndian>(*next)?;
+            writer.write_u32::<BigEndian>(*files)
+        }
+        Frame::Heartbeat => writer.write_u8(FRAME_HEARTBEAT)
+    }
+}
+
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Frame> {
+    match reader.read_u8()? {
+        FRAME_UPDATE => {
+            let sequence = reader.read_u64::<BigEndian>()?;
+            let length = reader.read_u32::<BigEndian>()? as usize;
+            if length > MAX_FRAME_LENGTH {
+                return Err(io::Error::new(ErrorKind::InvalidData, format!("Invalid replication frame length {}", length)));
+            }
+            let mut data = vec![0u8; length];
+            reader.read_exact(&mut data)?;
+            Ok(Frame::Update { sequence, data })
+        }
+        FRAME_CHECKPOINT => {
+            let next = reader.read_u64::<BigEndian>()?;
+            let files = reader.read_u32::<BigEndian>()?;
+            Ok(Frame::Checkpoint { next, files })
+        }
+        FRAME_HEARTBEAT => Ok(Frame::Heartbeat),
+        frame => Err(io::Error::new(ErrorKind::InvalidData, format!("Unknown replication frame {}", frame)))
+    }
+}
+
+fn rocksdb_error(e: Error) -> io::Error {
+    io::Error::new(ErrorKind::Other, e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::db::DatabaseOptions;
+    use crate::response::Response;
+    use crate::test_utils::{TempDatabase, TempDir};
+
+    #[test]
+    fn write_read_frames() {
+        let frames = vec![
+            Frame::Update { sequence: 42, data: b"batch".to_vec() },
+            Frame::Heartbeat,
+            Frame::Checkpoint { next: 43, files: 7 },
+        ];
+        let mut bytes = vec![];
+        for frame in &frames {
+            write_frame(&mut bytes, frame).unwrap();
+        }
+        let mut reader = Cursor::new(bytes);
+        for frame in frames {
+            assert_eq!(read_frame(&mut reader).unwrap(), frame);
+        }
+        assert!(read_frame(&mut reader).is_err());
+    }
+
+    #[test]
+    fn read_unknown_frame() {
+        assert!(read_frame(&mut Cursor::new(vec![9u8])).is_err());
+    }
+
+    #[test]
+    fn read_too_long_frame() {
+        let mut bytes = vec![FRAME_UPDATE];
+        bytes.write_u64::<BigEndian>(42).unwrap();
+        bytes.write_u32::<BigEndian>(MAX_FRAME_LENGTH as u32 + 1).unwrap();
+        let error = read_frame(&mut Cursor::new(bytes)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    fn replica(path: &str) -> Arc<Database> {
+        Database::open_with_options(path, &DatabaseOptions { replica: true, ..Default::default() })
+    }
+
+    #[test]
+    fn replica_follows_its_primary() {
+        let options = DatabaseOptions { wal_ttl: 60, ..Default::default() };
+        let primary = TempDatabase::open_with_options("primary", &options);
+        assert_eq!(primary.insert(b"before", 0, 100, b"a"), Response::Stored);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let primary_db = primary.clone();
+        thread::spawn(move || serve(listener, primary_db));
+        let replica_dir = TempDir::new("replica");
+        let next = fetch_checkpoint(&address, replica_dir.path()).unwrap();
+        let replica = replica(replica_dir.path());
+        replica.set_replication_sequence(next).unwrap();
+        assert!(replica.get_live_record(b"before").is_some());
+        let replica_db = replica.clone();
+        thread::spawn(move || replicate(&address, replica_db));
+        assert_eq!(primary.insert(b"after", 0, 100, b"b"), Response::Stored);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while replica.get_live_record(b"after").is_none() && Instant::now() < deadline {
+            thread::sleep(POLL_INTERVAL);
+        }
+        assert_eq!(replica.get_live_record(b"after").unwrap().value, b"b".to_vec());
+        assert!(replica.is_read_only());
+    }
+
+    #[test]
+    fn full_sync_replaces_the_data() {
+        let primary = TempDatabase::open("sync-primary");
+        assert_eq!(primary.insert(b"kept", 0, 100, b"a"), Response::Stored);
+        let checkpoint_dir = TempDir::new("sync-checkpoint");
+        primary.checkpoint(checkpoint_dir.path()).unwrap();
+        let replica_dir = TempDir::new("sync-replica");
+        let replica = replica(replica_dir.path());
+        assert_eq!(replica.insert(b"stale", 0, 100, b"b"), Response::Stored);
+        replica.replace_with_checkpoint(checkpoint_dir.path(), 42).unwrap();
+        assert!(!replica.is_syncing());
+        assert!(replica.get_live_record(b"kept").is_some());
+        assert!(replica.get_live_record(b"stale").is_none());
+        assert_eq!(replica.replication_sequence().unwrap(), 42);
+    }
+
+    #[test]
+    fn receive_files_rejects_paths() {
+        let mut bytes = vec![];
+        bytes.write_u16::<BigEndian>(10).unwrap();
+        bytes.extend_from_slice(b"../CURRENT");
+        let dir = TempDir::new("replication");
+        assert!(receive_files(&mut Cursor::new(bytes), dir.path(), 1).is_err());
+    }
+}
@@ -0,0 +1,420 @@
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use bytes::{BufMut, BytesMut};
+
+use crate::db::Database;
+use crate::response::Response;
+
+/// Time to live of the keys set without an expiration, which `TTL` reports as persistent.
+pub const PERSISTENT_TTL: u64 = u32::MAX as u64;
+
+/// Commands of a session, as recorded in the metrics and latency histograms.
+pub const RESP_COMMAND_NAMES: [&str; 13] = [
+    "resp_ping", "resp_info", "resp_hello", "resp_get", "resp_mget", "resp_del", "resp_exists", "resp_ttl",
+    "resp_set", "resp_incrby", "resp_decrby", "resp_append", "resp_expire",
+];
+
+/// Most arguments of a request, as in Redis.
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+
+/// Longest argument of a request, as in Redis.
+const MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+
+/// A reply of the RESP protocol, serialized for the version negotiated with `HELLO`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    /// Sent as a flat array of keys and values to RESP2 clients.
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    pub fn serialize(&self, protocol: u8, bytes_mut: &mut BytesMut) {
+        match self {
+            Reply::Simple(s) => put_line(bytes_mut, b'+', s.as_bytes()),
+            Reply::Error(e) => put_line(bytes_mut, b'-', e.as_bytes()),
+            Reply::Integer(n) => put_line(bytes_mut, b':', n.to_string().as_bytes()),
+            Reply::Bulk(value) => {
+                put_line(bytes_mut, b'$', value.len().to_string().as_bytes());
+                bytes_mut.put_slice(value);
+                bytes_mut.put_slice(b"\r\n");
+            }
+            Reply::Null if protocol >= 3 => bytes_mut.put_slice(b"_\r\n"),
+            Reply::Null => bytes_mut.put_slice(b"$-1\r\n"),
+            Reply::Array(replies) => {
+                put_line(bytes_mut, b'*', replies.len().to_string().as_bytes());
+                for reply in replies {
+                    reply.serialize(protocol, bytes_mut);
+                }
+            }
+            Reply::Map(entries) => {
+                match protocol {
+                    3 => put_line(bytes_mut, b'%', entries.len().to_string().as_bytes()),
+                    _ => put_line(bytes_mut, b'*', (entries.len() * 2).to_string().as_bytes())
+                }
+                for (key, value) in entries {
+                    key.serialize(protocol, bytes_mut);
+                    value.serialize(protocol, bytes_mut);
+                }
+            }
+        }
+    }
+}
+
+fn put_line(bytes_mut: &mut BytesMut, prefix: u8, line: &[u8]) {
+    bytes_mut.put_u8(prefix);
+    bytes_mut.put_slice(line);
+    bytes_mut.put_slice(b"\r\n");
+}
+
+/// Arguments of a request, followed by the number of bytes it takes.
+pub type Request<'a> = (Vec<&'a [u8]>, usize);
+
+/// Reads the first request of `input`, either an array of bulk strings or an inline command,
+/// or `None` while incomplete.
+pub fn parse_request(input: &[u8]) -> Result<Option<Request<'_>>, String> {
+    if input.first() != Some(&b'*') {
+        let end = match input.iter().position(|&byte| byte == b'\n') {
+            Some(end) => end,
+            None => return Ok(None)
+        };
+        let line = match end > 0 && input[end - 1] == b'\r' {
+            true => &input[..end - 1],
+            false => &input[..end]
+        };
+        let args = line.split(|byte| byte.is_ascii_whitespace()).filter(|arg| !arg.is_empty()).collect();
+        return Ok(Some((args, end + 1)));
+    }
+    let (count, mut position) = match read_length(input, 0, b'*', MAX_ARGUMENTS)? {
+        Some(header) => header,
+        None => return Ok(None)
+    };
+    // The count is not trusted to size the arguments before they are read
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let (length, start) = match read_length(input, position, b'$', MAX_BULK_LENGTH)? {
+            Some(header) => header,
+            None => return Ok(None)
+        };
+        if input.len() < start + length + 2 {
+            return Ok(None);
+        }
+        if &input[start + length..start + length + 2] != b"\r\n" {
+            return Err(String::from("Protocol error: expected '\\r\\n' after bulk string"));
+        }
+        args.push(&input[start..start + length]);
+        position = start + length + 2;
+    }
+    Ok(Some((args, position)))
+}
+
+/// Reads a `<prefix><length>\r\n` header at `position`, returning the length and where the
+/// header ends, a length above `max` being an error.
+fn read_length(input: &[u8], position: usize, prefix: u8, max: usize) -> Result<Option<(usize, usize)>, String> {
+    let end = match input[position..].windows(2).position(|window| window == b"\r\n") {
+        Some(end) => position + end,
+        None => return Ok(None)
+    };
+    if input[position] != prefix {
+        return Err(format!("Protocol error: expected '{}', got '{}'", prefix as char, input[position] as char));
+    }
+    match std::str::from_utf8(&input[position + 1..end]).ok().and_then(|length| length.parse().ok()) {
+        Some(length) if length <= max => Ok(Some((length, end + 2))),
+        _ => Err(String::from("Protocol error: invalid length"))
+    }
+}
+
+/// State of a RESP connection, which runs its commands against the same storage as the
+/// memcached listener.
+pub struct Session {
+    db: Arc<Database>,
+    protocol: u8,
+}
+
+impl Session {
+    pub fn new(db: Arc<Database>) -> Session {
+        Session { db, protocol: 2 }
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    pub fn handle(&mut self, args: &[&[u8]]) -> Reply {
+        let (name, args) = match args.split_first() {
+            Some((name, args)) => (String::from_utf8_lossy(name).to_ascii_uppercase(), args),
+            None => return Reply::Error(String::from("ERR empty command"))
+        };
+        let arity = match name.as_str() {
+            "PING" | "INFO" | "HELLO" => 0,
+            "GET" | "MGET" | "DEL" | "TTL" | "EXISTS" => 1,
+            "SET" | "INCRBY" | "DECRBY" | "APPEND" | "EXPIRE" => 2,
+            _ => return Reply::Error(format!("ERR unknown command '{}'", name))
+        };
+        if args.len() < arity {
+            return Reply::Error(format!("ERR wrong number of arguments for '{}' command", name.to_ascii_lowercase()));
+        }
+        let is_write = matches!(name.as_str(), "SET" | "DEL" | "INCRBY" | "DECRBY" | "APPEND" | "EXPIRE");
+        if is_write && self.db.is_read_only() {
+            return Reply::Error(String::from("READONLY You can't write against a read only instance."));
+        }
+
+        let start = Instant::now();
+        let reply = self.run(&name, args);
+        self.db.record_command(&format!("resp_{}", name.to_ascii_lowercase()), start.elapsed());
+        reply
+    }
+
+    /// Runs a known command whose arguments are checked.
+    fn run(&mut self, name: &str, args: &[&[u8]]) -> Reply {
+        match name {
+            "PING" => match args.first() {
+                Some(message) => Reply::Bulk(message.to_vec()),
+                None => Reply::Simple(String::from("PONG"))
+            },
+            "HELLO" => self.hello(args),
+            "INFO" => Reply::Bulk(info(&self.db)),
+            "GET" => match self.db.get_live_record(args[0]) {
+                Some(record) => Reply::Bulk(record.value),
+                None => Reply::Null
+            },
+            "MGET" => Reply::Array(args.iter().map(|key| match self.db.get_live_record(key) {
+                Some(record) => Reply::Bulk(record.value),
+                None => Reply::Null
+            }).collect()),
+            "SET" => self.set(args),
+            "DEL" => {
+                let deleted = args.iter().filter(|key| {
+                    self.db.get_live_record_for_update(key).is_some() && self.db.delete(key) == Response::Stored
+                }).count();
+                Reply::Integer(deleted as i64)
+            }
+            "EXISTS" => Reply::Integer(args.iter().filter(|key| self.db.get_live_record(key).is_some()).count() as i64),
+            "INCRBY" | "DECRBY" => match parse_integer(args[1]) {
+                Some(increment) if name == "DECRBY" => self.increment(args[0], increment.checked_neg()),
+                increment => self.increment(args[0], increment)
+            },
+            "APPEND" => self.append(args[0], args[1]),
+            "EXPIRE" => match parse_integer(args[1]) {
+                Some(seconds) if seconds <= 0 => self.run("DEL", &args[..1]),
+                Some(seconds) => Reply::Integer((self.db.touch(args[0], seconds as u64) == Response::Stored) as i64),
+                None => not_an_integer()
+            },
+            "TTL" => match self.db.get_live_record(args[0]) {
+                Some(record) => match record.deadline.saturating_sub(current_second()) {
+                    remaining if remaining > PERSISTENT_TTL / 2 => Reply::Integer(-1),
+                    remaining => Reply::Integer(remaining as i64)
+                },
+                None => Reply::Integer(-2)
+            },
+            _ => unreachable!()
+        }
+    }
+
+    /// Switches to the requested protocol version, answering with the server properties.
+    fn hello(&mut self, args: &[&[u8]]) -> Reply {
+        if let Some(version) = args.first() {
+            match parse_integer(version) {
+                Some(version) if version == 2 || version == 3 => self.protocol = version as u8,
+                _ => return Reply::Error(String::from("NOPROTO unsupported protocol version"))
+            }
+        }
+        Reply::Map(vec![
+            (Reply::Bulk(b"server".to_vec()), Reply::Bulk(b"rockscached".to_vec())),
+            (Reply::Bulk(b"version".to_vec()), Reply::Bulk(env!("CARGO_PKG_VERSION").as_bytes().to_vec())),
+            (Reply::Bulk(b"proto".to_vec()), Reply::Integer(self.protocol as i64)),
+            (Reply::Bulk(b"mode".to_vec()), Reply::Bulk(b"standalone".to_vec())),
+        ])
+    }
+
+    /// `SET key value [EX seconds|PX milliseconds] [NX|XX]`
+    fn set(&self, args: &[&[u8]]) -> Reply {
+        let (key, value) = (args[0], args[1]);
+        let mut ttl = PERSISTENT_TTL;
+        let (mut if_absent, mut if_present) = (false, false);
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match String::from_utf8_lossy(option).to_ascii_uppercase().as_str() {
+                "NX" if !if_present => if_absent = true,
+                "XX" if !if_absent => if_present = true,
+                unit @ "EX" | unit @ "PX" => {
+                    ttl = match options.next().and_then(|amount| parse_integer(amount)) {
+                        Some(amount) if amount > 0 && unit == "EX" => amount as u64,
+                        // Expiration times are kept in seconds
+                        Some(amount) if amount > 0 => (amount as u64 + 999) / 1000,
+                        Some(_) => return Reply::Error(String::from("ERR invalid expire time in 'set' command")),
+                        None => return not_an_integer()
+                    };
+                }
+                _ => return Reply::Error(String::from("ERR syntax error"))
+            }
+        }
+        let response = match (if_absent, if_present) {
+            (true, _) => self.db.insert_if_not_present(key, 0, ttl, value),
+            (_, true) => self.db.replace(key, 0, ttl, value),
+            _ => self.db.insert(key, 0, ttl, value)
+        };
+        match response {
+            Response::Stored => Reply::Simple(String::from("OK")),
+            Response::NotStored => Reply::Null,
+            _ => Reply::Error(String::from("ERR can not store the value"))
+        }
+    }
+
+    /// Adds `increment` to the number of `key`, which a missing key starts from 0.
+    /// Numbers are unsigned, so decrementing stops at 0.
+    fn increment(&self, key: &[u8], increment: Option<i64>) -> Reply {
+        let increment = match increment {
+            Some(increment) => increment,
+            None => return not_an_integer()
+        };
+        for _ in 0..2 {
+            let response = match increment {
+                increment if increment >= 0 => self.db.increment(key, increment as u64),
+                increment => self.db.decrement(key, increment.wrapping_neg() as u64)
+            };
+            match response {
+                Response::Value { value } => return match parse_integer(value[..value.len().saturating_sub(2)].as_ref()) {
+                    Some(n) => Reply::Integer(n),
+                    None => not_an_integer()
+                },
+                Response::NotStored | Response::NotFoundError => {
+                    self.db.insert_if_not_present(key, 0, PERSISTENT_TTL, b"0");
+                }
+                _ => return not_an_integer()
+            }
+        }
+        Reply::Error(String::from("ERR can not increment the value"))
+    }
+
+    /// Appends to the value of `key`, keeping its expiration, or sets it when missing.
+    fn append(&self, key: &[u8], value: &[u8]) -> Reply {
+        let (length, response) = match self.db.get_live_record_for_update(key) {
+            Some(record) => {
+                let ttl = record.deadline.saturating_sub(current_second()).max(1);
+                (record.value.len() + value.len(), self.db.append(key, record.flags, ttl, value))
+            }
+            None => (value.len(), self.db.insert(key, 0, PERSISTENT_TTL, value))
+        };
+        match response {
+            Response::Stored => Reply::Integer(length as i64),
+            _ => Reply::Error(String::from("ERR can not store the value"))
+        }
+    }
+}
+
+/// The memcached statistics of the namespace as an `INFO` section.
+fn info(db: &Database) -> Vec<u8> {
+    let stats = match db.stats() {
+        Response::Value { value } => value,
+        _ => vec![]
+    };
+    let mut bytes_mut = BytesMut::new();
+    bytes_mut.put_slice(b"# Stats\r\n");
+    for line in String::from_utf8_lossy(&stats).lines() {
+        let mut fields = line.splitn(3, ' ');
+        if let (Some("STAT"), Some(name), Some(value)) = (fields.next(), fields.next(), fields.next()) {
+            bytes_mut.put_slice(format!("{}:{}\r\n", name, value).as_bytes());
+        }
+    }
+    bytes_mut.to_vec()
+}
+
+fn parse_integer(bytes: &[u8]) -> Option<i64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn not_an_integer() -> Reply {
+    Reply::Error(String::from("ERR value is not an integer or out of range"))
+}
+
+fn current_second() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDatabase;
+
+    fn serialize(reply: Reply, protocol: u8) -> Vec<u8> {
+        let mut bytes_mut = BytesMut::new();
+        reply.serialize(protocol, &mut bytes_mut);
+        bytes_mut.to_vec()
+    }
+
+    #[test]
+    fn parse_request_array() {
+        let input = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nv\r\nlu\r\n*1\r\n$4\r\nPING\r\n";
+        let (args, length) = parse_request(input).unwrap().unwrap();
+        assert_eq!(args, vec![&b"SET"[..], &b"key"[..], &b"v\r\nlu"[..]]);
+        assert_eq!(parse_request(&input[length..]).unwrap().unwrap(), (vec![&b"PING"[..]], 14));
+    }
+
+    #[test]
+    fn parse_request_incomplete() {
+        let input = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n";
+        for end in 0..input.len() {
+            assert_eq!(parse_request(&input[..end]).unwrap(), None);
+        }
+        assert!(parse_request(b"*1\r\n$3\r\nGETX\r\n").is_err());
+        assert!(parse_request(b"*1\r\n:3\r\n").is_err());
+    }
+
+    #[test]
+    fn parse_request_rejects_huge_lengths() {
+        assert!(parse_request(b"*100000000000\r\n").is_err());
+        assert!(parse_request(b"*4611686018427387903\r\n$3\r\nGET\r\n").is_err());
+        assert!(parse_request(b"*1\r\n$18446744073709551615\r\nGET\r\n").is_err());
+        assert!(parse_request(b"*1\r\n$536870913\r\n").is_err());
+        assert_eq!(parse_request(b"*1048576\r\n$3\r\nGET\r\n").unwrap(), None);
+        assert_eq!(parse_request(b"*1\r\n$536870912\r\nGET\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn parse_request_inline() {
+        assert_eq!(parse_request(b"PING\r\n").unwrap().unwrap(), (vec![&b"PING"[..]], 6));
+        assert_eq!(parse_request(b"GET  key\n").unwrap().unwrap(), (vec![&b"GET"[..], &b"key"[..]], 9));
+        assert_eq!(parse_request(b"GET key").unwrap(), None);
+    }
+
+    #[test]
+    fn commands_are_counted() {
+        let db = TempDatabase::open("resp-commands");
+        let mut session = Session::new(db.clone());
+        assert_eq!(session.handle(&[b"SET", b"a", b"1"]), Reply::Simple(String::from("OK")));
+        assert_eq!(session.handle(&[b"get", b"a"]), Reply::Bulk(b"1".to_vec()));
+        assert_eq!(session.handle(&[b"EXPIRE", b"a", b"0"]), Reply::Integer(1));
+        let stats = String::from_utf8(db.latencies().serialize()).unwrap();
+        assert!(stats.contains("STAT latency:resp_set:count 1\r\n"));
+        assert!(stats.contains("STAT latency:resp_get:count 1\r\n"));
+        assert!(stats.contains("STAT latency:resp_expire:count 1\r\n"));
+        assert!(!stats.contains("resp_del"));
+    }
+
+    #[test]
+    fn set_if_absent() {
+        let db = TempDatabase::open("resp-set-nx");
+        let mut session = Session::new(db.clone());
+        assert_eq!(session.handle(&[b"SET", b"a", b"1", b"NX"]), Reply::Simple(String::from("OK")));
+        assert_eq!(session.handle(&[b"SET", b"a", b"2", b"nx"]), Reply::Null);
+        assert_eq!(session.handle(&[b"GET", b"a"]), Reply::Bulk(b"1".to_vec()));
+    }
+
+    #[test]
+    fn serialize_per_protocol() {
+        let reply = Reply::Array(vec![Reply::Bulk(b"a".to_vec()), Reply::Null, Reply::Integer(-2)]);
+        assert_eq!(serialize(reply.clone(), 2), b"*3\r\n$1\r\na\r\n$-1\r\n:-2\r\n".to_vec());
+        assert_eq!(serialize(reply, 3), b"*3\r\n$1\r\na\r\n_\r\n:-2\r\n".to_vec());
+        let map = Reply::Map(vec![(Reply::Simple(String::from("proto")), Reply::Integer(3))]);
+        assert_eq!(serialize(map.clone(), 2), b"*2\r\n+proto\r\n:3\r\n".to_vec());
+        assert_eq!(serialize(map, 3), b"%1\r\n+proto\r\n:3\r\n".to_vec());
+        assert_eq!(serialize(Reply::Error(String::from("ERR syntax error")), 2), b"-ERR syntax error\r\n".to_vec());
+    }
+}
@@ -1,34 +1,96 @@
 use bytes::Bytes;
 use log::error;
 
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum Response {
     Value {
         value: Vec<u8>,
     },
     Stored,
+    Ok,
+    /// Nothing is sent back, as requested with `noreply`.
+    NoReply,
     NotStored,
     NotFoundError,
     ServerError,
+    /// A write sent to a read only instance, such as a replica.
+    ReadOnly,
     NotImplemented,
+    /// A request the client can not send as is, such as a `use` of an unknown namespace.
+    ClientError {
+        msg: &'static str,
+    },
     Error {
         msg: Box<String>,
     },
 }
 
 impl Response {
+    /// Kind of the response, as traced for every command.
+    pub fn outcome(&self) -> &'static str {
+        match self {
+            Response::Value { .. } => "value",
+            Response::Stored => "stored",
+            Response::Ok => "ok",
+            Response::NoReply => "no_reply",
+            Response::NotStored => "not_stored",
+            Response::NotFoundError => "not_found",
+            Response::ServerError => "server_error",
+            Response::ReadOnly => "read_only",
+            Response::NotImplemented => "not_implemented",
+            Response::ClientError { .. } => "client_error",
+            Response::Error { .. } => "error",
+        }
+    }
+
+    /// Size of the serialized response, computed without serializing the values.
+    pub fn serialized_len(&self) -> usize {
+        match self {
+            Response::Value { value } => value.len(),
+            Response::ClientError { msg } => "CLIENT_ERROR \r\n".len() + msg.len(),
+            Response::Error { .. } => "ERROR\r\n".len(),
+            // The other responses are static
+            response => response.serialize().len()
+        }
+    }
+
     pub fn serialize(&self) -> Bytes {
         match &*self {
             Response::Value { ref value } => Bytes::from(value.clone()),
             Response::Stored => Bytes::from("STORED\r\n"),
+            Response::Ok => Bytes::from("OK\r\n"),
+            Response::NoReply => Bytes::new(),
             Response::NotFoundError => Bytes::from("END\r\n"),
             Response::ServerError => Bytes::from("SERVER_ERROR\r\n"),
+            Response::ReadOnly => Bytes::from("SERVER_ERROR read only\r\n"),
             Response::NotStored => Bytes::from("NOT_STORED\r\n"),
             Response::NotImplemented => Bytes::from("NOT_IMPLEMENTED\r\n"),
+            Response::ClientError { msg } => Bytes::from(format!("CLIENT_ERROR {}\r\n", msg)),
             Response::Error {msg} => {
                 error!("{}", msg);
                 Bytes::from("ERROR\r\n")
             },
         }
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialized_len_nominal() {
+        let responses = vec![
+            Response::Value { value: b"VALUE a 0 1\r\nb\r\nEND\r\n".to_vec() },
+            Response::Stored,
+            Response::NoReply,
+            Response::ReadOnly,
+            Response::ClientError { msg: "unknown namespace" },
+            Response::Error { msg: Box::new(String::from("Invalid command")) },
+        ];
+        for response in responses {
+            assert_eq!(response.serialized_len(), response.serialize().len());
+        }
+    }
+}
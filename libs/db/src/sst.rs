@@ -0,0 +1,328 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rocksdb::{DBCompressionType, Error, Options, SstFileWriter};
+
+use crate::dump::DumpEntry;
+use crate::record::Record;
+use crate::tags::{self, index_key};
+
+/// Offline building of SST files in the record format, so that large precomputed data sets
+/// are ingested by `Database::ingest` instead of going through `set`.
+///
+/// Every column family gets its own files, told apart by their name prefix:
+/// `data-000001.sst`, `access_times-000001.sst` and `tags-000001.sst`.
+/// The entries are sorted with an external merge sort, the last entry of a key winning.
+/// Values are stored neither compressed nor encrypted, so the files can not be ingested
+/// when encryption is enabled.
+pub const DATA_FILE_PREFIX: &str = "data-";
+pub const ACCESS_TIMES_FILE_PREFIX: &str = "access_times-";
+pub const TAGS_FILE_PREFIX: &str = "tags-";
+const FILE_EXTENSION: &str = "sst";
+
+/// Size in bytes of keys and values sorted in memory before being spilled to a run file.
+const RUN_BYTES: usize = 256 * 1024 * 1024;
+/// Size in bytes of keys and values from which a new SST file is started.
+const MAX_FILE_BYTES: usize = 256 * 1024 * 1024;
+const RUNS_DIR: &str = "runs";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SstKind {
+    Data,
+    AccessTimes,
+    Tags,
+}
+
+/// Kind of an SST file written by `SstBuilder`, from its name.
+pub fn sst_kind(path: &Path) -> Option<SstKind> {
+    if path.extension().and_then(|extension| extension.to_str()) != Some(FILE_EXTENSION) {
+        return None;
+    }
+    let name = path.file_name()?.to_str()?;
+    if name.starts_with(DATA_FILE_PREFIX) {
+        Some(SstKind::Data)
+    } else if name.starts_with(ACCESS_TIMES_FILE_PREFIX) {
+        Some(SstKind::AccessTimes)
+    } else if name.starts_with(TAGS_FILE_PREFIX) {
+        Some(SstKind::Tags)
+    } else {
+        None
+    }
+}
+
+/// Options of the SST files, matching those of the column families they are ingested into.
+fn sst_options() -> Options {
+    let mut options = Options::default();
+    options.set_compression_type(DBCompressionType::Lz4);
+    options
+}
+
+pub struct SstBuilder {
+    output_dir: PathBuf,
+    now: u64,
+    records: ExternalSorter,
+    tags: ExternalSorter,
+}
+
+impl SstBuilder {
+    /// Builds into `output_dir`, created if missing, with the time to live of the entries
+    /// counted from `now`.
+    pub fn new(output_dir: &str, now: u64) -> io::Result<SstBuilder> {
+        let output_dir = PathBuf::from(output_dir);
+        let runs_dir = output_dir.join(RUNS_DIR);
+        fs::create_dir_all(&runs_dir)?;
+        Ok(SstBuilder {
+            output_dir,
+            now,
+            records: ExternalSorter::new(runs_dir.join("records")),
+            tags: ExternalSorter::new(runs_dir.join("tags")),
+        })
+    }
+
+    pub fn add(&mut self, entry: DumpEntry) -> io::Result<()> {
+        if let Err(e) = tags::validate(&entry.tags) {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("Key {:?} {}", String::from_utf8_lossy(&entry.key), e)));
+        }
+        for tag in &entry.tags {
+            self.tags.add(index_key(tag, &entry.key), vec![])?;
+        }
+        let mut record = Record::new(self.now + entry.ttl, 0, entry.flags, entry.value);
+        record.created_at = Some(self.now);
+        record.last_access = Some(self.now);
+        record.tags = entry.tags;
+        self.records.add(entry.key, record.encode())
+    }
+
+    /// Writes the SST files and returns their paths.
+    pub fn finish(self) -> io::Result<Vec<PathBuf>> {
+        let options = sst_options();
+        let last_access = u64::to_be_bytes(self.now);
+        let mut files = vec![];
+        let mut records = self.records.sorted()?.peekable();
+        let mut index = 1;
+        while records.peek().is_some() {
+            // The access times follow the keys of the data file they belong to
+            let mut keys = vec![];
+            let data_file = write_sst_file(&options, self.output_dir.join(file_name(DATA_FILE_PREFIX, index)), &mut records, |key, _| {
+                keys.push(key.to_vec());
+            })?;
+            files.push(data_file);
+            let mut access_times = keys.into_iter().map(|key| Ok((key, last_access.to_vec()))).peekable();
+            files.push(write_sst_file(&options, self.output_dir.join(file_name(ACCESS_TIMES_FILE_PREFIX, index)), &mut access_times, |_, _| ())?);
+            index += 1;
+        }
+        let mut tags = self.tags.sorted()?.peekable();
+        let mut index = 1;
+        while tags.peek().is_some() {
+            files.push(write_sst_file(&options, self.output_dir.join(file_name(TAGS_FILE_PREFIX, index)), &mut tags, |_, _| ())?);
+            index += 1;
+        }
+        fs::remove_dir_all(self.output_dir.join(RUNS_DIR))?;
+        Ok(files)
+    }
+}
+
+fn file_name(prefix: &str, index: u32) -> String {
+    format!("{}{:06}.{}", prefix, index, FILE_EXTENSION)
+}
+
+/// Writes sorted entries to `path` until `MAX_FILE_BYTES` is reached, `written` being
+/// called with every entry written.
+fn write_sst_file<I, F>(options: &Options, path: PathBuf, entries: &mut Peekable<I>, mut written: F) -> io::Result<PathBuf>
+    where I: Iterator<Item=io::Result<(Vec<u8>, Vec<u8>)>>, F: FnMut(&[u8], &[u8]) {
+    let mut writer = SstFileWriter::create(options);
+    writer.open(&path).map_err(rocksdb_error)?;
+    let mut size = 0;
+    while size < MAX_FILE_BYTES {
+        let (key, value) = match entries.next() {
+            Some(entry) => entry?,
+            None => break
+        };
+        writer.put(&key, &value).map_err(rocksdb_error)?;
+        written(&key, &value);
+        size += key.len() + value.len();
+    }
+    writer.finish().map_err(rocksdb_error)?;
+    Ok(path)
+}
+
+fn rocksdb_error(e: Error) -> io::Error {
+    io::Error::new(ErrorKind::Other, e.to_string())
+}
+
+/// Sorts key-value pairs larger than the memory by spilling sorted runs to files, then
+/// merging them. The last value added for a key is the one kept.
+struct ExternalSorter {
+    prefix: PathBuf,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    size: usize,
+    runs: Vec<PathBuf>,
+}
+
+impl ExternalSorter {
+    fn new(prefix: PathBuf) -> ExternalSorter {
+        ExternalSorter { prefix, entries: vec![], size: 0, runs: vec![] }
+    }
+
+    fn add(&mut self, key: Vec<u8>, value: Vec<u8>) -> io::Result<()> {
+        self.size += key.len() + value.len();
+        self.entries.push((key, value));
+        if self.size >= RUN_BYTES {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let path = PathBuf::from(format!("{}-{}", self.prefix.display(), self.runs.len()));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for (key, value) in sort_entries(std::mem::take(&mut self.entries)) {
+            write_bytes(&mut writer, &key)?;
+            write_bytes(&mut writer, &value)?;
+        }
+        writer.flush()?;
+        self.runs.push(path);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn sorted(mut self) -> io::Result<MergedRuns> {
+        if !self.entries.is_empty() {
+            self.spill()?;
+        }
+        let mut runs = Vec::with_capacity(self.runs.len());
+        for path in &self.runs {
+            runs.push(BufReader::new(File::open(path)?));
+        }
+        MergedRuns::new(runs)
+    }
+}
+
+/// Sorts by key, keeping the last entry of a key.
+fn sort_entries(mut entries: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries.dedup_by(|later, kept| {
+        if later.0 == kept.0 {
+            std::mem::swap(later, kept);
+            true
+        } else {
+            false
+        }
+    });
+    entries
+}
+
+/// Next entry of a run, as its key, the index of the run and its value.
+type Head = (Vec<u8>, usize, Vec<u8>);
+
+/// Iterates over the entries of sorted runs in key order, the entry of the latest run
+/// winning when a key is in several runs.
+struct MergedRuns {
+    runs: Vec<BufReader<File>>,
+    heads: BinaryHeap<Reverse<Head>>,
+}
+
+impl MergedRuns {
+    fn new(runs: Vec<BufReader<File>>) -> io::Result<MergedRuns> {
+        let mut merged = MergedRuns { runs, heads: BinaryHeap::new() };
+        for run in 0..merged.runs.len() {
+            merged.advance(run)?;
+        }
+        Ok(merged)
+    }
+
+    fn advance(&mut self, run: usize) -> io::Result<()> {
+        if let Some((key, value)) = read_pair(&mut self.runs[run])? {
+            self.heads.push(Reverse((key, run, value)));
+        }
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let Reverse((key, run, mut value)) = match self.heads.pop() {
+            Some(head) => head,
+            None => return Ok(None)
+        };
+        self.advance(run)?;
+        // Runs are popped in increasing order for a given key, the last one is the latest
+        while let Some(Reverse((next_key, _, _))) = self.heads.peek() {
+            if *next_key != key {
+                break;
+            }
+            let Reverse((_, run, next_value)) = self.heads.pop().unwrap();
+            value = next_value;
+            self.advance(run)?;
+        }
+        Ok(Some((key, value)))
+    }
+}
+
+impl Iterator for MergedRuns {
+    type Item = io::Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_u32::<BigEndian>(bytes.len() as u32)?;
+    writer.write_all(bytes)
+}
+
+fn read_pair<R: Read>(reader: &mut R) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let key_length = match reader.read_u32::<BigEndian>() {
+        Ok(length) => length,
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    let mut key = vec![0u8; key_length as usize];
+    reader.read_exact(&mut key)?;
+    let mut value = vec![0u8; reader.read_u32::<BigEndian>()? as usize];
+    reader.read_exact(&mut value)?;
+    Ok(Some((key, value)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &[u8], value: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        (key.to_vec(), value.to_vec())
+    }
+
+    #[test]
+    fn sst_kind_nominal() {
+        assert_eq!(sst_kind(Path::new("/tmp/out/data-000001.sst")), Some(SstKind::Data));
+        assert_eq!(sst_kind(Path::new("access_times-000002.sst")), Some(SstKind::AccessTimes));
+        assert_eq!(sst_kind(Path::new("tags-000001.sst")), Some(SstKind::Tags));
+        assert_eq!(sst_kind(Path::new("data-000001.log")), None);
+        assert_eq!(sst_kind(Path::new("other.sst")), None);
+    }
+
+    #[test]
+    fn sort_entries_keeps_last_value() {
+        let sorted = sort_entries(vec![pair(b"k2", b"1"), pair(b"k1", b"2"), pair(b"k2", b"3"), pair(b"k2", b"4")]);
+        assert_eq!(sorted, vec![pair(b"k1", b"2"), pair(b"k2", b"4")]);
+    }
+
+    #[test]
+    fn external_sorter_merges_runs() {
+        let dir = std::env::temp_dir().join(format!("rockscached-sst-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut sorter = ExternalSorter::new(dir.join("test"));
+        sorter.add(b"k3".to_vec(), b"1".to_vec()).unwrap();
+        sorter.add(b"k1".to_vec(), b"2".to_vec()).unwrap();
+        sorter.spill().unwrap();
+        sorter.add(b"k2".to_vec(), b"3".to_vec()).unwrap();
+        sorter.add(b"k3".to_vec(), b"4".to_vec()).unwrap();
+        let sorted: Vec<_> = sorter.sorted().unwrap().map(Result::unwrap).collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(sorted, vec![pair(b"k1", b"2"), pair(b"k2", b"3"), pair(b"k3", b"4")]);
+    }
+}
@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use bytes::{BufMut, BytesMut};
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Expired records found by a read and queued for deletion.
+    pub expired_unfetched: AtomicU64,
+    /// Expired records actually removed from RocksDB.
+    pub reclaimed: AtomicU64,
+    /// Reads of clients answered by the hot cache, the reads of the writes not counting.
+    pub hot_cache_hits: AtomicU64,
+    /// Reads of clients which had to go to RocksDB while the hot cache is enabled.
+    pub hot_cache_misses: AtomicU64,
+    /// Records removed to keep the SST files under `max_disk_bytes`.
+    pub evictions: AtomicU64,
+    /// Size of the values before and after compression, for the compressed ones only.
+    pub compressed_bytes_in: AtomicU64,
+    pub compressed_bytes_out: AtomicU64,
+    /// Records moved to the current encryption key.
+    pub reencrypted: AtomicU64,
+}
+
+impl Stats {
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// `read_only` being the current mode of the instance, shared by every namespace.
+    pub fn serialize(&self, read_only: bool) -> Vec<u8> {
+        let mut bytes_mut = BytesMut::new();
+        append_stat(&mut bytes_mut, "expired_unfetched", self.expired_unfetched.load(Ordering::Relaxed));
+        append_stat(&mut bytes_mut, "reclaimed", self.reclaimed.load(Ordering::Relaxed));
+        append_stat(&mut bytes_mut, "hot_cache_hits", self.hot_cache_hits.load(Ordering::Relaxed));
+        append_stat(&mut bytes_mut, "hot_cache_misses", self.hot_cache_misses.load(Ordering::Relaxed));
+        append_stat(&mut bytes_mut, "evictions", self.evictions.load(Ordering::Relaxed));
+        let compressed_bytes_in = self.compressed_bytes_in.load(Ordering::Relaxed);
+        let compressed_bytes_out = self.compressed_bytes_out.load(Ordering::Relaxed);
+        append_stat(&mut bytes_mut, "compressed_bytes_in", compressed_bytes_in);
+        append_stat(&mut bytes_mut, "compressed_bytes_out", compressed_bytes_out);
+        let compression_ratio = match compressed_bytes_out {
+            0 => 1.0,
+            out => compressed_bytes_in as f64 / out as f64
+        };
+        append_stat(&mut bytes_mut, "compression_ratio", format!("{:.2}", compression_ratio));
+        append_stat(&mut bytes_mut, "reencrypted", self.reencrypted.load(Ordering::Relaxed));
+        append_stat(&mut bytes_mut, "read_only", read_only as u8);
+        bytes_mut.put_slice(b"END\r\n");
+        bytes_mut.to_vec()
+    }
+}
+
+fn append_stat<T: ToString>(bytes_mut: &mut BytesMut, name: &str, value: T) {
+    bytes_mut.put_slice(b"STAT ");
+    bytes_mut.put_slice(name.as_bytes());
+    bytes_mut.put_slice(b" ");
+    bytes_mut.put_slice(&value.to_string().into_bytes());
+    bytes_mut.put_slice(b"\r\n");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_nominal() {
+        let stats = Stats::default();
+        Stats::add(&stats.expired_unfetched, 3);
+        Stats::add(&stats.reclaimed, 2);
+        assert_eq!(stats.serialize(true), b"STAT expired_unfetched 3\r\nSTAT reclaimed 2\r\nSTAT hot_cache_hits 0\r\nSTAT hot_cache_misses 0\r\nSTAT evictions 0\r\nSTAT compressed_bytes_in 0\r\nSTAT compressed_bytes_out 0\r\nSTAT compression_ratio 1.00\r\nSTAT reencrypted 0\r\nSTAT read_only 1\r\nEND\r\n".to_vec());
+    }
+}
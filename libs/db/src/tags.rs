@@ -0,0 +1,59 @@
+/// Column family indexing the keys by tag, with an empty value under `<tag>\0<key>`.
+/// Memcached tags and keys can not contain a null byte, which keeps a tag from being the
+/// prefix of another one in the index.
+pub const TAGS_CF: &str = "tags";
+
+const SEPARATOR: u8 = 0;
+
+/// Most tags a key can carry, and longest tag, as memcached keys.
+pub const MAX_TAGS: usize = 32;
+pub const MAX_TAG_LENGTH: usize = 250;
+
+/// Checks the tags fit in a record, whose encoding prefixes them with 16 bits lengths.
+pub fn validate<T: AsRef<[u8]>>(tags: &[T]) -> Result<(), &'static str> {
+    if tags.len() > MAX_TAGS {
+        return Err("too many tags");
+    }
+    match tags.iter().any(|tag| tag.as_ref().len() > MAX_TAG_LENGTH) {
+        true => Err("tag too long"),
+        false => Ok(())
+    }
+}
+
+pub fn index_key(tag: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut index_key = index_prefix(tag);
+    index_key.extend_from_slice(key);
+    index_key
+}
+
+/// Prefix shared by the index entries of every key carrying `tag`.
+pub fn index_prefix(tag: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(tag.len() + 1);
+    prefix.extend_from_slice(tag);
+    prefix.push(SEPARATOR);
+    prefix
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_key_nominal() {
+        assert_eq!(index_key(b"product:42", b"page:1"), b"product:42\0page:1".to_vec());
+    }
+
+    #[test]
+    fn validate_bounds_the_tags() {
+        assert_eq!(validate(&[&b"a"[..], b"b"]), Ok(()));
+        assert_eq!(validate(&vec![b"a"; MAX_TAGS + 1]), Err("too many tags"));
+        assert_eq!(validate(&[vec![b'a'; MAX_TAG_LENGTH + 1]]), Err("tag too long"));
+    }
+
+    #[test]
+    fn index_prefix_does_not_match_longer_tags() {
+        assert!(!index_key(b"product:421", b"page:1").starts_with(&index_prefix(b"product:42")));
+        assert!(index_key(b"product:42", b"page:1").starts_with(&index_prefix(b"product:42")));
+    }
+}
@@ -0,0 +1,71 @@
+//! Temporary directories and databases for the tests of the crates, removed once dropped.
+
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::sync::Arc;
+use crate::db::{Database, DatabaseOptions};
+
+/// A path of the system temporary directory, unique to the name within the process.
+/// The directory is not created, for the paths a database or a checkpoint creates itself.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Starts from nothing, what an earlier process with the same id left being removed.
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("rockscached-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    /// Path of `name` within the directory.
+    pub fn join(&self, name: &str) -> String {
+        self.path.join(name).to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A database opened in a temporary directory, dereferencing to the database handle.
+pub struct TempDatabase {
+    // Declared first to be closed before its directory is removed
+    db: Arc<Database>,
+    _dir: TempDir,
+}
+
+impl TempDatabase {
+    pub fn open(name: &str) -> TempDatabase {
+        TempDatabase::open_with_options(name, &DatabaseOptions::default())
+    }
+
+    pub fn open_with_options(name: &str, options: &DatabaseOptions) -> TempDatabase {
+        let dir = TempDir::new(name);
+        let db = Database::open_with_options(dir.path(), options);
+        TempDatabase { db, _dir: dir }
+    }
+}
+
+impl Deref for TempDatabase {
+    type Target = Arc<Database>;
+
+    fn deref(&self) -> &Arc<Database> {
+        &self.db
+    }
+}
+
+// For the commands switching the namespace of their connection
+impl DerefMut for TempDatabase {
+    fn deref_mut(&mut self) -> &mut Arc<Database> {
+        &mut self.db
+    }
+}
@@ -0,0 +1,46 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use tracing::{error, info};
+
+use rockscached_db::db::Database;
+
+/// The database once opened, the admin listener starting before so that `/healthz`
+/// answers during a restore or the initial copy of a replica.
+pub type OpenedDatabase = Arc<RwLock<Option<Arc<Database>>>>;
+
+/// Serves `/metrics` in the Prometheus text format, `/healthz` while the process runs
+/// and `/readyz` once the database is open and writable.
+pub async fn serve(addr: SocketAddr, db: OpenedDatabase) {
+    let make_service = make_service_fn(move |_| {
+        let db = db.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle(request, db.clone()))) }
+    });
+    info!("Serving metrics and health checks on: {}", addr);
+    if let Err(e) = Server::bind(&addr).serve(make_service).await {
+        error!("Can not serve the admin listener on {}; error = {:?}", addr, e);
+    }
+}
+
+async fn handle(request: Request<Body>, db: OpenedDatabase) -> Result<Response<Body>, Infallible> {
+    let db = db.read().unwrap().clone();
+    let response = match (request.uri().path(), db) {
+        ("/healthz", _) => text(StatusCode::OK, String::from("ok")),
+        ("/readyz", None) => text(StatusCode::SERVICE_UNAVAILABLE, String::from("opening")),
+        ("/readyz", Some(db)) if db.is_read_only() => text(StatusCode::SERVICE_UNAVAILABLE, String::from("read only")),
+        ("/readyz", Some(_)) => text(StatusCode::OK, String::from("ready")),
+        ("/metrics", None) => text(StatusCode::SERVICE_UNAVAILABLE, String::from("opening")),
+        ("/metrics", Some(db)) => Response::builder()
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(Body::from(db.render_metrics()))
+            .unwrap(),
+        _ => text(StatusCode::NOT_FOUND, String::from("Unknown path"))
+    };
+    Ok(response)
+}
+
+fn text(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder().status(status).body(Body::from(body)).unwrap()
+}
@@ -0,0 +1,204 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tracing::{debug, error, warn};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{self, Instant};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::future::BoxFuture;
+
+/// Number of bytes read from a socket at once.
+const READ_SIZE: usize = 16 * 1024;
+
+/// Limits shared by the connections of every client listener.
+#[derive(Debug)]
+pub struct ConnectionLimits {
+    /// Open connections from which new ones are rejected, 0 for no limit.
+    max_connections: usize,
+    /// Time without any request read or response written after which a connection is closed.
+    idle_timeout: Option<Duration>,
+    /// Size of an incomplete request from which the connection is closed.
+    max_read_buffer: usize,
+    /// Size of the responses not sent yet from which requests are no longer read nor answered.
+    max_write_buffer: usize,
+    open: AtomicUsize,
+}
+
+/// A connection counted as open until dropped.
+#[derive(Debug)]
+pub struct OpenConnection(Arc<ConnectionLimits>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConnectionLimits {
+    /// An `idle_timeout` of 0 seconds keeps the idle connections open.
+    pub fn new(max_connections: usize, idle_timeout: u64, max_read_buffer: usize, max_write_buffer: usize) -> Arc<ConnectionLimits> {
+        Arc::new(ConnectionLimits {
+            max_connections,
+            idle_timeout: match idle_timeout {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds))
+            },
+            max_read_buffer,
+            max_write_buffer,
+            open: AtomicUsize::new(0),
+        })
+    }
+
+    /// Counts a new connection, unless `max_connections` are already open.
+    pub fn open(self: &Arc<Self>) -> Option<OpenConnection> {
+        let open = self.open.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.max_connections > 0 && open > self.max_connections {
+            self.open.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(OpenConnection(self.clone()))
+    }
+}
+
+/// Framing of the requests of a listener.
+pub trait Protocol {
+    /// Answers the complete requests at the start of `requests` and removes them, stopping
+    /// once `responses` holds `max_responses` bytes, telling whether the connection stays open.
+    /// The answer is a future for the protocols forwarding the requests.
+    fn handle<'a>(&'a mut self, requests: &'a mut BytesMut, responses: &'a mut BytesMut, max_responses: usize) -> BoxFuture<'a, bool>;
+
+    /// Response sent before closing a connection whose incomplete request exceeds the read buffer.
+    fn request_too_large(&self) -> &'static [u8];
+}
+
+/// Sends `rejection` to a client above the connection limit, then closes its connection.
+pub async fn reject<S: AsyncWrite + Unpin>(mut socket: S, client: String, rejection: &'static [u8]) {
+    warn!(client = %client, "Too many open connections, rejecting the client");
+    if let Err(e) = socket.write_all(rejection).await {
+        debug!("error on sending the rejection; error = {:?}", e);
+    }
+}
+
+/// Reads the requests of the client and writes the responses until it disconnects or
+/// idles. Once the pending responses reach the write buffer, neither are requests read nor
+/// the buffered ones answered until they are drained.
+pub async fn serve<S: AsyncRead + AsyncWrite, P: Protocol>(socket: S, limits: &ConnectionLimits, mut protocol: P) {
+    let (mut reader, mut writer) = io::split(socket);
+    let mut buf = [0u8; READ_SIZE];
+    let mut requests = BytesMut::new();
+    let mut responses = BytesMut::new();
+    let mut closing = false;
+    // Whether buffered requests were left unanswered at the write buffer
+    let mut paused = false;
+    let mut idle = time::delay_for(limits.idle_timeout.unwrap_or_default());
+    while !closing || !responses.is_empty() {
+        let reading = !closing && !paused;
+        tokio::select! {
+            read = reader.read(&mut buf), if reading => match read {
+                Ok(0) => closing = true,
+                Ok(n) => {
+                    requests.put_slice(&buf[0..n]);
+                    closing = !answer(&mut protocol, &mut requests, &mut responses, &mut paused, limits).await;
+                }
+                Err(e) => {
+                    debug!("error on reading requests; error = {:?}", e);
+                    return;
+                }
+            },
+            written = writer.write(responses.bytes()), if !responses.is_empty() => match written {
+                Ok(n) if n > 0 => {
+                    responses.advance(n);
+                    if paused && !closing && responses.len() < limits.max_write_buffer {
+                        closing = !answer(&mut protocol, &mut requests, &mut responses, &mut paused, limits).await;
+                    }
+                }
+                Ok(_) => return,
+                Err(e) => {
+                    error!("error on sending response; error = {:?}", e);
+                    return;
+                }
+            },
+            _ = &mut idle, if limits.idle_timeout.is_some() => {
+                debug!("Closing the idle connection");
+                return;
+            }
+        }
+        if let Some(idle_timeout) = limits.idle_timeout {
+            idle.reset(Instant::now() + idle_timeout);
+        }
+    }
+}
+
+/// Answers the buffered requests up to the write buffer, telling whether the connection
+/// stays open.
+async fn answer<P: Protocol>(protocol: &mut P, requests: &mut BytesMut, responses: &mut BytesMut, paused: &mut bool, limits: &ConnectionLimits) -> bool {
+    if !protocol.handle(requests, responses, limits.max_write_buffer).await {
+        return false;
+    }
+    *paused = responses.len() >= limits.max_write_buffer;
+    if !*paused && requests.len() > limits.max_read_buffer {
+        warn!(size = requests.len(), "Request too large, closing the connection");
+        responses.put_slice(protocol.request_too_large());
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use super::*;
+
+    #[test]
+    fn open_up_to_max_connections() {
+        let limits = ConnectionLimits::new(2, 0, 1024, 1024);
+        let first = limits.open().unwrap();
+        let _second = limits.open().unwrap();
+        assert!(limits.open().is_none());
+        drop(first);
+        assert!(limits.open().is_some());
+        let unlimited = ConnectionLimits::new(0, 0, 1024, 1024);
+        let connections: Vec<OpenConnection> = (0..100).filter_map(|_| unlimited.open()).collect();
+        assert_eq!(connections.len(), 100);
+    }
+
+    /// Answers each byte with ten.
+    struct Echo;
+
+    impl Protocol for Echo {
+        fn handle<'a>(&'a mut self, requests: &'a mut BytesMut, responses: &'a mut BytesMut, max_responses: usize) -> BoxFuture<'a, bool> {
+            Box::pin(async move {
+                while !requests.is_empty() && responses.len() < max_responses {
+                    responses.put_slice(&[requests[0]; 10]);
+                    requests.advance(1);
+                }
+                true
+            })
+        }
+
+        fn request_too_large(&self) -> &'static [u8] {
+            b"too large"
+        }
+    }
+
+    #[test]
+    fn requests_are_answered_up_to_the_write_buffer() {
+        let limits = ConnectionLimits::new(0, 0, 4, 25);
+        let mut requests = BytesMut::from(&b"abcdefgh"[..]);
+        let mut responses = BytesMut::new();
+        let mut paused = false;
+        assert!(block_on(answer(&mut Echo, &mut requests, &mut responses, &mut paused, &limits)));
+        assert!(paused);
+        assert_eq!(responses.len(), 30);
+        assert_eq!(&requests[..], b"defgh");
+        responses.advance(30);
+        assert!(block_on(answer(&mut Echo, &mut requests, &mut responses, &mut paused, &limits)));
+        assert!(paused);
+        assert_eq!(&requests[..], b"gh");
+        responses.advance(30);
+        assert!(block_on(answer(&mut Echo, &mut requests, &mut responses, &mut paused, &limits)));
+        assert!(!paused);
+        assert!(requests.is_empty());
+        assert_eq!(&responses[..], b"gggggggggghhhhhhhhhh");
+    }
+}
@@ -0,0 +1,326 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{CONTENT_LENGTH, ETAG, IF_MATCH, IF_NONE_MATCH};
+use hyper::service::{make_service_fn, service_fn};
+use tracing::{error, info};
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+
+use rockscached_db::db::Database;
+use rockscached_db::resp::PERSISTENT_TTL;
+use rockscached_db::response::Response as CacheResponse;
+
+const KEYS_PATH: &str = "/keys/";
+const MGET_PATH: &str = "/keys/_mget";
+const TTL_HEADER: &str = "x-ttl";
+const FLAGS_HEADER: &str = "x-flags";
+/// Largest request body read, like the default read buffer of the other listeners.
+const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Serves the keys over HTTP for clients which can not keep memcached connections:
+/// `GET`, `PUT` and `DELETE` on `/keys/{key}`, and `POST /keys/_mget` with a JSON array of
+/// keys. The time to live and flags go in the `X-TTL` and `X-Flags` headers, a key without
+/// `X-TTL` never expiring, and the `ETag` of a value is its CAS value, which `If-Match`
+/// compares before writing.
+pub async fn serve(addr: SocketAddr, db: Arc<Database>) {
+    let make_service = make_service_fn(move |_| {
+        let connection = HttpConnection::open(db.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let db = connection.0.clone();
+                async move {
+                    let start = Instant::now();
+                    let name = command_name(&request);
+                    let response = handle(request, db.clone()).await;
+                    if let Some(name) = name {
+                        db.record_command(name, start.elapsed());
+                    }
+                    response
+                }
+            }))
+        }
+    });
+    info!("Listening to HTTP clients on: {}", addr);
+    if let Err(e) = Server::bind(&addr).serve(make_service).await {
+        error!("Can not serve HTTP clients on {}; error = {:?}", addr, e);
+    }
+}
+
+/// A connection counted as open in the metrics until its service is dropped.
+struct HttpConnection(Arc<Database>);
+
+impl HttpConnection {
+    fn open(db: Arc<Database>) -> HttpConnection {
+        db.metrics().connection_opened("http");
+        HttpConnection(db)
+    }
+}
+
+impl Drop for HttpConnection {
+    fn drop(&mut self) {
+        self.0.metrics().connection_closed("http");
+    }
+}
+
+/// Name of the request in the metrics and latency histograms, if it is a known one.
+fn command_name(request: &Request<Body>) -> Option<&'static str> {
+    if !request.uri().path().starts_with(KEYS_PATH) {
+        return None;
+    }
+    match *request.method() {
+        Method::POST if request.uri().path() == MGET_PATH => Some("http_mget"),
+        Method::GET => Some("http_get"),
+        Method::PUT => Some("http_put"),
+        Method::DELETE => Some("http_delete"),
+        _ => None
+    }
+}
+
+async fn handle(request: Request<Body>, db: Arc<Database>) -> Result<Response<Body>, Infallible> {
+    let path = request.uri().path().to_string();
+    if !path.starts_with(KEYS_PATH) || path.len() == KEYS_PATH.len() {
+        return Ok(status(StatusCode::NOT_FOUND, "Unknown path"));
+    }
+    let key: Vec<u8> = percent_decode_str(&path[KEYS_PATH.len()..]).collect();
+    let is_write = request.method() == Method::PUT || request.method() == Method::DELETE;
+    if is_write && db.is_read_only() {
+        return Ok(status(StatusCode::SERVICE_UNAVAILABLE, "Read only"));
+    }
+    let response = match *request.method() {
+        Method::POST if path == MGET_PATH => mget(request, &db).await,
+        Method::GET => get(&key, &db),
+        Method::PUT => put(&key, request, &db).await,
+        Method::DELETE => match db.get_live_record_for_update(&key) {
+            Some(_) => match db.delete(&key) {
+                CacheResponse::Stored => status(StatusCode::NO_CONTENT, ""),
+                _ => status(StatusCode::INTERNAL_SERVER_ERROR, "Can not delete the key")
+            },
+            None => status(StatusCode::NOT_FOUND, "Not found")
+        },
+        _ => status(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+    };
+    Ok(response)
+}
+
+fn get(key: &[u8], db: &Database) -> Response<Body> {
+    match db.get_live_record(key) {
+        Some(record) => {
+            let mut builder = Response::builder()
+                .header(ETAG, etag(record.cas))
+                .header(FLAGS_HEADER, record.flags);
+            if let Some(ttl) = ttl(record.deadline, current_second()) {
+                builder = builder.header(TTL_HEADER, ttl);
+            }
+            builder.body(Body::from(record.value)).unwrap()
+        }
+        None => status(StatusCode::NOT_FOUND, "Not found")
+    }
+}
+
+/// Sets the body as the value of the key, only if its current `ETag` matches `If-Match`,
+/// or if it is missing with `If-None-Match: *`.
+async fn put(key: &[u8], request: Request<Body>, db: &Database) -> Response<Body> {
+    let (ttl, flags) = match (header_number(&request, TTL_HEADER), header_number(&request, FLAGS_HEADER)) {
+        (Ok(ttl), Ok(flags)) => (ttl.unwrap_or(PERSISTENT_TTL), flags.unwrap_or_default()),
+        _ => return status(StatusCode::BAD_REQUEST, "Invalid X-TTL or X-Flags header")
+    };
+    let if_match = request.headers().get(IF_MATCH).map(|value| value.to_str().unwrap_or_default().to_string());
+    let if_none_match = request.headers().get(IF_NONE_MATCH).map(|value| value.to_str().unwrap_or_default().to_string());
+    let value = match read_body(request).await {
+        Ok(value) => value,
+        Err(response) => return response
+    };
+    let response = match (if_match.as_deref(), if_none_match.as_deref()) {
+        (Some("*"), _) => db.replace(key, flags, ttl, &value),
+        (Some(tag), _) => match parse_etag(tag) {
+            Some(cas) => db.check_and_set(key, flags, ttl, &value, cas),
+            None => return status(StatusCode::PRECONDITION_FAILED, "Precondition failed")
+        },
+        (None, Some("*")) => db.insert_if_not_present(key, flags, ttl, &value),
+        _ => db.insert(key, flags, ttl, &value)
+    };
+    match response {
+        CacheResponse::Stored => {
+            let mut builder = Response::builder().status(StatusCode::NO_CONTENT);
+            if let Some(record) = db.get_live_record_for_update(key) {
+                builder = builder.header(ETAG, etag(record.cas));
+            }
+            builder.body(Body::empty()).unwrap()
+        }
+        CacheResponse::NotStored | CacheResponse::NotFoundError => status(StatusCode::PRECONDITION_FAILED, "Precondition failed"),
+        _ => status(StatusCode::INTERNAL_SERVER_ERROR, "Can not store the value")
+    }
+}
+
+/// Answers a JSON array with, in the order of the requested keys, either `null` or the
+/// value encoded in base64 with its flags, time to live and `ETag`.
+async fn mget(request: Request<Body>, db: &Database) -> Response<Body> {
+    let keys: Vec<String> = match read_body(request).await {
+        Ok(body) => match serde_json::from_slice(&body) {
+            Ok(keys) => keys,
+            Err(_) => return status(StatusCode::BAD_REQUEST, "Expecting a JSON array of keys")
+        },
+        Err(response) => return response
+    };
+    let now = current_second();
+    let values: Vec<serde_json::Value> = keys.iter().map(|key| match db.get_live_record(key.as_bytes()) {
+        Some(record) => json!({
+            "key": key,
+            "value": base64::encode(&record.value),
+            "flags": record.flags,
+            "ttl": ttl(record.deadline, now),
+            "etag": etag(record.cas),
+        }),
+        None => serde_json::Value::Null
+    }).collect();
+    Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::Value::Array(values).to_string()))
+        .unwrap()
+}
+
+/// Reads the body of the request, up to `MAX_BODY_SIZE` bytes.
+async fn read_body(request: Request<Body>) -> Result<Bytes, Response<Body>> {
+    let too_large = || status(StatusCode::PAYLOAD_TOO_LARGE, "Body too large");
+    match header_number::<usize>(&request, CONTENT_LENGTH.as_str()) {
+        Ok(Some(length)) if length > MAX_BODY_SIZE => return Err(too_large()),
+        _ => ()
+    }
+    let mut body = request.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| status(StatusCode::BAD_REQUEST, "Can not read the body"))?;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(bytes))
+}
+
+/// Time to live of a record expiring at `deadline`, unless it never expires.
+fn ttl(deadline: u64, now: u64) -> Option<u64> {
+    match deadline.saturating_sub(now) {
+        remaining if remaining > PERSISTENT_TTL / 2 => None,
+        remaining => Some(remaining)
+    }
+}
+
+fn status(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder().status(status).body(Body::from(message.to_string())).unwrap()
+}
+
+/// Value of a numeric header, if present.
+fn header_number<T: FromStr>(request: &Request<Body>, name: &str) -> Result<Option<T>, ()> {
+    match request.headers().get(name) {
+        Some(value) => value.to_str().map_err(|_| ())?.trim().parse().map(Some).map_err(|_| ()),
+        None => Ok(None)
+    }
+}
+
+fn etag(cas: u64) -> String {
+    format!("\"{:x}\"", cas)
+}
+
+fn parse_etag(tag: &str) -> Option<u64> {
+    let tag = tag.trim();
+    let tag = tag.strip_prefix("W/").unwrap_or(tag);
+    if tag.len() < 2 || !tag.starts_with('"') || !tag.ends_with('"') {
+        return None;
+    }
+    u64::from_str_radix(&tag[1..tag.len() - 1], 16).ok()
+}
+
+fn current_second() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rockscached_db::test_utils::TempDatabase;
+
+    #[test]
+    fn etag_round_trip() {
+        assert_eq!(etag(255), "\"ff\"");
+        assert_eq!(parse_etag(&etag(1234567)), Some(1234567));
+        assert_eq!(parse_etag(" W/\"1a\" "), Some(26));
+        assert_eq!(parse_etag("1a"), None);
+        assert_eq!(parse_etag("\""), None);
+        assert_eq!(parse_etag("\"zz\""), None);
+    }
+
+    #[test]
+    fn header_number_nominal() {
+        let request = Request::builder().header(TTL_HEADER, " 60").header(FLAGS_HEADER, "x").body(Body::empty()).unwrap();
+        assert_eq!(header_number::<u64>(&request, TTL_HEADER), Ok(Some(60)));
+        assert_eq!(header_number::<u32>(&request, FLAGS_HEADER), Err(()));
+        assert_eq!(header_number::<u32>(&request, "x-missing"), Ok(None));
+    }
+
+    fn request(method: Method, key: &str, headers: &[(&str, &str)], body: &'static [u8]) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(format!("{}{}", KEYS_PATH, key));
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::from(body)).unwrap()
+    }
+
+    async fn send(db: &Arc<Database>, request: Request<Body>) -> Response<Body> {
+        handle(request, db.clone()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn put_get_and_conditional_put() {
+        let db = TempDatabase::open("http");
+
+        let response = send(&db, request(Method::PUT, "a", &[(FLAGS_HEADER, "3")], b"1")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let stored = response.headers()[ETAG].to_str().unwrap().to_string();
+        let response = send(&db, request(Method::GET, "a", &[], b"")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ETAG].to_str().unwrap(), stored);
+        assert_eq!(response.headers()[FLAGS_HEADER], "3");
+        assert!(response.headers().get(TTL_HEADER).is_none());
+        assert_eq!(&hyper::body::to_bytes(response.into_body()).await.unwrap()[..], b"1");
+
+        let response = send(&db, request(Method::PUT, "a", &[(IF_MATCH.as_str(), "\"0\"")], b"2")).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        let response = send(&db, request(Method::PUT, "a", &[(IF_MATCH.as_str(), &stored), (TTL_HEADER, "60")], b"2")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_ne!(response.headers()[ETAG].to_str().unwrap(), stored);
+        let response = send(&db, request(Method::PUT, "a", &[(IF_NONE_MATCH.as_str(), "*")], b"3")).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        let response = send(&db, request(Method::GET, "a", &[], b"")).await;
+        let ttl: u64 = response.headers()[TTL_HEADER].to_str().unwrap().parse().unwrap();
+        assert!(ttl > 0 && ttl <= 60);
+        assert_eq!(&hyper::body::to_bytes(response.into_body()).await.unwrap()[..], b"2");
+        assert_eq!(send(&db, request(Method::GET, "b", &[], b"")).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn large_bodies_are_rejected() {
+        let db = TempDatabase::open("http-large");
+        let body = Body::from(vec![b'x'; MAX_BODY_SIZE + 1]);
+        let response = send(&db, Request::builder().method(Method::PUT).uri("/keys/a").body(body).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..3 {
+                if sender.send_data(Bytes::from(vec![b'x'; MAX_BODY_SIZE / 2])).await.is_err() {
+                    return;
+                }
+            }
+        });
+        let response = send(&db, Request::builder().method(Method::PUT).uri("/keys/a").body(body).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(send(&db, request(Method::GET, "a", &[], b"")).await.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,187 @@
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::fs;
+use tracing::{Event, Level, Subscriber};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+/// Configuration of log4rs, whose root level also applies to the JSON output.
+const LOG4RS_CONFIG: &str = "log4rs.yml";
+
+/// Sends the logs to log4rs as configured in `log4rs.yml`, tracing events included, or with
+/// the `json` format writes them as JSON lines. In both formats, the spans of connections and
+/// commands are logged once closed with their fields, and the fields of the spans of an event
+/// follow it. The connection spans are at the `info` level and the command ones at `debug`,
+/// so that every command is only logged with a root level of `debug`.
+pub fn init(format: &str) -> Result<(), Box<dyn Error>> {
+    match format {
+        "json" => {
+            let level = root_level(&fs::read_to_string(LOG4RS_CONFIG).unwrap_or_default());
+            // The records of the `log` macros, used by the storage, become tracing events
+            tracing_log::LogTracer::init()?;
+            let subscriber = tracing_subscriber::fmt()
+                .json()
+                .with_max_level(level)
+                .with_span_events(FmtSpan::CLOSE)
+                .finish();
+            tracing::subscriber::set_global_default(subscriber)?;
+        }
+        _ => {
+            log4rs::init_file(LOG4RS_CONFIG, Default::default()).unwrap();
+            tracing::subscriber::set_global_default(Registry::default().with(LogLayer))?;
+        }
+    }
+    Ok(())
+}
+
+/// Forwards the tracing events to the `log` logger followed by the fields of their spans,
+/// and logs every span with its fields once closed.
+struct LogLayer;
+
+/// Fields of a span as ` name=value` pairs, kept while it is open.
+struct SpanFields(String);
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for LogLayer {
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !log_enabled(attrs.metadata()) {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            span.extensions_mut().insert(SpanFields(fields.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(SpanFields(fields)) = span.extensions_mut().get_mut::<SpanFields>() {
+                let mut recorded = Fields::default();
+                values.record(&mut recorded);
+                fields.push_str(&recorded.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if !log_enabled(metadata) {
+            return;
+        }
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let mut line = fields.message + &fields.fields;
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(SpanFields(span_fields)) = span.extensions().get::<SpanFields>() {
+                    write!(line, " {}{{{}}}", span.name(), span_fields.trim_start()).unwrap();
+                }
+            }
+        }
+        log(metadata, format_args!("{}", line));
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                log(span.metadata(), format_args!("{} closed;{}", span.name(), fields));
+            }
+        }
+    }
+}
+
+/// The message of an event, and its other fields or those of a span.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: String,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message.push_str(value),
+            name => write!(self.fields, " {}={}", name, value).unwrap()
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => write!(self.message, "{:?}", value).unwrap(),
+            name => write!(self.fields, " {}={:?}", name, value).unwrap()
+        }
+    }
+}
+
+fn log_level(metadata: &tracing::Metadata<'_>) -> log::Level {
+    match *metadata.level() {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        Level::TRACE => log::Level::Trace,
+    }
+}
+
+fn log_enabled(metadata: &tracing::Metadata<'_>) -> bool {
+    let log_metadata = log::Metadata::builder().level(log_level(metadata)).target(metadata.target()).build();
+    log::logger().enabled(&log_metadata)
+}
+
+fn log(metadata: &tracing::Metadata<'_>, message: fmt::Arguments<'_>) {
+    log::logger().log(&log::Record::builder()
+        .args(message)
+        .level(log_level(metadata))
+        .target(metadata.target())
+        .module_path(metadata.module_path())
+        .file(metadata.file())
+        .line(metadata.line())
+        .build());
+}
+
+/// Level of the root logger of a log4rs configuration, `info` when missing.
+fn root_level(config: &str) -> Level {
+    serde_yaml::from_str::<serde_yaml::Value>(config).ok()
+        .and_then(|config| config["root"]["level"].as_str().and_then(|level| level.parse().ok()))
+        .unwrap_or(Level::INFO)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use super::*;
+
+    /// Keeps the message and fields of every event.
+    struct FieldsLayer(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl<S: Subscriber> Layer<S> for FieldsLayer {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            self.0.lock().unwrap().push((fields.message, fields.fields));
+        }
+    }
+
+    #[test]
+    fn fields_are_formatted() {
+        let events = Arc::new(Mutex::new(vec![]));
+        tracing::subscriber::with_default(Registry::default().with(FieldsLayer(events.clone())), || {
+            tracing::info!(client = "127.0.0.1:1234", keys = 2, "Command {}", "handled");
+        });
+        let expected = (String::from("Command handled"), String::from(" client=127.0.0.1:1234 keys=2"));
+        assert_eq!(*events.lock().unwrap(), vec![expected]);
+    }
+
+    #[test]
+    fn root_level_nominal() {
+        assert_eq!(root_level("root:\n  level: debug\n  appenders:\n    - stdout\n"), Level::DEBUG);
+        assert_eq!(root_level("root:\n  level: WARN\n"), Level::WARN);
+        assert_eq!(root_level("appenders: {}\n"), Level::INFO);
+        assert_eq!(root_level(""), Level::INFO);
+    }
+}
@@ -1,15 +1,34 @@
 #![warn(rust_2018_idioms)]
 
+mod admin;
+mod connection;
+mod http;
+mod logging;
+mod memcached;
+mod proxy;
+mod resp;
+mod udp;
 
 use std::error::Error;
-use log::{info, error};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
 use tokio::net::TcpListener;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use clap::{Arg, App};
-use bytes::{BytesMut, BufMut, Buf};
+use tokio::task;
+use tokio::time;
+use clap::{Arg, App, ArgMatches, SubCommand};
+use futures::future;
 
-use rockscached_db::db::Database;
-use rockscached_db::command::Command;
+use rockscached_db::db::{Database, DatabaseOptions, NamespaceOptions};
+use rockscached_db::dump::{read_entry, read_header};
+use rockscached_db::replication;
+use rockscached_db::sst::SstBuilder;
+
+use crate::connection::ConnectionLimits;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -24,6 +43,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .help("The socket address to listen to")
             .default_value("127.0.0.1:8080")
             .takes_value(true))
+        .arg(Arg::with_name("unix_socket")
+            .long("unix_socket")
+            .value_name("path")
+            .help("The UNIX socket to listen to, instead of the socket address unless --address is given too")
+            .takes_value(true))
+        .arg(Arg::with_name("unix_socket_mode")
+            .long("unix_socket_mode")
+            .value_name("octal mode")
+            .help("The permissions of the UNIX socket")
+            .default_value("700")
+            .takes_value(true))
         .arg(Arg::with_name("db_dir")
             .short("d")
             .long("db_dir")
@@ -31,47 +61,459 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .help("The directory where the data will be stored")
             .default_value("/tmp/rocksdb")
             .takes_value(true))
+        .arg(Arg::with_name("hot_cache_size")
+            .long("hot_cache_size")
+            .value_name("bytes")
+            .help("The size of the in-memory cache in front of RocksDB, 0 to disable it")
+            .default_value("0")
+            .takes_value(true))
+        .arg(Arg::with_name("max_disk_bytes")
+            .long("max_disk_bytes")
+            .value_name("bytes")
+            .help("The size of the SST files above which least recently used keys are evicted, 0 for no limit")
+            .default_value("0")
+            .takes_value(true))
+        .arg(Arg::with_name("compression_threshold")
+            .long("compression_threshold")
+            .value_name("bytes")
+            .help("The size from which values are compressed with zstd, 0 to disable compression")
+            .default_value("0")
+            .takes_value(true))
+        .arg(Arg::with_name("encryption_key_file")
+            .long("encryption_key_file")
+            .value_name("file")
+            .help("The YAML file holding the keys used to encrypt the stored values, encryption is disabled without it. Values are then compressed without a trained dictionary")
+            .takes_value(true))
+        .arg(Arg::with_name("default_ttl")
+            .long("default_ttl")
+            .value_name("seconds")
+            .help("The time to live of the values set with an expiration time of 0, 0 to keep it as given")
+            .default_value("0")
+            .takes_value(true))
+        .arg(Arg::with_name("namespaces")
+            .long("namespaces")
+            .value_name("file")
+            .help("The YAML file listing the namespaces, selected by a connection with `use <namespace>`")
+            .takes_value(true))
+        .arg(Arg::with_name("backup_dir")
+            .long("backup_dir")
+            .value_name("directory")
+            .help("The directory where incremental backups are stored, backups are disabled without it")
+            .takes_value(true))
+        .arg(Arg::with_name("checkpoint_dir")
+            .long("checkpoint_dir")
+            .value_name("directory")
+            .help("The directory where the `checkpoint <name>` command creates checkpoints, the command is disabled without it")
+            .takes_value(true))
+        .arg(Arg::with_name("ingest_dir")
+            .long("ingest_dir")
+            .value_name("directory")
+            .help("The staging directory of the SST files moved in by the `ingest <path>` command, relative to it; the command is disabled without it")
+            .takes_value(true))
+        .arg(Arg::with_name("backup_interval")
+            .long("backup_interval")
+            .value_name("seconds")
+            .help("The time between two backups, 0 to only back up on the `backup` command")
+            .default_value("0")
+            .takes_value(true))
+        .arg(Arg::with_name("backups_to_keep")
+            .long("backups_to_keep")
+            .value_name("count")
+            .help("The number of backups kept, the older ones being purged")
+            .default_value("7")
+            .takes_value(true))
+        .arg(Arg::with_name("restore_from")
+            .long("restore_from")
+            .value_name("directory")
+            .help("The backup directory whose latest backup replaces the data directory before starting")
+            .takes_value(true))
+        .arg(Arg::with_name("read_only")
+            .long("read_only")
+            .help("Starts rejecting writes, until switched back with `read_only off`"))
+        .arg(Arg::with_name("replication_address")
+            .long("replication_address")
+            .value_name("host:port")
+            .help("The socket address replicas connect to, replication is disabled without it. Replicas are not authenticated, so it must stay on a private network")
+            .takes_value(true))
+        .arg(Arg::with_name("replicate_from")
+            .long("replicate_from")
+            .value_name("host:port")
+            .help("The replication address of the primary to follow, running as a read only replica")
+            .takes_value(true))
+        .arg(Arg::with_name("wal_ttl")
+            .long("wal_ttl")
+            .value_name("seconds")
+            .help("The time the write-ahead log is kept for replicas to catch up without a full sync")
+            .default_value("0")
+            .takes_value(true))
+        .arg(Arg::with_name("resp_address")
+            .long("resp_address")
+            .value_name("host:port")
+            .help("The socket address Redis clients connect to, sharing the data of the memcached clients")
+            .takes_value(true))
+        .arg(Arg::with_name("udp_address")
+            .long("udp_address")
+            .value_name("host:port")
+            .help("The socket address memcached UDP clients send their get and gets requests to, which must not be exposed publicly as spoofed requests make it reflect responses to others")
+            .takes_value(true))
+        .arg(Arg::with_name("admin_address")
+            .long("admin_address")
+            .value_name("host:port")
+            .help("The socket address serving /metrics, /healthz and /readyz, apart from the data ports")
+            .takes_value(true))
+        .arg(Arg::with_name("http_address")
+            .long("http_address")
+            .value_name("host:port")
+            .help("The socket address HTTP clients connect to, to read and write keys under /keys/")
+            .takes_value(true))
+        .arg(Arg::with_name("slow_log_threshold")
+            .long("slow_log_threshold")
+            .value_name("milliseconds")
+            .help("The time from which a command is kept in the slow log shown by `slow_log`, 0 to disable it")
+            .default_value("10")
+            .takes_value(true))
+        .arg(Arg::with_name("slow_log_size")
+            .long("slow_log_size")
+            .value_name("commands")
+            .help("The number of slow commands kept, the oldest being dropped")
+            .default_value("128")
+            .takes_value(true))
+        .arg(Arg::with_name("hot_keys_sample_rate")
+            .long("hot_keys_sample_rate")
+            .value_name("accesses")
+            .help("One read or write in this number is counted to find the hot keys shown by `stats hotkeys`, 0 to disable it")
+            .default_value("100")
+            .takes_value(true))
+        .arg(Arg::with_name("hot_keys_window")
+            .long("hot_keys_window")
+            .value_name("seconds")
+            .help("The length of the windows over which the rates of the hot keys are estimated")
+            .default_value("10")
+            .takes_value(true))
+        .arg(Arg::with_name("max_connections")
+            .long("max_connections")
+            .value_name("connections")
+            .help("The number of client connections from which new ones are rejected, 0 for no limit")
+            .default_value("1024")
+            .takes_value(true))
+        .arg(Arg::with_name("idle_timeout")
+            .long("idle_timeout")
+            .value_name("seconds")
+            .help("The time without any request or response after which a client connection is closed, 0 to keep it open")
+            .default_value("0")
+            .takes_value(true))
+        .arg(Arg::with_name("max_read_buffer")
+            .long("max_read_buffer")
+            .value_name("bytes")
+            .help("The size of an incomplete request from which the client connection is closed")
+            .default_value("2097152")
+            .takes_value(true))
+        .arg(Arg::with_name("max_write_buffer")
+            .long("max_write_buffer")
+            .value_name("bytes")
+            .help("The size of the responses not sent yet from which the requests of a client are no longer read nor answered")
+            .default_value("1048576")
+            .takes_value(true))
+        .arg(Arg::with_name("log_format")
+            .long("log_format")
+            .help("The format of the logs, text going through the log4rs.yml configuration, whose root level debug logs every command")
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .takes_value(true))
+        .arg(Arg::with_name("proxy")
+            .long("proxy")
+            .value_name("file")
+            .help("Runs as a proxy sharding the keys across the backends of the YAML file, without a data directory")
+            .takes_value(true))
+        .subcommand(SubCommand::with_name("dump")
+            .about("Writes the live records of a namespace to a portable dump file, then exits")
+            .arg(Arg::with_name("file")
+                .help("The dump file to write")
+                .required(true)
+                .index(1))
+            .arg(namespace_arg()))
+        .subcommand(SubCommand::with_name("load")
+            .about("Loads a dump file written by `dump` into a namespace, then exits")
+            .arg(Arg::with_name("file")
+                .help("The dump file to read")
+                .required(true)
+                .index(1))
+            .arg(namespace_arg()))
+        .subcommand(SubCommand::with_name("build_sst")
+            .about("Sorts the records of dump files into SST files to ingest with `ingest`, then exits")
+            .arg(Arg::with_name("output_dir")
+                .help("The directory where the SST files are written")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("files")
+                .help("The dump files to read, a key in several of them keeping its last record")
+                .required(true)
+                .multiple(true)
+                .index(2)))
         .get_matches();
 
-    log4rs::init_file("log4rs.yml", Default::default()).unwrap();
+    logging::init(matches.value_of("log_format").unwrap_or("text"))?;
+
+    // Building SST files is done offline, without opening the data directory
+    if let ("build_sst", Some(build_matches)) = matches.subcommand() {
+        return build_sst(build_matches);
+    }
 
     let addr = matches.value_of("address").unwrap_or("127.0.0.1:8080");
+    let limits = ConnectionLimits::new(
+        matches.value_of("max_connections").unwrap_or("1024").parse()?,
+        matches.value_of("idle_timeout").unwrap_or("0").parse()?,
+        matches.value_of("max_read_buffer").unwrap_or("2097152").parse()?,
+        matches.value_of("max_write_buffer").unwrap_or("1048576").parse()?,
+    );
+
+    if let Some(path) = matches.value_of("proxy") {
+        let options = serde_yaml::from_str::<proxy::ProxyOptions>(&fs::read_to_string(path)?)?;
+        return proxy::serve(addr, proxy::Proxy::new(options), limits).await;
+    }
+
+    let opened_db: admin::OpenedDatabase = Arc::new(RwLock::new(None));
+    if let Some(admin_address) = matches.value_of("admin_address") {
+        tokio::spawn(admin::serve(admin_address.parse()?, opened_db.clone()));
+    }
 
-    let mut listener = TcpListener::bind(&addr).await?;
     let database_directory = matches.value_of("db_dir").unwrap_or("/tmp/rocksdb");
-    info!("Listening on: {}", addr);
     info!("Storing data in {}", database_directory);
-    let db = Database::open(database_directory);
-    loop {
-        match listener.accept().await {
-            Ok((mut socket, client_addr)) => {
-                info!("Establing connection with {:?}", client_addr);
-                let db = db.clone();
-                tokio::spawn(async move {
-                    loop {
-                        let mut buf = [0u8; 1024];
-                        let mut bytes_mut = BytesMut::new();
-                        while let Ok(n) = socket.read(&mut buf).await {
-                            match n {
-                                0 => {
-                                    return;
-                                }
-                                n => {
-                                    bytes_mut.put_slice(&buf[0..n]);
-                                    if n < 1024 || (buf[1022] == b'\r' && buf[1023] == b'\n') {
-                                        let response = Command::handle(bytes_mut.bytes(), &db);
-                                        let response_bytes = response.serialize();
-                                        if let Err(e) = socket.write_all(response_bytes.bytes()).await {
-                                            error!("error on sending response; error = {:?}", e);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                });
+    if let Some(backup_dir) = matches.value_of("restore_from") {
+        Database::restore(backup_dir, database_directory)?;
+        info!("Restored the latest backup from {}", backup_dir);
+    }
+    let primary = matches.value_of("replicate_from");
+    // A replica starts from a copy of its primary
+    let initial_sequence = match primary {
+        Some(primary) if !Path::new(database_directory).exists() => {
+            info!("Copying the data directory of {}", primary);
+            Some(replication::fetch_checkpoint(primary, database_directory)?)
+        }
+        _ => None
+    };
+    let options = DatabaseOptions {
+        hot_cache_size: matches.value_of("hot_cache_size").unwrap_or("0").parse()?,
+        max_disk_bytes: matches.value_of("max_disk_bytes").unwrap_or("0").parse()?,
+        compression_threshold: matches.value_of("compression_threshold").unwrap_or("0").parse()?,
+        encryption_key_file: matches.value_of("encryption_key_file").map(String::from),
+        default_ttl: matches.value_of("default_ttl").unwrap_or("0").parse()?,
+        namespaces: match matches.value_of("namespaces") {
+            Some(path) => serde_yaml::from_str::<Vec<NamespaceOptions>>(&fs::read_to_string(path)?)?,
+            None => vec![]
+        },
+        backup_dir: matches.value_of("backup_dir").map(String::from),
+        backups_to_keep: matches.value_of("backups_to_keep").unwrap_or("7").parse()?,
+        checkpoint_dir: matches.value_of("checkpoint_dir").map(String::from),
+        ingest_dir: matches.value_of("ingest_dir").map(String::from),
+        read_only: matches.is_present("read_only"),
+        wal_ttl: matches.value_of("wal_ttl").unwrap_or("0").parse()?,
+        replica: primary.is_some(),
+        slow_log_threshold: matches.value_of("slow_log_threshold").unwrap_or("10").parse()?,
+        slow_log_size: matches.value_of("slow_log_size").unwrap_or("128").parse()?,
+        hot_keys_sample_rate: matches.value_of("hot_keys_sample_rate").unwrap_or("100").parse()?,
+        hot_keys_window: matches.value_of("hot_keys_window").unwrap_or("10").parse()?,
+    };
+    let db = Database::open_with_options(database_directory, &options);
+    if let Some(sequence) = initial_sequence {
+        db.set_replication_sequence(sequence)?;
+    }
+    *opened_db.write().unwrap() = Some(db.clone());
+
+    match matches.subcommand() {
+        ("dump", Some(dump_matches)) => return dump(&db, dump_matches),
+        ("load", Some(load_matches)) => return load(&db, load_matches),
+        _ => ()
+    }
+
+    if let Some(primary) = primary {
+        let primary = primary.to_string();
+        let replica_db = db.clone();
+        thread::spawn(move || replication::replicate(&primary, replica_db));
+    }
+
+    if let Some(replication_address) = matches.value_of("replication_address") {
+        let replication_listener = std::net::TcpListener::bind(replication_address)?;
+        info!("Serving replicas on: {}", replication_address);
+        let primary_db = db.clone();
+        thread::spawn(move || replication::serve(replication_listener, primary_db));
+    }
+
+    // Like memcached, a UNIX socket replaces the TCP listener unless an address is given too
+    let listener = match matches.occurrences_of("address") > 0 || !matches.is_present("unix_socket") {
+        true => {
+            let listener = TcpListener::bind(&addr).await?;
+            info!("Listening on: {}", addr);
+            Some(listener)
+        }
+        false => None
+    };
+    let unix_listener = match matches.value_of("unix_socket") {
+        Some(path) => {
+            let mode = u32::from_str_radix(matches.value_of("unix_socket_mode").unwrap_or("700"), 8)?;
+            let listener = memcached::bind_unix(path, mode)?;
+            info!("Listening on: {}", path);
+            Some((listener, path.to_string()))
+        }
+        None => None
+    };
+
+    if let Some(resp_address) = matches.value_of("resp_address") {
+        tokio::spawn(resp::serve(resp_address.to_string(), db.clone(), limits.clone()));
+    }
+    if let Some(http_address) = matches.value_of("http_address") {
+        tokio::spawn(http::serve(http_address.parse()?, db.clone()));
+    }
+    if let Some(udp_address) = matches.value_of("udp_address") {
+        tokio::spawn(udp::serve(udp_address.to_string(), db.clone()));
+    }
+
+    let reclaimer_db = db.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            // RocksDB blocks the thread, which must not be a worker of the runtime
+            let db = reclaimer_db.clone();
+            let reclaiming = task::spawn_blocking(move || {
+                for namespace in all_namespaces(&db) {
+                    namespace.reclaim_expired();
+                }
+            });
+            if let Err(e) = reclaiming.await {
+                error!("Can not reclaim expired keys; error = {:?}", e);
+            }
+        }
+    });
+
+    let evictor_db = db.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            let db = evictor_db.clone();
+            let evicting = task::spawn_blocking(move || {
+                for namespace in all_namespaces(&db) {
+                    namespace.evict();
+                }
+            });
+            if let Err(e) = evicting.await {
+                error!("Can not evict keys; error = {:?}", e);
+            }
+        }
+    });
+
+    let trainer_db = db.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let db = trainer_db.clone();
+            match task::spawn_blocking(move || db.train_compression_dictionary()).await {
+                Ok(Some(id)) => info!("Compressing values with dictionary {}", id),
+                Ok(None) => (),
+                Err(e) => error!("Can not train a compression dictionary; error = {:?}", e)
+            }
+        }
+    });
+
+    let backup_interval: u64 = matches.value_of("backup_interval").unwrap_or("0").parse()?;
+    if backup_interval > 0 {
+        let backup_db = db.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(backup_interval));
+            loop {
+                interval.tick().await;
+                if let Err(e) = backup_db.backup() {
+                    error!("Can not back up; error = {:?}", e);
+                }
+            }
+        });
+    }
+
+    let reencrypter_db = db.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let db = reencrypter_db.clone();
+            let reencrypting = task::spawn_blocking(move || {
+                all_namespaces(&db).iter().map(|namespace| namespace.reencrypt()).sum::<u32>()
+            });
+            match reencrypting.await {
+                Ok(0) => (),
+                Ok(reencrypted) => info!("Re-encrypted {} records with the current key", reencrypted),
+                Err(e) => error!("Can not re-encrypt records; error = {:?}", e)
             }
-            Err(e) => error!("error accepting socket; error = {:?}", e),
         }
+    });
+
+    let mut listeners = vec![];
+    if let Some(listener) = listener {
+        listeners.push(tokio::spawn(memcached::serve_tcp(listener, db.clone(), limits.clone())));
+    }
+    if let Some((listener, path)) = unix_listener {
+        listeners.push(tokio::spawn(memcached::serve_unix(listener, path, db.clone(), limits.clone())));
     }
-}
\ No newline at end of file
+    future::join_all(listeners).await;
+    Ok(())
+}
+
+fn namespace_arg() -> Arg<'static, 'static> {
+    Arg::with_name("namespace")
+        .long("namespace")
+        .value_name("namespace")
+        .help("The namespace to dump or load")
+        .default_value("default")
+        .takes_value(true)
+}
+
+fn selected_namespace(db: &Arc<Database>, matches: &ArgMatches<'_>) -> Result<Arc<Database>, Box<dyn Error>> {
+    let name = matches.value_of("namespace").unwrap_or("default");
+    db.namespace(name).ok_or_else(|| format!("Unknown namespace {}", name).into())
+}
+
+fn dump(db: &Arc<Database>, matches: &ArgMatches<'_>) -> Result<(), Box<dyn Error>> {
+    let namespace = selected_namespace(db, matches)?;
+    let path = matches.value_of("file").unwrap();
+    let mut writer = BufWriter::new(File::create(path)?);
+    let dumped = namespace.dump(&mut writer, |dumped| info!("Dumped {} records", dumped))?;
+    info!("Dumped {} records of namespace {} to {}", dumped, namespace.name(), path);
+    Ok(())
+}
+
+fn load(db: &Arc<Database>, matches: &ArgMatches<'_>) -> Result<(), Box<dyn Error>> {
+    let namespace = selected_namespace(db, matches)?;
+    let path = matches.value_of("file").unwrap();
+    let mut reader = BufReader::new(File::open(path)?);
+    let loaded = namespace.load(&mut reader, |loaded| info!("Loaded {} records", loaded))?;
+    info!("Loaded {} records from {} into namespace {}", loaded, path, namespace.name());
+    Ok(())
+}
+
+fn build_sst(matches: &ArgMatches<'_>) -> Result<(), Box<dyn Error>> {
+    let output_dir = matches.value_of("output_dir").unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut builder = SstBuilder::new(output_dir, now)?;
+    let mut added: u64 = 0;
+    for path in matches.values_of("files").unwrap() {
+        let mut reader = BufReader::new(File::open(path)?);
+        read_header(&mut reader)?;
+        while let Some(entry) = read_entry(&mut reader)? {
+            builder.add(entry)?;
+            added += 1;
+            if added % 1_000_000 == 0 {
+                info!("Sorted {} records", added);
+            }
+        }
+    }
+    let files = builder.finish()?;
+    info!("Wrote {} records to {} SST files in {}", added, files.len(), output_dir);
+    Ok(())
+}
+
+fn all_namespaces(db: &Arc<Database>) -> Vec<Arc<Database>> {
+    let mut namespaces = vec![db.clone()];
+    namespaces.extend(db.namespaces().iter().cloned());
+    namespaces
+}
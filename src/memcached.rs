@@ -0,0 +1,101 @@
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::sync::Arc;
+use tracing::{error, info, info_span, Instrument};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::future::BoxFuture;
+
+use rockscached_db::command::Command;
+use rockscached_db::db::Database;
+use rockscached_db::parser::request_length;
+
+use crate::connection::{self, ConnectionLimits, Protocol};
+
+/// A memcached client, whose namespace is changed by `use`.
+struct Memcached {
+    db: Arc<Database>,
+    client: String,
+}
+
+impl Protocol for Memcached {
+    /// Answers the pipelined requests one by one.
+    fn handle<'a>(&'a mut self, requests: &'a mut BytesMut, responses: &'a mut BytesMut, max_responses: usize) -> BoxFuture<'a, bool> {
+        Box::pin(async move {
+            while responses.len() < max_responses {
+                let length = match request_length(requests.bytes()) {
+                    Some(length) => length,
+                    None => break
+                };
+                let response = Command::handle(&requests[..length], &mut self.db, &self.client);
+                responses.put_slice(response.serialize().bytes());
+                requests.advance(length);
+            }
+            true
+        })
+    }
+
+    fn request_too_large(&self) -> &'static [u8] {
+        b"SERVER_ERROR request too large\r\n"
+    }
+}
+
+/// Accepts memcached clients over TCP.
+pub async fn serve_tcp(mut listener: TcpListener, db: Arc<Database>, limits: Arc<ConnectionLimits>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, client_addr)) => spawn_connection(socket, client_addr.to_string(), "memcached", &db, &limits),
+            Err(e) => error!("error accepting socket; error = {:?}", e),
+        }
+    }
+}
+
+/// Binds a UNIX socket at `path`, replacing the socket left by a previous run, with the
+/// permissions of `mode`.
+pub fn bind_unix(path: &str, mode: u32) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path)));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Accepts memcached clients over a UNIX socket, the clients being named after its path.
+pub async fn serve_unix(mut listener: UnixListener, path: String, db: Arc<Database>, limits: Arc<ConnectionLimits>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => spawn_connection(socket, format!("unix:{}", path), "unix", &db, &limits),
+            Err(e) => error!("error accepting socket; error = {:?}", e),
+        }
+    }
+}
+
+fn spawn_connection<S>(socket: S, client: String, listener: &'static str, db: &Arc<Database>, limits: &Arc<ConnectionLimits>)
+    where S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    let connection = match limits.open() {
+        Some(connection) => connection,
+        None => {
+            db.metrics().connection_rejected(listener);
+            tokio::spawn(connection::reject(socket, client, b"SERVER_ERROR too many open connections\r\n"));
+            return;
+        }
+    };
+    info!(client = %client, "Connection opened");
+    let span = info_span!("connection", listener, client = %client);
+    let memcached = Memcached { db: db.clone(), client };
+    let limits = limits.clone();
+    let db = db.clone();
+    db.metrics().connection_opened(listener);
+    tokio::spawn(async move {
+        connection::serve(socket, &limits, memcached).await;
+        db.metrics().connection_closed(listener);
+        drop(connection);
+    }.instrument(span));
+}
@@ -0,0 +1,496 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+use futures::future::{join_all, BoxFuture};
+use tracing::{error, info, info_span, warn, Instrument};
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use bytes::{BytesMut, BufMut, Buf};
+
+use rockscached_db::command::Command;
+use rockscached_db::parser::{parse, request_length};
+use rockscached_db::response::Response;
+
+use crate::connection::{self, ConnectionLimits, Protocol};
+
+/// Settings of the proxy mode, read from the YAML file given to `--proxy`, e.g.
+/// ```yaml
+/// backends:
+///   - address: 10.0.0.1:8080
+///   - address: 10.0.0.2:8080
+///     weight: 2
+/// health_check_interval: 1
+/// failure_limit: 2
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProxyOptions {
+    pub backends: Vec<BackendOptions>,
+    /// Time in seconds between two health checks of every backend.
+    pub health_check_interval: u64,
+    /// Number of consecutive failures after which a backend is ejected from the ring,
+    /// until a health check succeeds.
+    pub failure_limit: u32,
+    /// Time in milliseconds a backend has to answer a request.
+    pub timeout: u64,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> ProxyOptions {
+        ProxyOptions { backends: vec![], health_check_interval: 1, failure_limit: 2, timeout: 1_000 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackendOptions {
+    pub address: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// Ketama consistent hash ring, compatible with libketama and twemproxy: every backend gets
+/// points from the MD5 of `<address>-<index>` in proportion to its weight, and a key goes
+/// to the backend of the first point following the hash of the key.
+struct Ring {
+    points: Vec<(u32, usize)>,
+}
+
+const HASHES_PER_BACKEND: f64 = 40.0;
+const POINTS_PER_HASH: usize = 4;
+
+impl Ring {
+    /// Ring of the backends whose index is in `live`.
+    fn new(backends: &[BackendOptions], live: &[usize]) -> Ring {
+        let total_weight: u32 = live.iter().map(|&index| backends[index].weight).sum();
+        let mut points = vec![];
+        for &index in live {
+            let share = backends[index].weight as f64 / total_weight as f64;
+            let hashes = (share * HASHES_PER_BACKEND * live.len() as f64).floor() as usize;
+            for hash in 0..hashes {
+                let digest = md5::compute(format!("{}-{}", backends[index].address, hash));
+                for point in 0..POINTS_PER_HASH {
+                    points.push((ketama_point(&digest.0, point), index));
+                }
+            }
+        }
+        points.sort();
+        Ring { points }
+    }
+
+    fn backend(&self, key: &[u8]) -> Option<usize> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let hash = ketama_point(&md5::compute(key).0, 0);
+        let position = match self.points.binary_search_by(|&(point, _)| point.cmp(&hash)) {
+            Ok(position) | Err(position) => position % self.points.len(),
+        };
+        Some(self.points[position].1)
+    }
+}
+
+fn ketama_point(digest: &[u8; 16], point: usize) -> u32 {
+    let bytes = &digest[point * 4..point * 4 + 4];
+    (bytes[3] as u32) << 24 | (bytes[2] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[0] as u32
+}
+
+struct Backend {
+    address: String,
+    healthy: AtomicBool,
+    failures: AtomicU32,
+}
+
+type Stream = BufReader<TcpStream>;
+
+/// How the answer of a backend ends.
+#[derive(Clone, Copy, PartialEq)]
+enum Reply {
+    /// A single line.
+    Line,
+    /// Lines up to `END`, with the data of the `VALUE` lines.
+    Values,
+    /// Nothing is sent back, as requested with `noreply`.
+    None,
+}
+
+/// Routes the commands of the text protocol to backend nodes by key, so that clients see
+/// a single node.
+pub struct Proxy {
+    options: ProxyOptions,
+    backends: Vec<Backend>,
+    ring: RwLock<Ring>,
+}
+
+impl Proxy {
+    pub fn new(options: ProxyOptions) -> Arc<Proxy> {
+        let backends: Vec<Backend> = options.backends.iter().map(|backend| Backend {
+            address: backend.address.clone(),
+            healthy: AtomicBool::new(true),
+            failures: AtomicU32::new(0),
+        }).collect();
+        let live: Vec<usize> = (0..backends.len()).collect();
+        let ring = RwLock::new(Ring::new(&options.backends, &live));
+        Arc::new(Proxy { options, backends, ring })
+    }
+
+    fn rebuild_ring(&self) {
+        let live: Vec<usize> = (0..self.backends.len())
+            .filter(|&index| self.backends[index].healthy.load(Ordering::Relaxed))
+            .collect();
+        *self.ring.write().unwrap() = Ring::new(&self.options.backends, &live);
+    }
+
+    fn record_success(&self, index: usize) {
+        let backend = &self.backends[index];
+        backend.failures.store(0, Ordering::Relaxed);
+        if !backend.healthy.swap(true, Ordering::Relaxed) {
+            info!("Backend {} is back", backend.address);
+            self.rebuild_ring();
+        }
+    }
+
+    fn record_failure(&self, index: usize, e: &io::Error) {
+        let backend = &self.backends[index];
+        let failures = backend.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.options.failure_limit && backend.healthy.swap(false, Ordering::Relaxed) {
+            warn!("Ejecting backend {} after {} failures {}", backend.address, failures, e);
+            self.rebuild_ring();
+        }
+    }
+
+    /// Checks every backend with a `stats` request, ejecting the failing ones and bringing
+    /// back those answering again.
+    pub async fn check_health(&self) {
+        let checks = (0..self.backends.len()).map(|index| self.exchange(index, None, b"stats\r\n".to_vec(), Reply::Values));
+        for (index, (_, result)) in join_all(checks).await.into_iter().enumerate() {
+            match result {
+                Ok(ref response) if response.ends_with(b"END\r\n") => self.record_success(index),
+                Ok(_) => self.record_failure(index, &io::Error::new(io::ErrorKind::InvalidData, "Invalid stats response")),
+                // The failure is already recorded by the exchange
+                Err(_) => (),
+            }
+        }
+    }
+
+    /// Sends `request` to a backend over `stream`, or a new connection, and reads its reply.
+    /// The connection is given back unless it failed.
+    async fn exchange(&self, index: usize, stream: Option<Stream>, request: Vec<u8>, reply: Reply) -> (Option<Stream>, io::Result<Vec<u8>>) {
+        let timeout = Duration::from_millis(self.options.timeout);
+        let address = &self.backends[index].address;
+        let result = time::timeout(timeout, async {
+            let mut stream = match stream {
+                Some(stream) => stream,
+                None => BufReader::new(TcpStream::connect(address).await?)
+            };
+            stream.get_mut().write_all(&request).await?;
+            let response = read_reply(&mut stream, reply).await?;
+            Ok((stream, response))
+        }).await.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Backend timed out")));
+        match result {
+            Ok((stream, response)) => (Some(stream), Ok(response)),
+            Err(e) => {
+                self.record_failure(index, &e);
+                (None, Err(e))
+            }
+        }
+    }
+
+    /// Sends `request` to the given backends at once, keeping their connections in
+    /// `connections`.
+    async fn exchange_all(&self, connections: &mut HashMap<usize, Stream>, requests: Vec<(usize, Vec<u8>)>, reply: Reply) -> Vec<(usize, io::Result<Vec<u8>>)> {
+        let exchanges = requests.into_iter().map(|(index, request)| {
+            let stream = connections.remove(&index);
+            async move { (index, self.exchange(index, stream, request, reply).await) }
+        }).collect::<Vec<_>>();
+        let mut results = vec![];
+        for (index, (stream, result)) in join_all(exchanges).await {
+            if let Some(stream) = stream {
+                connections.insert(index, stream);
+            }
+            results.push((index, result));
+        }
+        results
+    }
+
+    /// Runs a command of a client, `connections` being its connections to the backends.
+    pub async fn handle(&self, request: &[u8], connections: &mut HashMap<usize, Stream>) -> Vec<u8> {
+        let command = match parse(request) {
+            Ok(command) => command,
+            Err(e) => return Response::Error { msg: Box::new(e) }.serialize().to_vec(),
+        };
+        match command {
+            Command::Get { keys } => self.get(connections, "get", keys).await,
+            Command::Gets { keys } => self.get(connections, "gets", keys).await,
+            Command::Set { key, .. } | Command::Add { key, .. } | Command::Append { key, .. }
+            | Command::Prepend { key, .. } | Command::TaggedSet { key, .. } | Command::Delete { key }
+            | Command::Increment { key, .. } | Command::Decrement { key, .. } => {
+                let index = match self.ring.read().unwrap().backend(key) {
+                    Some(index) => index,
+                    None => return Response::ServerError.serialize().to_vec()
+                };
+                match self.exchange_all(connections, vec![(index, request.to_vec())], Reply::Line).await.pop() {
+                    Some((_, Ok(response))) => response,
+                    _ => Response::ServerError.serialize().to_vec()
+                }
+            }
+            Command::FlushAll => {
+                let results = self.broadcast(connections, request, Reply::Line).await;
+                match results.iter().all(|result| matches!(result, Ok(response) if response == b"OK\r\n")) {
+                    true => Response::Ok.serialize().to_vec(),
+                    false => Response::ServerError.serialize().to_vec()
+                }
+            }
+            Command::InvalidateTag { noreply, .. } | Command::DeletePrefix { noreply, .. } | Command::DeleteRange { noreply, .. } => {
+                if noreply {
+                    self.broadcast(connections, request, Reply::None).await;
+                    return vec![];
+                }
+                let mut total = 0;
+                for result in self.broadcast(connections, request, Reply::Line).await {
+                    match result.ok().and_then(|response| String::from_utf8_lossy(&response).trim_end().parse::<u64>().ok()) {
+                        Some(n) => total += n,
+                        None => return Response::ServerError.serialize().to_vec()
+                    }
+                }
+                format!("{}\r\n", total).into_bytes()
+            }
+            Command::Stats => self.stats(),
+            // Administration commands are sent to the nodes directly
+            _ => Response::NotImplemented.serialize().to_vec()
+        }
+    }
+
+    /// Splits a multi-key get by backend, then answers the values in the order of the keys.
+    async fn get(&self, connections: &mut HashMap<usize, Stream>, verb: &str, keys: Vec<&[u8]>) -> Vec<u8> {
+        let mut keys_by_backend: Vec<(usize, Vec<&[u8]>)> = vec![];
+        {
+            let ring = self.ring.read().unwrap();
+            for key in &keys {
+                let index = match ring.backend(key) {
+                    Some(index) => index,
+                    None => continue
+                };
+                match keys_by_backend.iter_mut().find(|(backend, _)| *backend == index) {
+                    Some((_, backend_keys)) => backend_keys.push(key),
+                    None => keys_by_backend.push((index, vec![key]))
+                }
+            }
+        }
+        let requests = keys_by_backend.into_iter().map(|(index, backend_keys)| {
+            let mut request = verb.as_bytes().to_vec();
+            for key in backend_keys {
+                request.push(b' ');
+                request.extend_from_slice(key);
+            }
+            request.extend_from_slice(b"\r\n");
+            (index, request)
+        }).collect();
+        // The keys of a failing backend are answered as misses
+        let mut values = HashMap::new();
+        for (_, result) in self.exchange_all(connections, requests, Reply::Values).await {
+            if let Ok(response) = result {
+                values.extend(split_values(&response));
+            }
+        }
+        let mut response = vec![];
+        for key in keys {
+            if let Some(value) = values.get(key) {
+                response.extend_from_slice(value);
+            }
+        }
+        response.extend_from_slice(b"END\r\n");
+        response
+    }
+
+    async fn broadcast(&self, connections: &mut HashMap<usize, Stream>, request: &[u8], reply: Reply) -> Vec<io::Result<Vec<u8>>> {
+        let requests = (0..self.backends.len())
+            .filter(|&index| self.backends[index].healthy.load(Ordering::Relaxed))
+            .map(|index| (index, request.to_vec()))
+            .collect();
+        self.exchange_all(connections, requests, reply).await.into_iter().map(|(_, result)| result).collect()
+    }
+
+    fn stats(&self) -> Vec<u8> {
+        let mut bytes_mut = BytesMut::new();
+        for backend in &self.backends {
+            let healthy = backend.healthy.load(Ordering::Relaxed) as u8;
+            bytes_mut.put_slice(format!("STAT backend:{} {}\r\n", backend.address, healthy).as_bytes());
+        }
+        bytes_mut.put_slice(b"END\r\n");
+        bytes_mut.to_vec()
+    }
+}
+
+async fn read_reply(stream: &mut Stream, reply: Reply) -> io::Result<Vec<u8>> {
+    let mut response = vec![];
+    if reply == Reply::None {
+        return Ok(response);
+    }
+    loop {
+        let mut line = vec![];
+        if stream.read_until(b'\n', &mut line).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Backend closed the connection"));
+        }
+        response.extend_from_slice(&line);
+        if reply == Reply::Line || line == b"END\r\n" || line == b"ERROR\r\n" || line.starts_with(b"SERVER_ERROR") {
+            return Ok(response);
+        }
+        if line.starts_with(b"VALUE ") {
+            let length = value_length(&line)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid VALUE line"))?;
+            let mut data = vec![0u8; length + 2];
+            stream.read_exact(&mut data).await?;
+            response.extend_from_slice(&data);
+        }
+    }
+}
+
+/// Length of the data following a `VALUE <key> <flags> <bytes> [<cas>]` line.
+fn value_length(line: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(line).ok()?;
+    line.trim_end().split(' ').nth(3)?.parse().ok()
+}
+
+/// Splits the answer of a get into the `VALUE` line and data of every key.
+fn split_values(response: &[u8]) -> HashMap<Vec<u8>, Vec<u8>> {
+    let mut values = HashMap::new();
+    let mut rest = response;
+    while rest.starts_with(b"VALUE ") {
+        let line_end = match rest.windows(2).position(|window| window == b"\r\n") {
+            Some(position) => position + 2,
+            None => break
+        };
+        let length = match value_length(&rest[..line_end]) {
+            Some(length) => length,
+            None => break
+        };
+        let end = (line_end + length + 2).min(rest.len());
+        let key = rest[6..line_end - 2].split(|&byte| byte == b' ').next().unwrap_or_default();
+        values.insert(key.to_vec(), rest[..end].to_vec());
+        rest = &rest[end..];
+    }
+    values
+}
+
+/// A client of the proxy, with its connections to the backends.
+struct ProxyClient {
+    proxy: Arc<Proxy>,
+    connections: HashMap<usize, Stream>,
+}
+
+impl Protocol for ProxyClient {
+    /// Forwards the pipelined requests one by one.
+    fn handle<'a>(&'a mut self, requests: &'a mut BytesMut, responses: &'a mut BytesMut, max_responses: usize) -> BoxFuture<'a, bool> {
+        Box::pin(async move {
+            while responses.len() < max_responses {
+                let length = match request_length(requests.bytes()) {
+                    Some(length) => length,
+                    None => break
+                };
+                let response = self.proxy.handle(&requests[..length], &mut self.connections).await;
+                responses.put_slice(&response);
+                requests.advance(length);
+            }
+            true
+        })
+    }
+
+    fn request_too_large(&self) -> &'static [u8] {
+        b"SERVER_ERROR request too large\r\n"
+    }
+}
+
+/// Accepts the clients of the proxy, checking the health of the backends in the background.
+pub async fn serve(addr: &str, proxy: Arc<Proxy>, limits: Arc<ConnectionLimits>) -> Result<(), Box<dyn std::error::Error>> {
+    let health_proxy = proxy.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(health_proxy.options.health_check_interval.max(1)));
+        loop {
+            interval.tick().await;
+            health_proxy.check_health().await;
+        }
+    });
+
+    let mut listener = TcpListener::bind(addr).await?;
+    info!("Proxying {} backends on: {}", proxy.backends.len(), addr);
+    loop {
+        match listener.accept().await {
+            Ok((socket, client_addr)) => {
+                let connection = match limits.open() {
+                    Some(connection) => connection,
+                    None => {
+                        tokio::spawn(connection::reject(socket, client_addr.to_string(), b"SERVER_ERROR too many open connections\r\n"));
+                        continue;
+                    }
+                };
+                info!(client = %client_addr, "Connection opened");
+                let span = info_span!("connection", listener = "proxy", client = %client_addr);
+                let client = ProxyClient { proxy: proxy.clone(), connections: HashMap::new() };
+                let limits = limits.clone();
+                tokio::spawn(async move {
+                    connection::serve(socket, &limits, client).await;
+                    drop(connection);
+                }.instrument(span));
+            }
+            Err(e) => error!("error accepting socket; error = {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends(addresses: &[&str]) -> Vec<BackendOptions> {
+        addresses.iter().map(|address| BackendOptions { address: address.to_string(), weight: 1 }).collect()
+    }
+
+    #[test]
+    fn ketama_point_nominal() {
+        let digest = md5::compute(b"foo");
+        assert_eq!(ketama_point(&digest.0, 0), 3675831724);
+        assert_eq!(ketama_point(&digest.0, 1), 1559806540);
+    }
+
+    #[test]
+    fn ring_points_per_weight() {
+        let mut options = backends(&["10.0.0.1:8080", "10.0.0.2:8080"]);
+        assert_eq!(Ring::new(&options, &[0, 1]).points.len(), 2 * 160);
+        options[1].weight = 3;
+        let ring = Ring::new(&options, &[0, 1]);
+        assert_eq!(ring.points.iter().filter(|(_, index)| *index == 0).count(), 80);
+        assert_eq!(ring.points.iter().filter(|(_, index)| *index == 1).count(), 240);
+    }
+
+    #[test]
+    fn ring_only_moves_keys_of_removed_backend() {
+        let options = backends(&["10.0.0.1:8080", "10.0.0.2:8080", "10.0.0.3:8080"]);
+        let full = Ring::new(&options, &[0, 1, 2]);
+        let reduced = Ring::new(&options, &[0, 2]);
+        for i in 0..1000 {
+            let key = format!("key:{}", i);
+            let before = full.backend(key.as_bytes()).unwrap();
+            let after = reduced.backend(key.as_bytes()).unwrap();
+            assert!(before == 1 || before == after);
+            assert_ne!(after, 1);
+        }
+        assert_eq!(Ring::new(&options, &[]).backend(b"key"), None);
+    }
+
+    #[test]
+    fn split_values_nominal() {
+        let values = split_values(b"VALUE k1 0 2\r\nab\r\nVALUE k2 3 4 12\r\nc\r\nd\r\nEND\r\n");
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[&b"k1".to_vec()], b"VALUE k1 0 2\r\nab\r\n".to_vec());
+        assert_eq!(values[&b"k2".to_vec()], b"VALUE k2 3 4 12\r\nc\r\nd\r\n".to_vec());
+        assert!(split_values(b"END\r\n").is_empty());
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::Arc;
+use tracing::{error, info, info_span, Instrument};
+use tokio::net::TcpListener;
+use bytes::{Buf, BytesMut};
+use futures::future::BoxFuture;
+
+use rockscached_db::db::Database;
+use rockscached_db::resp::{Reply, Session, parse_request};
+
+use crate::connection::{self, ConnectionLimits, Protocol};
+
+/// Accepts Redis clients, whose commands share the storage of the memcached listener.
+pub async fn serve(addr: String, db: Arc<Database>, limits: Arc<ConnectionLimits>) {
+    let mut listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Can not listen to RESP clients on {}; error = {:?}", addr, e);
+            return;
+        }
+    };
+    info!("Listening to RESP clients on: {}", addr);
+    loop {
+        match listener.accept().await {
+            Ok((socket, client_addr)) => {
+                let connection = match limits.open() {
+                    Some(connection) => connection,
+                    None => {
+                        db.metrics().connection_rejected("resp");
+                        tokio::spawn(connection::reject(socket, client_addr.to_string(), b"-ERR too many open connections\r\n"));
+                        continue;
+                    }
+                };
+                info!(client = %client_addr, "RESP connection opened");
+                let span = info_span!("connection", listener = "resp", client = %client_addr);
+                let session = Session::new(db.clone());
+                let limits = limits.clone();
+                let db = db.clone();
+                db.metrics().connection_opened("resp");
+                tokio::spawn(async move {
+                    connection::serve(socket, &limits, session).await;
+                    db.metrics().connection_closed("resp");
+                    drop(connection);
+                }.instrument(span));
+            }
+            Err(e) => error!("error accepting socket; error = {:?}", e),
+        }
+    }
+}
+
+impl Protocol for Session {
+    /// Answers the pipelined requests one by one, closing the connection on a malformed one.
+    fn handle<'a>(&'a mut self, requests: &'a mut BytesMut, responses: &'a mut BytesMut, max_responses: usize) -> BoxFuture<'a, bool> {
+        Box::pin(async move {
+            while responses.len() < max_responses {
+                match parse_request(requests.bytes()) {
+                    Ok(Some((args, length))) => {
+                        let reply = Session::handle(self, &args);
+                        reply.serialize(self.protocol(), responses);
+                        requests.advance(length);
+                    }
+                    Ok(None) => return true,
+                    Err(e) => {
+                        Reply::Error(format!("ERR {}", e)).serialize(self.protocol(), responses);
+                        return false;
+                    }
+                }
+            }
+            true
+        })
+    }
+
+    fn request_too_large(&self) -> &'static [u8] {
+        b"-ERR request too large\r\n"
+    }
+}
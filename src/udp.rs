@@ -0,0 +1,153 @@
+use std::sync::Arc;
+use tracing::{debug, error, info};
+use tokio::net::UdpSocket;
+use bytes::{BufMut, BytesMut};
+use byteorder::{BigEndian, ByteOrder};
+
+use rockscached_db::command::Command;
+use rockscached_db::db::Database;
+use rockscached_db::parser::parse;
+
+/// Size of the frame header: request id, sequence number, total datagrams and a reserved
+/// field, each a big endian `u16`.
+const HEADER_SIZE: usize = 8;
+/// Size of a response datagram, header included, as sent by memcached.
+const MAX_DATAGRAM_SIZE: usize = 1400;
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+/// Most keys of a request, bounding the amplification of a request with a spoofed source.
+const MAX_KEYS: usize = 32;
+/// Most datagrams of a response, larger responses being replaced with an error.
+const MAX_RESPONSE_DATAGRAMS: usize = 8;
+
+/// Answers the `get` and `gets` requests of memcached UDP clients, each request being a
+/// single datagram. As the source of a datagram can be spoofed, the responses are bounded,
+/// and the listener must not be reachable from public networks.
+pub async fn serve(addr: String, db: Arc<Database>) {
+    let mut socket = match UdpSocket::bind(&addr).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Can not listen to UDP clients on {}; error = {:?}", addr, e);
+            return;
+        }
+    };
+    info!("Listening to UDP clients on: {}", addr);
+    let mut buf = vec![0u8; MAX_REQUEST_SIZE];
+    loop {
+        let (n, client_addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!("error receiving datagram; error = {:?}", e);
+                continue;
+            }
+        };
+        let (request_id, request) = match parse_frame(&buf[0..n]) {
+            Some(frame) => frame,
+            None => {
+                debug!(client = %client_addr, "Ignoring a malformed or multi-datagram request");
+                continue;
+            }
+        };
+        let response = handle(request, &db, &client_addr.to_string());
+        for datagram in frames(request_id, &response) {
+            if let Err(e) = socket.send_to(&datagram, &client_addr).await {
+                error!("error on sending response; error = {:?}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Runs the reads only, the commands being stateless over UDP.
+fn handle(request: &[u8], db: &Arc<Database>, client: &str) -> Vec<u8> {
+    match parse(request) {
+        Ok(Command::Get { keys }) | Ok(Command::Gets { keys }) if keys.len() > MAX_KEYS => {
+            b"SERVER_ERROR too many keys for UDP\r\n".to_vec()
+        }
+        Ok(Command::Get { .. }) | Ok(Command::Gets { .. }) => {
+            Command::handle(request, &mut db.clone(), client).serialize().to_vec()
+        }
+        Ok(_) => b"CLIENT_ERROR only get and gets are supported over UDP\r\n".to_vec(),
+        Err(_) => b"ERROR\r\n".to_vec()
+    }
+}
+
+/// The request id and the request of a datagram, unless it is not the only one of its request.
+fn parse_frame(datagram: &[u8]) -> Option<(u16, &[u8])> {
+    if datagram.len() < HEADER_SIZE {
+        return None;
+    }
+    let sequence = BigEndian::read_u16(&datagram[2..4]);
+    let total = BigEndian::read_u16(&datagram[4..6]);
+    match (sequence, total) {
+        (0, 1) => Some((BigEndian::read_u16(&datagram[0..2]), &datagram[HEADER_SIZE..])),
+        _ => None
+    }
+}
+
+/// The datagrams of a response, each with a frame header numbering it.
+fn frames(request_id: u16, response: &[u8]) -> Vec<Vec<u8>> {
+    let chunk_size = MAX_DATAGRAM_SIZE - HEADER_SIZE;
+    let response = match (response.len() + chunk_size - 1) / chunk_size {
+        total if total > MAX_RESPONSE_DATAGRAMS => &b"SERVER_ERROR response too large for UDP\r\n"[..],
+        _ => response
+    };
+    let chunks: Vec<&[u8]> = match response.is_empty() {
+        true => vec![response],
+        false => response.chunks(chunk_size).collect()
+    };
+    let total = chunks.len() as u16;
+    chunks.iter().enumerate().map(|(sequence, chunk)| {
+        let mut datagram = BytesMut::with_capacity(HEADER_SIZE + chunk.len());
+        datagram.put_u16(request_id);
+        datagram.put_u16(sequence as u16);
+        datagram.put_u16(total);
+        datagram.put_u16(0);
+        datagram.put_slice(chunk);
+        datagram.to_vec()
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rockscached_db::test_utils::TempDatabase;
+
+    #[test]
+    fn parse_single_datagram_requests() {
+        assert_eq!(parse_frame(b"\x00\x07\x00\x00\x00\x01\x00\x00get a\r\n"), Some((7, &b"get a\r\n"[..])));
+        assert_eq!(parse_frame(b"\x00\x07\x00\x00\x00\x02\x00\x00get a\r\n"), None);
+        assert_eq!(parse_frame(b"\x00\x07\x00\x01\x00\x01\x00\x00get a\r\n"), None);
+        assert_eq!(parse_frame(b"\x00\x07\x00"), None);
+    }
+
+    #[test]
+    fn frames_split_the_response() {
+        let response = vec![b'x'; 3000];
+        let datagrams = frames(258, &response);
+        assert_eq!(datagrams.len(), 3);
+        assert_eq!(&datagrams[0][0..HEADER_SIZE], b"\x01\x02\x00\x00\x00\x03\x00\x00");
+        assert_eq!(&datagrams[2][0..HEADER_SIZE], b"\x01\x02\x00\x02\x00\x03\x00\x00");
+        assert_eq!(datagrams[0].len(), MAX_DATAGRAM_SIZE);
+        assert_eq!(datagrams.iter().map(|datagram| datagram.len() - HEADER_SIZE).sum::<usize>(), 3000);
+        assert_eq!(frames(1, b"END\r\n"), vec![b"\x00\x01\x00\x00\x00\x01\x00\x00END\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn frames_of_large_responses_are_an_error() {
+        let response = vec![b'x'; MAX_RESPONSE_DATAGRAMS * (MAX_DATAGRAM_SIZE - HEADER_SIZE)];
+        assert_eq!(frames(1, &response).len(), MAX_RESPONSE_DATAGRAMS);
+        let response = vec![b'x'; MAX_RESPONSE_DATAGRAMS * (MAX_DATAGRAM_SIZE - HEADER_SIZE) + 1];
+        assert_eq!(frames(1, &response), vec![b"\x00\x01\x00\x00\x00\x01\x00\x00SERVER_ERROR response too large for UDP\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn requests_with_too_many_keys_are_an_error() {
+        let db = TempDatabase::open("udp");
+        let keys: Vec<String> = (0..=MAX_KEYS).map(|i| format!("k{}", i)).collect();
+        let request = format!("get {}\r\n", keys.join(" "));
+        assert_eq!(handle(request.as_bytes(), &db, "client"), b"SERVER_ERROR too many keys for UDP\r\n".to_vec());
+        let request = format!("get {}\r\n", keys[1..].join(" "));
+        assert_eq!(handle(request.as_bytes(), &db, "client"), b"END\r\n".to_vec());
+    }
+}